@@ -0,0 +1,889 @@
+use crate::commands::{self, Command, CATEGORIES};
+use crate::config::{split_args, Config};
+use crate::connection::Connection;
+use crate::db::now_ms;
+use crate::glob;
+use crate::handler::{arg_int, arg_str, error, ok, wrong_arity};
+use crate::parser::RespOrig;
+use crate::server::Server;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+/// the user every connection starts as
+pub const DEFAULT_USER: &str = "default";
+/// a denial like an earlier one within this window counts towards the earlier entry
+const LOG_MERGE_MS: u64 = 60_000;
+const NO_ACLFILE: &str = "ERR This Redis instance is not configured to use an ACL file. You may want to \
+     specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a \
+     Redis configuration file set) in order to store users in the Redis configuration.";
+
+/// users by name, and the denials recorded for ACL LOG
+#[derive(Debug)]
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    /// newest first
+    log: VecDeque<LogEntry>,
+    next_entry_id: u64,
+}
+
+/// a user's state. the root selector and any extra ones each grant a set of commands on a
+/// set of keys and channels, and a call is allowed when one of them allows all of it
+#[derive(Debug, Clone, Default)]
+struct User {
+    enabled: bool,
+    nopass: bool,
+    /// hex SHA-256 of each password
+    passwords: Vec<String>,
+    root: Selector,
+    selectors: Vec<Selector>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Selector {
+    /// in the order given, the last one matching a command decides. empty allows nothing
+    commands: Vec<CommandRule>,
+    keys: Vec<KeyPattern>,
+    /// glob patterns, `*` allows every channel
+    channels: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct CommandRule {
+    allow: bool,
+    target: Target,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    All,
+    /// index into `CATEGORIES`
+    Category(usize),
+    /// lower-cased, like the subcommand
+    Command(String),
+    Subcommand(String, String),
+}
+
+#[derive(Debug, Clone)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+/// what a selector did not allow
+#[derive(Debug)]
+enum Denial {
+    /// the command, or `command|subcommand`
+    Command(String),
+    Key(String),
+    Channel(String),
+}
+
+#[derive(Debug)]
+struct LogEntry {
+    count: u64,
+    reason: &'static str,
+    /// `toplevel`, `multi` or `lua`
+    context: &'static str,
+    object: String,
+    username: String,
+    client_info: String,
+    entry_id: u64,
+    created: u64,
+    updated: u64,
+}
+
+impl Acl {
+    /// the default user with `requirepass`, then the users of `aclfile`
+    pub fn load(config: &Config) -> Result<Acl, Error> {
+        let mut acl = Acl { users: BTreeMap::new(), log: VecDeque::new(), next_entry_id: 0 };
+        acl.users = match &config.aclfile {
+            Some(path) => {
+                let users = read_file(path, config.requirepass.as_deref())
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                info!(path = ?path, users = users.len(), "ACL file loaded");
+                users
+            }
+            None => initial_users(config.requirepass.as_deref()),
+        };
+        Ok(acl)
+    }
+
+    /// CONFIG SET requirepass: replaces the default user's passwords
+    pub fn set_requirepass(&mut self, password: Option<&str>) {
+        if let Some(user) = self.users.get_mut(DEFAULT_USER) {
+            user.set_requirepass(password);
+            info!("Default user password changed");
+        }
+    }
+
+    /// new connections are logged in as the default user when it needs no password
+    pub fn default_authenticates(&self) -> bool {
+        self.users.get(DEFAULT_USER).is_some_and(|u| u.enabled && u.nopass)
+    }
+
+    pub fn has_user(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+
+    fn authenticate(&self, username: &str, password: &[u8]) -> bool {
+        let Some(user) = self.users.get(username).filter(|u| u.enabled) else {
+            return false;
+        };
+        let hash = sha256_hex(password);
+        user.nopass || user.passwords.iter().any(|p| constant_time_eq(p.as_bytes(), hash.as_bytes()))
+    }
+
+    /// merges with a recent entry for the same denial, and drops the oldest beyond `max_len`
+    fn log(&mut self, max_len: usize, reason: &'static str, context: &'static str, object: String, username: &str, client_info: String) {
+        let now = now_ms();
+        let similar = self.log.iter().position(|e| {
+            e.reason == reason
+                && e.context == context
+                && e.object == object
+                && e.username == username
+                && now.saturating_sub(e.updated) < LOG_MERGE_MS
+        });
+        let entry = match similar.and_then(|i| self.log.remove(i)) {
+            Some(mut entry) => {
+                entry.count += 1;
+                entry.updated = now;
+                entry.client_info = client_info;
+                entry
+            }
+            None => {
+                self.next_entry_id += 1;
+                LogEntry {
+                    count: 1,
+                    reason,
+                    context,
+                    object,
+                    username: username.to_string(),
+                    client_info,
+                    entry_id: self.next_entry_id - 1,
+                    created: now,
+                    updated: now,
+                }
+            }
+        };
+        self.log.push_front(entry);
+        self.log.truncate(max_len);
+    }
+}
+
+impl User {
+    /// `on nopass ~* &* +@all`
+    fn default_user() -> User {
+        let mut user = User { enabled: true, nopass: true, ..User::default() };
+        for rule in ["~*", "&*", "+@all"] {
+            user.root.apply(rule).expect("default rules are valid");
+        }
+        user
+    }
+
+    fn set_requirepass(&mut self, password: Option<&str>) {
+        self.passwords.clear();
+        match password {
+            Some(password) => {
+                self.nopass = false;
+                self.passwords.push(sha256_hex(password.as_bytes()));
+            }
+            None => self.nopass = true,
+        }
+    }
+
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "reset" => *self = User::default(),
+            "clearselectors" => self.selectors.clear(),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.add_password(sha256_hex(password.as_bytes()));
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    self.remove_password(&sha256_hex(password.as_bytes()))?;
+                } else if let Some(hash) = rule.strip_prefix('#') {
+                    self.add_password(valid_hash(hash)?);
+                } else if let Some(hash) = rule.strip_prefix('!') {
+                    self.remove_password(&valid_hash(hash)?)?;
+                } else if let Some(rules) = rule.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+                    let mut selector = Selector::default();
+                    for rule in rules.split_whitespace() {
+                        selector.apply(rule)?;
+                    }
+                    self.selectors.push(selector);
+                } else {
+                    self.root.apply(rule)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn remove_password(&mut self, hash: &str) -> Result<(), String> {
+        let before = self.passwords.len();
+        self.passwords.retain(|p| p != hash);
+        if self.passwords.len() == before {
+            return Err("The password you are trying to remove from the user does not exist".to_string());
+        }
+        Ok(())
+    }
+
+    /// the root selector's denial when none of the selectors allows the call
+    fn check(&self, spec: &Command, items: &[RespOrig]) -> Result<(), Denial> {
+        let denial = match self.root.check(spec, items) {
+            Ok(()) => return Ok(()),
+            Err(denial) => denial,
+        };
+        if self.selectors.iter().any(|s| s.check(spec, items).is_ok()) {
+            return Ok(());
+        }
+        Err(denial)
+    }
+
+    /// the rules that recreate this user, as ACL LIST and ACL SAVE write them
+    fn describe(&self) -> String {
+        let mut words = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            words.push("nopass".to_string());
+        }
+        words.extend(self.passwords.iter().map(|p| format!("#{p}")));
+        words.push(self.root.describe());
+        words.extend(self.selectors.iter().map(|s| format!("({})", s.describe())));
+        words.join(" ")
+    }
+}
+
+impl Selector {
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "allkeys" => return self.apply("~*"),
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => return self.apply("&*"),
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => return self.apply("+@all"),
+            "nocommands" => return self.apply("-@all"),
+            _ => {
+                if let Some(pattern) = rule.strip_prefix('~') {
+                    self.add_keys(pattern, true, true);
+                } else if let Some(rest) = rule.strip_prefix('%') {
+                    let (perms, pattern) = rest.split_once('~').ok_or("Syntax error")?;
+                    let (read, write) = (perms.contains(['R', 'r']), perms.contains(['W', 'w']));
+                    if perms.is_empty() || perms.chars().any(|c| !"RWrw".contains(c)) {
+                        return Err("Syntax error".to_string());
+                    }
+                    self.add_keys(pattern, read, write);
+                } else if let Some(pattern) = rule.strip_prefix('&') {
+                    if !self.channels.iter().any(|c| c == pattern) {
+                        self.channels.push(pattern.to_string());
+                    }
+                } else if let Some(name) = rule.strip_prefix('+') {
+                    self.add_command(true, name)?;
+                } else if let Some(name) = rule.strip_prefix('-') {
+                    self.add_command(false, name)?;
+                } else {
+                    return Err("Syntax error".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_keys(&mut self, pattern: &str, read: bool, write: bool) {
+        match self.keys.iter_mut().find(|k| k.pattern == pattern) {
+            Some(existing) => {
+                existing.read |= read;
+                existing.write |= write;
+            }
+            None => self.keys.push(KeyPattern { pattern: pattern.to_string(), read, write }),
+        }
+    }
+
+    /// a rule replaces earlier ones for the same target, and `@all` replaces everything
+    fn add_command(&mut self, allow: bool, name: &str) -> Result<(), String> {
+        let unknown = || "Unknown command or category name in ACL".to_string();
+        let name = name.to_lowercase();
+        let target = if let Some(category) = name.strip_prefix('@') {
+            match category {
+                "all" => Target::All,
+                _ => Target::Category(CATEGORIES.iter().position(|c| *c == category).ok_or_else(unknown)?),
+            }
+        } else {
+            let (command, sub) = match name.split_once('|') {
+                Some((command, sub)) if !sub.is_empty() => (command, Some(sub)),
+                Some(_) => return Err(unknown()),
+                None => (name.as_str(), None),
+            };
+            commands::lookup(&command.to_uppercase()).ok_or_else(unknown)?;
+            match sub {
+                Some(sub) => Target::Subcommand(command.to_string(), sub.to_string()),
+                None => Target::Command(command.to_string()),
+            }
+        };
+        match &target {
+            Target::All => self.commands.clear(),
+            Target::Command(command) => self.commands.retain(|r| match &r.target {
+                Target::Command(c) | Target::Subcommand(c, _) => c != command,
+                _ => true,
+            }),
+            target => self.commands.retain(|r| r.target != *target),
+        }
+        self.commands.push(CommandRule { allow, target });
+        Ok(())
+    }
+
+    fn allows_command(&self, spec: &Command, sub: Option<&str>) -> bool {
+        let name = spec.name.to_lowercase();
+        let categories = spec.categories();
+        self.commands.iter().fold(false, |allowed, rule| {
+            let matches = match &rule.target {
+                Target::All => true,
+                Target::Category(index) => categories & (1 << index) != 0,
+                Target::Command(command) => *command == name,
+                Target::Subcommand(command, s) => *command == name && Some(s.as_str()) == sub,
+            };
+            if matches {
+                rule.allow
+            } else {
+                allowed
+            }
+        })
+    }
+
+    /// one pattern has to grant all the access asked for, see `Command::key_access`
+    fn allows_key(&self, key: &[u8], (read, write): (bool, bool)) -> bool {
+        self.keys.iter().any(|k| {
+            (k.read || !read) && (k.write || !write) && glob::matches(k.pattern.as_bytes(), key, false)
+        })
+    }
+
+    /// a PSUBSCRIBE pattern must be allowed literally, since it stands for many channels
+    fn allows_channel(&self, channel: &[u8], literal: bool) -> bool {
+        self.channels.iter().any(|c| {
+            c == "*" || if literal { c.as_bytes() == channel } else { glob::matches(c.as_bytes(), channel, false) }
+        })
+    }
+
+    fn check(&self, spec: &Command, items: &[RespOrig]) -> Result<(), Denial> {
+        let sub = items.get(1).and_then(arg_str).map(str::to_lowercase);
+        if !self.allows_command(spec, sub.as_deref()) {
+            return Err(Denial::Command(command_object(spec, items)));
+        }
+        if !spec.has_shard_channels() {
+            for index in spec.key_indexes(items) {
+                let Some(key) = items[index].as_bytes() else { continue };
+                if !self.allows_key(key, spec.key_access()) {
+                    return Err(Denial::Key(String::from_utf8_lossy(key).into_owned()));
+                }
+            }
+        }
+        let (channels, literal) = match spec.name {
+            "PUBLISH" | "SPUBLISH" => (items.get(1..2).unwrap_or_default(), false),
+            "SUBSCRIBE" | "SSUBSCRIBE" => (items.get(1..).unwrap_or_default(), false),
+            "PSUBSCRIBE" => (items.get(1..).unwrap_or_default(), true),
+            _ => (&[][..], false),
+        };
+        for channel in channels.iter().filter_map(RespOrig::as_bytes) {
+            if !self.allows_channel(channel, literal) {
+                return Err(Denial::Channel(String::from_utf8_lossy(channel).into_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    fn keys_rule(&self) -> String {
+        let patterns: Vec<String> = self
+            .keys
+            .iter()
+            .map(|k| match (k.read, k.write) {
+                (true, true) => format!("~{}", k.pattern),
+                (true, false) => format!("%R~{}", k.pattern),
+                _ => format!("%W~{}", k.pattern),
+            })
+            .collect();
+        patterns.join(" ")
+    }
+
+    fn channels_rule(&self) -> String {
+        let patterns: Vec<String> = self.channels.iter().map(|c| format!("&{c}")).collect();
+        patterns.join(" ")
+    }
+
+    fn commands_rule(&self) -> String {
+        if self.commands.is_empty() {
+            return "-@all".to_string();
+        }
+        let rules: Vec<String> = self
+            .commands
+            .iter()
+            .map(|rule| {
+                let sign = if rule.allow { '+' } else { '-' };
+                match &rule.target {
+                    Target::All => format!("{sign}@all"),
+                    Target::Category(index) => format!("{sign}@{}", CATEGORIES[*index]),
+                    Target::Command(command) => format!("{sign}{command}"),
+                    Target::Subcommand(command, sub) => format!("{sign}{command}|{sub}"),
+                }
+            })
+            .collect();
+        rules.join(" ")
+    }
+
+    fn describe(&self) -> String {
+        let mut words = Vec::new();
+        if !self.keys.is_empty() {
+            words.push(self.keys_rule());
+        }
+        if self.channels.iter().any(|c| c == "*") {
+            words.push("&*".to_string());
+        } else {
+            words.push("resetchannels".to_string());
+            if !self.channels.is_empty() {
+                words.push(self.channels_rule());
+            }
+        }
+        words.push(self.commands_rule());
+        words.join(" ")
+    }
+
+    /// the fields ACL GETUSER reports for a selector
+    fn fields(&self) -> Vec<RespOrig> {
+        vec![
+            bulk("commands"),
+            bulk(self.commands_rule()),
+            bulk("keys"),
+            bulk(self.keys_rule()),
+            bulk("channels"),
+            bulk(self.channels_rule()),
+        ]
+    }
+}
+
+impl Denial {
+    fn reason(&self) -> &'static str {
+        match self {
+            Denial::Command(_) => "command",
+            Denial::Key(_) => "key",
+            Denial::Channel(_) => "channel",
+        }
+    }
+
+    fn object(&self) -> &str {
+        match self {
+            Denial::Command(object) | Denial::Key(object) | Denial::Channel(object) => object,
+        }
+    }
+
+    fn reply(&self, username: &str) -> RespOrig {
+        match self {
+            Denial::Command(command) => {
+                error(&format!("NOPERM User {username} has no permissions to run the '{command}' command"))
+            }
+            Denial::Key(_) => error("NOPERM No permissions to access a key"),
+            Denial::Channel(_) => error("NOPERM No permissions to access a channel"),
+        }
+    }
+
+    /// the ACL DRYRUN answer
+    fn explain(&self, username: &str) -> String {
+        match self {
+            Denial::Command(command) => format!("User {username} has no permissions to run the '{command}' command"),
+            Denial::Key(key) => format!("User {username} has no permissions to access the '{key}' key"),
+            Denial::Channel(channel) => format!("User {username} has no permissions to access the '{channel}' channel"),
+        }
+    }
+}
+
+/// NOPERM unless the connection's user may run the command. runs for every frame a client
+/// sends, before it is dispatched; a denial inside MULTI makes EXEC fail
+pub fn check(resp: &RespOrig, server: &Server, conn: &mut Connection) -> Option<RespOrig> {
+    let RespOrig::Array(items) = resp else {
+        return None;
+    };
+    let spec = commands::lookup(&items.first().and_then(arg_str)?.to_uppercase())?;
+    if spec.is_no_auth() {
+        return None;
+    }
+    let context = if conn.in_multi() { "multi" } else { "toplevel" };
+    let denied = check_call(server, conn, spec, items, context).err()?;
+    conn.flag_transaction();
+    Some(denied)
+}
+
+/// checks one call, for a client or for `redis.call` in a script. denials go to ACL LOG
+pub(crate) fn check_call(
+    server: &Server,
+    conn: &Connection,
+    spec: &Command,
+    items: &[RespOrig],
+    context: &'static str,
+) -> Result<(), RespOrig> {
+    let max_len = server.config().acllog_max_len;
+    let mut acl = server.acl.lock().unwrap();
+    let denial = match acl.users.get(&conn.user) {
+        Some(user) => match user.check(spec, items) {
+            Ok(()) => return Ok(()),
+            Err(denial) => denial,
+        },
+        // deleted since the client logged in
+        None => Denial::Command(command_object(spec, items)),
+    };
+    debug!(user = %conn.user, reason = denial.reason(), object = denial.object(), "Command denied by ACL");
+    let reply = denial.reply(&conn.user);
+    let object = denial.object().to_string();
+    acl.log(max_len, denial.reason(), context, object, &conn.user, client_info(conn));
+    Err(reply)
+}
+
+/// checks a password for AUTH and HELLO. failures are recorded in ACL LOG
+pub(crate) fn authenticate(server: &Server, conn: &Connection, username: &str, password: &[u8]) -> bool {
+    let max_len = server.config().acllog_max_len;
+    let mut acl = server.acl.lock().unwrap();
+    if acl.authenticate(username, password) {
+        return true;
+    }
+    acl.log(max_len, "auth", "toplevel", "AUTH".to_string(), username, client_info(conn));
+    false
+}
+
+/// ACL SETUSER | GETUSER | DELUSER | LIST | USERS | WHOAMI | CAT | DRYRUN | LOG | SAVE | LOAD | GENPASS
+pub fn command(args: &[RespOrig], server: &Server, conn: &Connection) -> RespOrig {
+    let Some(sub) = args.first().and_then(arg_str) else {
+        return wrong_arity("acl");
+    };
+    let sub = sub.to_uppercase();
+    let args = &args[1..];
+    match (sub.as_str(), args) {
+        ("SETUSER", [name, rules @ ..]) => setuser(server, name, rules),
+        ("GETUSER", [name]) => getuser(server, name),
+        ("DELUSER", [_, ..]) => deluser(server, args),
+        ("LIST", []) => {
+            let acl = server.acl.lock().unwrap();
+            RespOrig::Array(acl.users.iter().map(|(name, user)| bulk(format!("user {name} {}", user.describe()))).collect())
+        }
+        ("USERS", []) => RespOrig::Array(server.acl.lock().unwrap().users.keys().map(|name| bulk(name.clone())).collect()),
+        ("WHOAMI", []) => bulk(conn.user.clone()),
+        ("CAT", []) => RespOrig::Array(CATEGORIES.iter().map(|c| bulk(*c)).collect()),
+        ("CAT", [category]) => cat(category),
+        ("DRYRUN", [username, command, ..]) => dryrun(server, username, command, &args[1..]),
+        ("LOG", []) => log(server, usize::MAX),
+        ("LOG", [arg]) if arg_str(arg).is_some_and(|a| a.eq_ignore_ascii_case("RESET")) => {
+            server.acl.lock().unwrap().log.clear();
+            ok()
+        }
+        ("LOG", [count]) => match arg_int(count).and_then(|c| usize::try_from(c).ok()) {
+            Some(count) => log(server, count),
+            None => error("ERR value is out of range, must be positive"),
+        },
+        ("SAVE", []) => save(server),
+        ("LOAD", []) => load(server),
+        ("GENPASS", []) => genpass(256),
+        ("GENPASS", [bits]) => match arg_int(bits).filter(|b| (1..=4096).contains(b)) {
+            Some(bits) => genpass(bits as usize),
+            None => error(
+                "ERR ACL GENPASS argument must be the number of bits for the output password, a positive number up to 4096",
+            ),
+        },
+        (
+            "SETUSER" | "GETUSER" | "DELUSER" | "LIST" | "USERS" | "WHOAMI" | "CAT" | "DRYRUN" | "LOG" | "SAVE"
+            | "LOAD" | "GENPASS",
+            _,
+        ) => wrong_arity(&format!("acl|{}", sub.to_lowercase())),
+        _ => error(&format!("ERR unknown subcommand '{}'. Try ACL HELP.", sub.to_lowercase())),
+    }
+}
+
+/// creates the user if needed; either every rule applies or none does
+fn setuser(server: &Server, name: &RespOrig, rules: &[RespOrig]) -> RespOrig {
+    let (Some(name), Some(rules)) = (arg_str(name), rules.iter().map(arg_str).collect::<Option<Vec<&str>>>()) else {
+        return error("ERR Error in ACL SETUSER modifier: arguments must be valid utf-8");
+    };
+    let rules = match join_selectors(rules.into_iter().map(str::to_string).collect()) {
+        Ok(rules) => rules,
+        Err(e) => return error(&format!("ERR {e}")),
+    };
+    let mut acl = server.acl.lock().unwrap();
+    let mut user = acl.users.get(name).cloned().unwrap_or_default();
+    for rule in &rules {
+        if let Err(e) = user.apply(rule) {
+            return error(&format!("ERR Error in ACL SETUSER modifier '{rule}': {e}"));
+        }
+    }
+    acl.users.insert(name.to_string(), user);
+    info!(user = name, "ACL user updated");
+    ok()
+}
+
+fn getuser(server: &Server, name: &RespOrig) -> RespOrig {
+    let acl = server.acl.lock().unwrap();
+    let Some(user) = arg_str(name).and_then(|name| acl.users.get(name)) else {
+        return RespOrig::NullArray;
+    };
+    let mut flags = vec![bulk(if user.enabled { "on" } else { "off" })];
+    if user.nopass {
+        flags.push(bulk("nopass"));
+    }
+    let mut fields = vec![
+        bulk("flags"),
+        RespOrig::Array(flags),
+        bulk("passwords"),
+        RespOrig::Array(user.passwords.iter().map(|p| bulk(p.clone())).collect()),
+    ];
+    fields.extend(user.root.fields());
+    fields.push(bulk("selectors"));
+    fields.push(RespOrig::Array(user.selectors.iter().map(|s| RespOrig::Array(s.fields())).collect()));
+    RespOrig::Array(fields)
+}
+
+fn deluser(server: &Server, names: &[RespOrig]) -> RespOrig {
+    let names: Vec<&str> = names.iter().filter_map(arg_str).collect();
+    if names.contains(&DEFAULT_USER) {
+        return error("ERR The 'default' user cannot be removed");
+    }
+    let mut acl = server.acl.lock().unwrap();
+    let removed = names.iter().filter(|name| acl.users.remove(**name).is_some()).count();
+    info!(removed, "ACL users deleted");
+    RespOrig::Int(removed as i64)
+}
+
+fn cat(category: &RespOrig) -> RespOrig {
+    let name = arg_str(category).unwrap_or_default().to_lowercase();
+    let Some(index) = CATEGORIES.iter().position(|c| *c == name) else {
+        return error(&format!("ERR Unknown category '{name}'"));
+    };
+    let names = commands::all()
+        .iter()
+        .filter(|c| c.categories() & (1 << index) != 0)
+        .map(|c| bulk(c.name.to_lowercase()))
+        .collect();
+    RespOrig::Array(names)
+}
+
+/// whether `username` could run a command, without running it or logging anything
+fn dryrun(server: &Server, username: &RespOrig, command: &RespOrig, items: &[RespOrig]) -> RespOrig {
+    let username = arg_str(username).unwrap_or_default();
+    let command = arg_str(command).unwrap_or_default();
+    let acl = server.acl.lock().unwrap();
+    let Some(user) = acl.users.get(username) else {
+        return error(&format!("ERR User '{username}' not found"));
+    };
+    let Some(spec) = commands::lookup(&command.to_uppercase()) else {
+        return error(&format!("ERR Command '{command}' not found"));
+    };
+    let argc = items.len() as i32;
+    if (spec.arity > 0 && argc != spec.arity) || argc < -spec.arity {
+        return wrong_arity(&command.to_lowercase());
+    }
+    match user.check(spec, items) {
+        Ok(()) => ok(),
+        Err(denial) => bulk(denial.explain(username)),
+    }
+}
+
+fn log(server: &Server, count: usize) -> RespOrig {
+    let now = now_ms();
+    let acl = server.acl.lock().unwrap();
+    let entries = acl
+        .log
+        .iter()
+        .take(count)
+        .map(|e| {
+            RespOrig::Array(vec![
+                bulk("count"),
+                RespOrig::Int(e.count as i64),
+                bulk("reason"),
+                bulk(e.reason),
+                bulk("context"),
+                bulk(e.context),
+                bulk("object"),
+                bulk(e.object.clone()),
+                bulk("username"),
+                bulk(e.username.clone()),
+                bulk("age-seconds"),
+                bulk(format!("{:.3}", now.saturating_sub(e.created) as f64 / 1000.0)),
+                bulk("client-info"),
+                bulk(e.client_info.clone()),
+                bulk("entry-id"),
+                RespOrig::Int(e.entry_id as i64),
+                bulk("timestamp-created"),
+                RespOrig::Int(e.created as i64),
+                bulk("timestamp-last-updated"),
+                RespOrig::Int(e.updated as i64),
+            ])
+        })
+        .collect();
+    RespOrig::Array(entries)
+}
+
+/// writes every user to `aclfile`, replacing it in one rename
+fn save(server: &Server) -> RespOrig {
+    let Some(path) = server.config().aclfile.clone() else {
+        return error(NO_ACLFILE);
+    };
+    let text: String = {
+        let acl = server.acl.lock().unwrap();
+        acl.users.iter().map(|(name, user)| format!("user {name} {}\n", user.describe())).collect()
+    };
+    let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+    match fs::write(&tmp, text).and_then(|()| fs::rename(&tmp, &path)) {
+        Ok(()) => {
+            info!(path = ?path, "ACL file saved");
+            ok()
+        }
+        Err(e) => {
+            warn!(error = ?e, path = ?path, "Failed to save the ACL file");
+            error("ERR There was an error trying to save the ACLs. Please check the server logs for more information")
+        }
+    }
+}
+
+/// replaces every user with the ones in `aclfile`, or changes nothing if it has an error
+fn load(server: &Server) -> RespOrig {
+    let (path, requirepass) = {
+        let config = server.config();
+        (config.aclfile.clone(), config.requirepass.clone())
+    };
+    let Some(path) = path else {
+        return error(NO_ACLFILE);
+    };
+    match read_file(&path, requirepass.as_deref()) {
+        Ok(users) => {
+            info!(path = ?path, users = users.len(), "ACL file reloaded");
+            server.acl.lock().unwrap().users = users;
+            ok()
+        }
+        Err(e) => error(&format!("ERR {e}")),
+    }
+}
+
+fn genpass(bits: usize) -> RespOrig {
+    let mut random = vec![0u8; bits.div_ceil(8)];
+    if let Err(e) = fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut random)) {
+        warn!(error = ?e, "Failed to read random bytes");
+        return error("ERR Failed to generate a password");
+    }
+    let mut password: String = random.iter().map(|b| format!("{b:02x}")).collect();
+    password.truncate(bits.div_ceil(4));
+    bulk(password)
+}
+
+/// the default user, with `requirepass` as its password if there is one
+fn initial_users(requirepass: Option<&str>) -> BTreeMap<String, User> {
+    let mut user = User::default_user();
+    if requirepass.is_some() {
+        user.set_requirepass(requirepass);
+    }
+    BTreeMap::from([(DEFAULT_USER.to_string(), user)])
+}
+
+/// `user <name> <rules...>` lines. a default user the file does not mention stays as
+/// `requirepass` makes it
+fn read_file(path: &Path, requirepass: Option<&str>) -> Result<BTreeMap<String, User>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Error loading ACLs, opening file '{}': {e}", path.display()))?;
+    let mut users = initial_users(requirepass);
+    let mut seen = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let failed = |e: &str| format!("{}:{}: {e}", path.display(), number + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = split_args(line).map_err(|e| failed(&e))?;
+        let [keyword, name, rules @ ..] = &words[..] else {
+            return Err(failed("line should start with user keyword"));
+        };
+        if keyword != "user" {
+            return Err(failed("line should start with user keyword"));
+        }
+        if seen.contains(name) {
+            return Err(failed(&format!("duplicate user '{name}' found")));
+        }
+        seen.push(name.clone());
+        let mut user = User::default();
+        for rule in join_selectors(rules.to_vec()).map_err(|e| failed(&e))? {
+            user.apply(&rule).map_err(|e| failed(&format!("{e}. Use ACL SETUSER to check the rule '{rule}'")))?;
+        }
+        users.insert(name.clone(), user);
+    }
+    Ok(users)
+}
+
+/// a selector such as `(~app:* +get)` arrives split at its spaces, this joins it back
+fn join_selectors(words: Vec<String>) -> Result<Vec<String>, String> {
+    let mut rules: Vec<String> = Vec::new();
+    let mut open: Option<String> = None;
+    for word in words {
+        match open.take() {
+            Some(mut selector) => {
+                selector.push(' ');
+                selector.push_str(&word);
+                if word.ends_with(')') {
+                    rules.push(selector);
+                } else {
+                    open = Some(selector);
+                }
+            }
+            None if word.starts_with('(') && !word.ends_with(')') => open = Some(word),
+            None => rules.push(word),
+        }
+    }
+    match open {
+        Some(selector) => Err(format!("Unmatched parenthesis in acl selector starting at '{selector}'.")),
+        None => Ok(rules),
+    }
+}
+
+/// the name ACL reports for a call: `config|get` for commands with subcommands
+pub(crate) fn command_object(spec: &Command, items: &[RespOrig]) -> String {
+    let name = spec.name.to_lowercase();
+    match items.get(1).and_then(arg_str).filter(|_| spec.has_subcommands()) {
+        Some(sub) => format!("{name}|{}", sub.to_lowercase()),
+        None => name,
+    }
+}
+
+fn client_info(conn: &Connection) -> String {
+    format!("db={} user={}", conn.db, conn.user)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// `#` and `!` take the hash exactly as ACL LIST shows it
+fn valid_hash(hash: &str) -> Result<String, String> {
+    if hash.len() != 64 || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+    }
+    Ok(hash.to_string())
+}
+
+/// compares without returning early, so the time taken does not reveal the password
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn bulk(s: impl Into<String>) -> RespOrig {
+    RespOrig::BulkString(Bytes::from(s.into()))
+}
@@ -0,0 +1,139 @@
+use crate::acl::{self, DEFAULT_USER};
+use crate::commands::{self, Command};
+use crate::connection::Connection;
+use crate::handler::{arg_int, arg_str, error, ok};
+use crate::parser::RespOrig;
+use crate::server::Server;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use tracing::{debug, warn};
+
+const PROTECTED_MODE: &str = "DENIED Redis is running in protected mode because protected mode is enabled \
+    and no password is set for the default user. In this mode connections are only accepted from the \
+    loopback interface. If you want to connect from external computers to Redis you may adopt one of the \
+    following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' \
+    from the loopback interface by connecting to Redis from the same host the server is running, however \
+    MAKE SURE Redis is not publicly accessible from internet if you do so. Use CONFIG REWRITE to make this \
+    change permanent. 2) Alternatively you can just disable the protected mode by editing the Redis \
+    configuration file, and setting the protected mode option to 'no', and then restarting the server. \
+    3) If you started the server manually just for testing, restart it with the '--protected-mode no' \
+    option. 4) Set up an authentication password for the default user. NOTE: You only need to do one of \
+    the above things in order for the server to start accepting connections from the outside.";
+
+/// refuses everything but the commands that log in until the connection is authenticated
+pub fn check(resp: &RespOrig, conn: &Connection) -> Option<RespOrig> {
+    if conn.authenticated {
+        return None;
+    }
+    let RespOrig::Array(items) = resp else {
+        return None;
+    };
+    let name = items.first().and_then(arg_str)?;
+    if commands::lookup(&name.to_uppercase()).is_some_and(Command::is_no_auth) {
+        return None;
+    }
+    debug!(command = name, "Command refused before authentication");
+    Some(error("NOAUTH Authentication required."))
+}
+
+/// with `protected-mode yes` and no password on the default user, clients other than
+/// loopback and unix socket ones are told why and disconnected
+pub fn protected_mode(server: &Server, peer: Option<SocketAddr>) -> Option<RespOrig> {
+    let peer = peer?;
+    if peer.ip().to_canonical().is_loopback() || !server.config().protected_mode {
+        return None;
+    }
+    if !server.acl.lock().unwrap().default_authenticates() {
+        return None;
+    }
+    warn!(%peer, "Refused a client in protected mode");
+    Some(error(PROTECTED_MODE))
+}
+
+/// AUTH [username] password
+pub fn auth(args: &[RespOrig], server: &Server, conn: &mut Connection) -> RespOrig {
+    let (username, password) = match args {
+        [password] => (None, password),
+        [username, password] => (arg_str(username), password),
+        _ => return error("ERR syntax error"),
+    };
+    if username.is_none() && server.acl.lock().unwrap().default_authenticates() {
+        return error(
+            "ERR AUTH <password> called without any password configured for the default user. \
+             Are you sure your configuration is correct?",
+        );
+    }
+    login(server, conn, username.unwrap_or(DEFAULT_USER), password.as_bytes())
+}
+
+/// HELLO [protover [AUTH username password]]. only RESP2 is spoken, so the reply is a flat
+/// array of field names and values
+pub fn hello(args: &[RespOrig], server: &Server, conn: &mut Connection) -> RespOrig {
+    if let Some(protover) = args.first() {
+        match arg_int(protover) {
+            Some(2) => {}
+            Some(3) => return error("NOPROTO unsupported protocol version"),
+            _ => return error("ERR Protocol version is not an integer or out of range"),
+        }
+    }
+    match args.get(1..).unwrap_or_default() {
+        [] => {}
+        [option, username, password] if arg_str(option).is_some_and(|o| o.eq_ignore_ascii_case("AUTH")) => {
+            let Some(username) = arg_str(username) else {
+                return error("ERR syntax error");
+            };
+            let reply = login(server, conn, username, password.as_bytes());
+            if matches!(reply, RespOrig::Error(_)) {
+                return reply;
+            }
+        }
+        [option, ..] => {
+            let option = arg_str(option).unwrap_or_default();
+            return error(&format!("ERR Syntax error in HELLO option '{option}'"));
+        }
+    }
+    if !conn.authenticated {
+        return error(
+            "NOAUTH HELLO must be called with the client already authenticated, otherwise the \
+             HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and \
+             select the RESP protocol version at the same time",
+        );
+    }
+
+    let mode = if server.cluster.is_some() { "cluster" } else { "standalone" };
+    let role = match server.replication.lock().unwrap().role() {
+        "slave" => "replica",
+        role => role,
+    };
+    RespOrig::Array(vec![
+        bulk("server"),
+        bulk("redis"),
+        bulk("version"),
+        bulk(env!("CARGO_PKG_VERSION")),
+        bulk("proto"),
+        RespOrig::Int(2),
+        bulk("mode"),
+        bulk(mode),
+        bulk("role"),
+        bulk(role),
+        bulk("modules"),
+        RespOrig::Array(Vec::new()),
+    ])
+}
+
+/// logs the connection in as `username`. a failed attempt leaves it as it was
+fn login(server: &Server, conn: &mut Connection, username: &str, password: Option<&Bytes>) -> RespOrig {
+    let password = password.map_or(&[][..], |p| &p[..]);
+    if !acl::authenticate(server, conn, username, password) {
+        warn!(username, "Authentication failed");
+        return error("WRONGPASS invalid username-password pair or user is disabled.");
+    }
+    debug!(username, "Client authenticated");
+    conn.authenticated = true;
+    conn.user = username.to_string();
+    ok()
+}
+
+fn bulk(s: &str) -> RespOrig {
+    RespOrig::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+}
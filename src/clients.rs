@@ -0,0 +1,639 @@
+use crate::acl;
+use crate::commands::{self, Command};
+use crate::config::{OutputLimit, OutputLimits};
+use crate::connection::Connection;
+use crate::handler::{arg_int, arg_str, error, ok, wrong_arity};
+use crate::parser::RespOrig;
+use crate::server::Server;
+use bytes::{Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// the connected clients, for CLIENT LIST and KILL, and the CLIENT PAUSE state
+#[derive(Debug)]
+pub struct Clients {
+    table: Mutex<BTreeMap<u64, Client>>,
+    next_id: AtomicU64,
+    pause: watch::Sender<Option<Pause>>,
+    /// a copy of `client-output-buffer-limit`, which output is queued under. no other lock
+    /// is taken while holding it
+    limits: Mutex<OutputLimits>,
+}
+
+impl Clients {
+    pub fn new(limits: OutputLimits) -> Clients {
+        Clients {
+            table: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            pause: watch::channel(None).0,
+            limits: Mutex::new(limits),
+        }
+    }
+
+    pub fn set_limits(&self, limits: OutputLimits) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    /// the limit for a class of clients, the master link counts as normal. normal clients
+    /// never have more than the reply being written pending, see `Config::output_limits`
+    pub fn limit(&self, class: Type) -> OutputLimit {
+        let limits = self.limits.lock().unwrap();
+        match class {
+            Type::Normal | Type::Master => limits.normal,
+            Type::Replica => limits.replica,
+            Type::PubSub => limits.pubsub,
+        }
+    }
+}
+
+/// output queued for a client but not written yet. shared with whoever queues it: the
+/// connection loop, PUBLISH for subscribers or the replication feed for replicas
+#[derive(Debug, Default)]
+pub struct OutputBuffer {
+    bytes: AtomicUsize,
+    replies: AtomicUsize,
+    /// since when `bytes` has been over the soft limit
+    soft_since: Mutex<Option<Instant>>,
+    /// disconnects the client, see `Registration::killed`
+    kill: CancellationToken,
+}
+
+impl OutputBuffer {
+    /// accounts `len` bytes about to be queued. past `limit` the client is disconnected and
+    /// false returned, the output should be dropped then
+    pub fn push(&self, len: usize, limit: OutputLimit) -> bool {
+        if self.kill.is_cancelled() {
+            return false;
+        }
+        let bytes = (self.bytes.fetch_add(len, Ordering::Relaxed) + len) as u64;
+        self.replies.fetch_add(1, Ordering::Relaxed);
+        let hard = limit.hard > 0 && bytes >= limit.hard;
+        let soft = {
+            let mut since = self.soft_since.lock().unwrap();
+            if limit.soft > 0 && bytes >= limit.soft {
+                let since = since.get_or_insert_with(Instant::now);
+                since.elapsed() >= Duration::from_secs(limit.soft_seconds)
+            } else {
+                *since = None;
+                false
+            }
+        };
+        if hard || soft {
+            warn!(bytes, hard, soft, "Client output buffer over its limit, disconnecting");
+            self.kill.cancel();
+            return false;
+        }
+        true
+    }
+
+    /// `len` bytes of one queued reply left for the socket
+    pub fn written(&self, len: usize) {
+        self.bytes.fetch_sub(len, Ordering::Relaxed);
+        self.replies.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// accounts a reply the connection loop is about to write. false when the client is over
+/// its limit or already killed, the reply is dropped then and must not be `written`
+pub fn reply_queued(server: &Server, conn: &Connection, len: usize) -> bool {
+    let class = if conn.subscriber.is_subscribed() { Type::PubSub } else { Type::Normal };
+    conn.output.push(len, server.clients.limit(class))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pause {
+    until: Instant,
+    /// ALL rather than WRITE
+    all: bool,
+}
+
+/// the TYPE of CLIENT LIST and KILL
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Normal,
+    Master,
+    Replica,
+    PubSub,
+}
+
+impl Type {
+    fn parse(name: &str) -> Option<Type> {
+        match name.to_lowercase().as_str() {
+            "normal" => Some(Type::Normal),
+            "master" => Some(Type::Master),
+            "replica" | "slave" => Some(Type::Replica),
+            "pubsub" => Some(Type::PubSub),
+            _ => None,
+        }
+    }
+}
+
+/// what CLIENT LIST shows of a connection. the connection's task keeps it current, see
+/// `record`
+#[derive(Debug)]
+struct Client {
+    id: u64,
+    addr: String,
+    laddr: String,
+    unix: bool,
+    /// `Normal` for anything but the replication links, pubsub is told by `subscriptions`
+    role: Type,
+    created: Instant,
+    last_interaction: Instant,
+    name: Option<String>,
+    user: String,
+    db: usize,
+    /// channels, patterns and shard channels
+    subscriptions: (usize, usize, usize),
+    /// commands queued since MULTI
+    multi: Option<usize>,
+    watch: usize,
+    qbuf: usize,
+    qbuf_free: usize,
+    /// the last command run, `config|get` style
+    cmd: Option<String>,
+    closing: bool,
+    /// waiting in WAIT, MIGRATE or a pause rather than for input, see `blocked`
+    blocked: bool,
+    no_evict: bool,
+    no_touch: bool,
+    output: Arc<OutputBuffer>,
+}
+
+impl Client {
+    fn client_type(&self) -> Type {
+        let (sub, psub, ssub) = self.subscriptions;
+        match self.role {
+            Type::Normal if sub + psub + ssub > 0 => Type::PubSub,
+            role => role,
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        for (set, flag) in [
+            (self.role == Type::Replica, 'S'),
+            (self.role == Type::Master, 'M'),
+            (self.client_type() == Type::PubSub, 'P'),
+            (self.multi.is_some(), 'x'),
+            (self.closing, 'c'),
+            (self.unix, 'U'),
+            (self.no_evict, 'e'),
+            (self.no_touch, 'T'),
+        ] {
+            if set {
+                flags.push(flag);
+            }
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+
+    /// a CLIENT LIST line, without the newline
+    fn line(&self, now: Instant) -> String {
+        let (sub, psub, ssub) = self.subscriptions;
+        let omem = self.output.bytes.load(Ordering::Relaxed);
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} sub={sub} psub={psub} ssub={ssub} \
+             multi={} watch={} qbuf={} qbuf-free={} obl=0 oll={} omem={omem} tot-mem={} cmd={} user={} resp=2",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.name.as_deref().unwrap_or_default(),
+            (now - self.created).as_secs(),
+            (now - self.last_interaction).as_secs(),
+            self.flags(),
+            self.db,
+            self.multi.map_or(-1, |queued| queued as i64),
+            self.watch,
+            self.qbuf,
+            self.qbuf_free,
+            self.output.replies.load(Ordering::Relaxed),
+            self.qbuf + self.qbuf_free + omem,
+            self.cmd.as_deref().unwrap_or("NULL"),
+            self.user,
+        )
+    }
+}
+
+/// a client's entry in the table, removed when this is dropped
+#[derive(Debug)]
+pub struct Registration {
+    server: Arc<Server>,
+    pub id: u64,
+    pub output: Arc<OutputBuffer>,
+}
+
+impl Registration {
+    /// resolves once CLIENT KILL picked this client or its output went over the limit, its
+    /// task should then drop the connection
+    pub async fn killed(&self) {
+        self.output.kill.cancelled().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.server.clients.table.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// adds a client to the table. `addr` is the peer, `laddr` our side of the connection.
+/// a normal client is refused with the error to send it once `maxclients` are connected,
+/// counted under the same lock as the insert so concurrent accepts cannot overshoot
+pub fn register(
+    server: &Arc<Server>,
+    role: Type,
+    addr: String,
+    laddr: String,
+    unix: bool,
+) -> Result<Registration, RespOrig> {
+    let maxclients = server.config().maxclients;
+    let mut table = server.clients.table.lock().unwrap();
+    // master and replica links are in the table too, but only normal clients count
+    if role == Type::Normal && table.values().filter(|c| c.role == Type::Normal).count() >= maxclients {
+        warn!(client = %addr, maxclients, "Refusing client, maxclients reached");
+        return Err(error("ERR max number of clients reached"));
+    }
+    let id = server.clients.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let output = Arc::new(OutputBuffer::default());
+    let now = Instant::now();
+    let client = Client {
+        id,
+        addr,
+        laddr,
+        unix,
+        role,
+        created: now,
+        last_interaction: now,
+        name: None,
+        user: acl::DEFAULT_USER.to_string(),
+        db: 0,
+        subscriptions: (0, 0, 0),
+        multi: None,
+        watch: 0,
+        qbuf: 0,
+        qbuf_free: 0,
+        cmd: None,
+        closing: false,
+        blocked: false,
+        no_evict: false,
+        no_touch: false,
+        output: output.clone(),
+    };
+    debug!(id, addr = %client.addr, "Client registered");
+    table.insert(id, client);
+    Ok(Registration { server: server.clone(), id, output })
+}
+
+/// a connection that turned into a replica's link after PSYNC
+pub fn set_role(server: &Server, id: u64, role: Type) {
+    if let Some(client) = server.clients.table.lock().unwrap().get_mut(&id) {
+        client.role = role;
+    }
+}
+
+/// marks the client as blocked until the guard is dropped, which the idle `timeout` spares
+pub fn blocked(server: &Server, id: u64) -> Blocked<'_> {
+    if let Some(client) = server.clients.table.lock().unwrap().get_mut(&id) {
+        client.blocked = true;
+    }
+    Blocked { server, id }
+}
+
+pub struct Blocked<'a> {
+    server: &'a Server,
+    id: u64,
+}
+
+impl Drop for Blocked<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.server.clients.table.lock().unwrap().get_mut(&self.id) {
+            client.blocked = false;
+            client.last_interaction = Instant::now();
+        }
+    }
+}
+
+/// closes clients idle for longer than `timeout`. replication links, subscribers and
+/// blocked clients are left alone
+pub async fn close_idle_cycle(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let timeout = server.config().timeout;
+        if timeout == 0 {
+            continue;
+        }
+        let now = Instant::now();
+        let table = server.clients.table.lock().unwrap();
+        for client in table.values() {
+            let idle = (now - client.last_interaction).as_secs();
+            if idle > timeout && client.client_type() == Type::Normal && !client.blocked {
+                info!(id = client.id, addr = %client.addr, idle, "Closing idle client");
+                client.output.kill.cancel();
+            }
+        }
+    }
+}
+
+/// brings the connection's entry up to date: before it runs `frame`, or with `None` once it
+/// waits for more input. `buf` is its query buffer
+pub fn record(server: &Server, conn: &Connection, frame: Option<&RespOrig>, buf: &BytesMut) {
+    let cmd = frame.and_then(command_name);
+    let mut table = server.clients.table.lock().unwrap();
+    let Some(client) = table.get_mut(&conn.id) else {
+        return;
+    };
+    client.last_interaction = Instant::now();
+    client.name.clone_from(&conn.name);
+    client.user.clone_from(&conn.user);
+    client.db = conn.db;
+    client.subscriptions = conn.subscriber.counts();
+    client.multi = conn.transaction.as_ref().map(|t| t.queue.len());
+    client.watch = conn.watch_count();
+    client.qbuf = buf.len();
+    client.qbuf_free = buf.capacity() - buf.len();
+    client.closing = conn.quit;
+    client.no_evict = conn.no_evict;
+    client.no_touch = conn.no_touch;
+    if cmd.is_some() {
+        client.cmd = cmd;
+    }
+}
+
+fn command_name(frame: &RespOrig) -> Option<String> {
+    let RespOrig::Array(items) = frame else {
+        return None;
+    };
+    match lookup(items) {
+        Some(spec) => Some(acl::command_object(spec, items)),
+        None => Some(items.first().and_then(arg_str)?.to_lowercase()),
+    }
+}
+
+/// waits out a CLIENT PAUSE that holds back `frame`: WRITE pauses what may write, including
+/// an EXEC with writes queued, ALL pauses everything but CLIENT UNPAUSE
+pub async fn wait_unpaused(server: &Server, frame: &RespOrig, conn: &Connection) {
+    let mut pause = server.clients.pause.subscribe();
+    let mut marked = None;
+    loop {
+        let until = match *pause.borrow_and_update() {
+            Some(Pause { until, all }) if until > Instant::now() && held_back(frame, conn, all) => until,
+            _ => return,
+        };
+        debug!("Command held back by CLIENT PAUSE");
+        marked.get_or_insert_with(|| blocked(server, conn.id));
+        tokio::select! {
+            _ = tokio::time::sleep_until(until) => {},
+            _ = pause.changed() => {},
+        }
+    }
+}
+
+/// no keys expire while clients are paused, so the dataset stays as they left it
+pub fn is_paused(server: &Server) -> bool {
+    server.clients.pause.borrow().is_some_and(|p| p.until > Instant::now())
+}
+
+fn held_back(frame: &RespOrig, conn: &Connection, all: bool) -> bool {
+    let RespOrig::Array(items) = frame else {
+        return false;
+    };
+    let unpause = items.get(1).and_then(arg_str).is_some_and(|s| s.eq_ignore_ascii_case("UNPAUSE"));
+    match lookup(items) {
+        Some(spec) if spec.name == "CLIENT" && unpause => false,
+        _ if all => true,
+        Some(spec) if spec.name == "EXEC" => conn.transaction.as_ref().is_some_and(|t| {
+            t.queue.iter().any(|queued| matches!(queued, RespOrig::Array(q) if lookup(q).is_some_and(Command::may_replicate)))
+        }),
+        // queueing is fine, EXEC is what waits
+        Some(_) if conn.in_multi() => false,
+        spec => spec.is_some_and(Command::may_replicate),
+    }
+}
+
+fn lookup(items: &[RespOrig]) -> Option<&'static Command> {
+    items.first().and_then(arg_str).and_then(|name| commands::lookup(&name.to_uppercase()))
+}
+
+/// CLIENT subcommand [args]
+pub fn command(args: &[RespOrig], server: &Server, conn: &mut Connection) -> RespOrig {
+    let Some(sub) = args.first().and_then(arg_str) else {
+        return wrong_arity("client");
+    };
+    let args = &args[1..];
+    match (sub.to_uppercase().as_str(), args) {
+        ("ID", []) => RespOrig::Int(conn.id as i64),
+        ("INFO", []) => {
+            let table = server.clients.table.lock().unwrap();
+            let line = table.get(&conn.id).map(|c| c.line(Instant::now())).unwrap_or_default();
+            RespOrig::BulkString(Bytes::from(line + "\n"))
+        }
+        ("LIST", args) => list(args, server),
+        ("GETNAME", []) => match &conn.name {
+            Some(name) => RespOrig::BulkString(Bytes::copy_from_slice(name.as_bytes())),
+            None => RespOrig::NullBulkString,
+        },
+        ("SETNAME", [name]) => {
+            let name = name.as_bytes().map(|n| String::from_utf8_lossy(n).into_owned()).unwrap_or_default();
+            if name.chars().any(|c| !c.is_ascii_graphic()) {
+                return error("ERR Client names cannot contain spaces, newlines or special characters.");
+            }
+            conn.name = (!name.is_empty()).then_some(name);
+            ok()
+        }
+        ("KILL", [addr]) => {
+            let filter = Filter { addr: arg_str(addr).map(str::to_string), ..Filter::default() };
+            match kill(server, conn, &filter) {
+                0 => error("ERR No such client"),
+                _ => ok(),
+            }
+        }
+        ("KILL", args) if !args.is_empty() => match Filter::parse(args, server) {
+            Ok(filter) => RespOrig::Int(kill(server, conn, &filter) as i64),
+            Err(e) => e,
+        },
+        ("PAUSE", [timeout, mode @ ..]) if mode.len() <= 1 => {
+            let all = match mode.first().and_then(arg_str).map(str::to_uppercase).as_deref() {
+                None | Some("ALL") => true,
+                Some("WRITE") => false,
+                Some(_) => return error("ERR syntax error"),
+            };
+            match arg_int(timeout) {
+                Some(ms) if ms >= 0 => {
+                    pause(server, Duration::from_millis(ms as u64), all);
+                    ok()
+                }
+                Some(_) => error("ERR timeout is negative"),
+                None => error("ERR timeout is not an integer or out of range"),
+            }
+        }
+        ("UNPAUSE", []) => {
+            info!("Clients unpaused");
+            server.clients.pause.send_replace(None);
+            ok()
+        }
+        ("NO-EVICT" | "NO-TOUCH", [switch]) => {
+            let on = match arg_str(switch).map(str::to_uppercase).as_deref() {
+                Some("ON") => true,
+                Some("OFF") => false,
+                _ => return error("ERR syntax error"),
+            };
+            if sub.eq_ignore_ascii_case("NO-EVICT") {
+                conn.no_evict = on;
+            } else {
+                conn.no_touch = on;
+            }
+            ok()
+        }
+        (
+            "ID" | "INFO" | "GETNAME" | "SETNAME" | "KILL" | "PAUSE" | "UNPAUSE" | "NO-EVICT" | "NO-TOUCH",
+            _,
+        ) => error(&format!("ERR wrong number of arguments for 'client|{}' command", sub.to_lowercase())),
+        _ => error(&format!("ERR unknown subcommand '{sub}'. Try CLIENT HELP.")),
+    }
+}
+
+/// CLIENT LIST [TYPE type] [ID id [id ...]]
+fn list(args: &[RespOrig], server: &Server) -> RespOrig {
+    let mut kind = None;
+    let mut ids = None;
+    match args {
+        [] => {}
+        [option, name] if arg_str(option).is_some_and(|o| o.eq_ignore_ascii_case("TYPE")) => {
+            let name = arg_str(name).unwrap_or_default();
+            match Type::parse(name) {
+                Some(t) => kind = Some(t),
+                None => return error(&format!("ERR Unknown client type '{name}'")),
+            }
+        }
+        [option, list @ ..] if !list.is_empty() && arg_str(option).is_some_and(|o| o.eq_ignore_ascii_case("ID")) => {
+            let parsed: Option<Vec<u64>> =
+                list.iter().map(|id| arg_int(id).filter(|&id| id > 0).map(|id| id as u64)).collect();
+            match parsed {
+                Some(parsed) => ids = Some(parsed),
+                None => return error("ERR Invalid client ID"),
+            }
+        }
+        _ => return error("ERR syntax error"),
+    }
+
+    let now = Instant::now();
+    let table = server.clients.table.lock().unwrap();
+    let out: String = table
+        .values()
+        .filter(|c| kind.is_none_or(|t| c.client_type() == t))
+        .filter(|c| ids.as_ref().is_none_or(|ids| ids.contains(&c.id)))
+        .map(|c| c.line(now) + "\n")
+        .collect();
+    RespOrig::BulkString(Bytes::from(out))
+}
+
+/// which clients CLIENT KILL picks. every given condition has to hold
+#[derive(Debug, Default)]
+struct Filter {
+    id: Option<u64>,
+    addr: Option<String>,
+    laddr: Option<String>,
+    user: Option<String>,
+    kind: Option<Type>,
+    /// connected for longer than this many seconds
+    maxage: Option<u64>,
+    /// leave the calling client alone
+    skipme: bool,
+}
+
+impl Filter {
+    /// the filter form: ID, ADDR, LADDR, USER, TYPE, MAXAGE and SKIPME pairs
+    fn parse(args: &[RespOrig], server: &Server) -> Result<Filter, RespOrig> {
+        let mut filter = Filter { skipme: true, ..Filter::default() };
+        let mut pairs = args.chunks_exact(2);
+        for pair in pairs.by_ref() {
+            let (Some(option), Some(value)) = (arg_str(&pair[0]), arg_str(&pair[1])) else {
+                return Err(error("ERR syntax error"));
+            };
+            match option.to_uppercase().as_str() {
+                "ID" => match value.parse::<u64>() {
+                    Ok(id) if id > 0 => filter.id = Some(id),
+                    _ => return Err(error("ERR client-id should be greater than 0")),
+                },
+                "ADDR" => filter.addr = Some(value.to_string()),
+                "LADDR" => filter.laddr = Some(value.to_string()),
+                "USER" => {
+                    if !server.acl.lock().unwrap().has_user(value) {
+                        return Err(error(&format!("ERR No such user '{value}'")));
+                    }
+                    filter.user = Some(value.to_string());
+                }
+                "TYPE" => match Type::parse(value) {
+                    Some(kind) => filter.kind = Some(kind),
+                    None => return Err(error(&format!("ERR Unknown client type '{value}'"))),
+                },
+                "MAXAGE" => match value.parse::<u64>() {
+                    Ok(maxage) => filter.maxage = Some(maxage),
+                    Err(_) => return Err(error("ERR syntax error")),
+                },
+                "SKIPME" => match value.to_lowercase().as_str() {
+                    "yes" => filter.skipme = true,
+                    "no" => filter.skipme = false,
+                    _ => return Err(error("ERR syntax error")),
+                },
+                _ => return Err(error("ERR syntax error")),
+            }
+        }
+        if !pairs.remainder().is_empty() {
+            return Err(error("ERR syntax error"));
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, client: &Client, me: u64, now: Instant) -> bool {
+        !(self.skipme && client.id == me)
+            && self.id.is_none_or(|id| client.id == id)
+            && self.addr.as_ref().is_none_or(|addr| client.addr == *addr)
+            && self.laddr.as_ref().is_none_or(|laddr| client.laddr == *laddr)
+            && self.user.as_ref().is_none_or(|user| client.user == *user)
+            && self.kind.is_none_or(|kind| client.client_type() == kind)
+            && self.maxage.is_none_or(|maxage| (now - client.created).as_secs() >= maxage)
+    }
+}
+
+/// ends the matching clients' connections and counts them. the caller's own connection
+/// closes once the reply is written
+fn kill(server: &Server, conn: &mut Connection, filter: &Filter) -> usize {
+    let now = Instant::now();
+    let table = server.clients.table.lock().unwrap();
+    let mut killed = 0;
+    for client in table.values().filter(|c| filter.matches(c, conn.id, now)) {
+        info!(id = client.id, addr = %client.addr, "Killing client");
+        if client.id == conn.id {
+            conn.quit = true;
+        } else {
+            client.output.kill.cancel();
+        }
+        killed += 1;
+    }
+    killed
+}
+
+/// a later deadline or ALL extends a running pause, it never shrinks
+fn pause(server: &Server, timeout: Duration, all: bool) {
+    let until = Instant::now() + timeout;
+    server.clients.pause.send_modify(|pause| {
+        let (until, all) = match *pause {
+            Some(current) if current.until > Instant::now() => (current.until.max(until), current.all || all),
+            _ => (until, all),
+        };
+        *pause = Some(Pause { until, all });
+    });
+    info!(?timeout, all, "Clients paused");
+}
@@ -0,0 +1,911 @@
+use crate::cluster_bus;
+use crate::commands::Command;
+use crate::handler::{arg_int, arg_str, error, ok, wrong_arity};
+use crate::parser::RespOrig;
+use crate::pubsub;
+use crate::replication;
+use crate::server::{random_id, Server};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, info, warn};
+
+pub const SLOTS: usize = 16384;
+/// the cluster bus listens on the client port plus this
+pub const BUS_PORT_OFFSET: u16 = 10000;
+
+/// CRC16/XMODEM, the variant redis uses for key slots
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// only the part inside the first non-empty `{...}` is hashed, so related keys can be
+/// forced into the same slot
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = memchr::memchr(b'{', key)
+        .and_then(|open| {
+            let rest = &key[open + 1..];
+            memchr::memchr(b'}', rest).map(|close| &rest[..close])
+        })
+        .filter(|tag| !tag.is_empty())
+        .unwrap_or(key);
+    crc16(hashed) % SLOTS as u16
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClusterNode {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub cport: u16,
+    /// master id when this node is a replica
+    pub master: Option<String>,
+    pub config_epoch: u64,
+    /// no pong within the node timeout, our own opinion only
+    pub pfail: bool,
+    /// agreed by a majority of masters
+    pub fail: bool,
+    /// unix ms timestamps, 0 when unset
+    pub ping_sent: u64,
+    pub pong_received: u64,
+    pub fail_time: u64,
+    pub voted_time: u64,
+    /// masters that gossiped this node as failing, with the time of their last report
+    pub fail_reports: HashMap<String, u64>,
+    pub repl_offset: u64,
+    /// outbound bus link, see `cluster_bus`
+    pub link: Option<UnboundedSender<Bytes>>,
+    pub link_connected: bool,
+}
+
+impl ClusterNode {
+    pub fn new(id: String, ip: String, port: u16, cport: u16) -> ClusterNode {
+        ClusterNode {
+            id,
+            ip,
+            port,
+            cport,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.master.is_none()
+    }
+}
+
+/// a replica's attempt to get elected in place of its failed master
+#[derive(Debug)]
+pub struct Election {
+    /// unix ms when the auth request goes out, delayed by rank so the best replica goes first
+    pub start_at: u64,
+    pub epoch: u64,
+    pub sent: bool,
+    pub votes: HashSet<String>,
+}
+
+/// a node we were asked to MEET and whose id we do not know yet
+#[derive(Debug)]
+pub struct Handshake {
+    pub ip: String,
+    pub port: u16,
+    pub cport: u16,
+    pub started: u64,
+    pub link: UnboundedSender<Bytes>,
+}
+
+#[derive(Debug)]
+pub struct ClusterState {
+    pub myself: String,
+    pub nodes: BTreeMap<String, ClusterNode>,
+    /// owner id of every slot
+    pub slots: Vec<Option<String>>,
+    pub current_epoch: u64,
+    pub last_vote_epoch: u64,
+    /// milliseconds without pong before a node is flagged PFAIL
+    pub node_timeout: u64,
+    pub election: Option<Election>,
+    pub handshakes: Vec<Handshake>,
+    /// forgotten nodes are not re-added from gossip until this unix ms
+    pub blacklist: HashMap<String, u64>,
+    /// slots being moved out, with the target node id
+    pub migrating: BTreeMap<usize, String>,
+    /// slots being moved in, with the source node id
+    pub importing: BTreeMap<usize, String>,
+    /// set when something persisted changed, the bus cron saves it
+    pub dirty: bool,
+    config_file: PathBuf,
+}
+
+impl ClusterState {
+    /// reads the nodes file, or creates one describing a fresh node with no slots
+    pub fn load(
+        config_file: PathBuf,
+        ip: &str,
+        port: u16,
+        node_timeout: u64,
+    ) -> Result<ClusterState, Error> {
+        let mut state = ClusterState {
+            myself: String::new(),
+            nodes: BTreeMap::new(),
+            slots: vec![None; SLOTS],
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            node_timeout,
+            election: None,
+            handshakes: Vec::new(),
+            blacklist: HashMap::new(),
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            dirty: false,
+            config_file,
+        };
+
+        match fs::read_to_string(&state.config_file) {
+            Ok(contents) => {
+                info!(file = ?state.config_file, "Loading cluster config");
+                state.parse(&contents)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(file = ?state.config_file, "No cluster config found, creating a new node");
+            }
+            Err(e) => return Err(e),
+        }
+
+        if state.myself.is_empty() {
+            state.myself = random_id();
+        }
+        let myself = state.myself.clone();
+        let node = state
+            .nodes
+            .entry(myself.clone())
+            .or_insert_with(|| ClusterNode::new(myself, String::new(), port, 0));
+        // the address always comes from the running process, not from the file
+        node.ip = ip.to_string();
+        node.port = port;
+        node.cport = port.checked_add(BUS_PORT_OFFSET).ok_or_else(|| {
+            Error::new(std::io::ErrorKind::InvalidInput, format!("port {port} leaves no room for the cluster bus port"))
+        })?;
+        info!(myself = %state.myself, nodes = state.nodes.len(), "Cluster state ready");
+        state.save()?;
+        Ok(state)
+    }
+
+    /// the nodes.conf format redis uses, which is also the CLUSTER NODES output
+    fn parse(&mut self, contents: &str) -> Result<(), Error> {
+        let invalid = |line: &str| {
+            Error::new(std::io::ErrorKind::InvalidData, format!("bad cluster config line: {line}"))
+        };
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields[0] == "vars" {
+                for pair in fields[1..].chunks(2) {
+                    match pair {
+                        ["currentEpoch", v] => self.current_epoch = v.parse().unwrap_or(0),
+                        ["lastVoteEpoch", v] => self.last_vote_epoch = v.parse().unwrap_or(0),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            if fields.len() < 8 {
+                return Err(invalid(line));
+            }
+            let (ip, port, cport) = parse_address(fields[1]).ok_or_else(|| invalid(line))?;
+            let flags: Vec<&str> = fields[2].split(',').collect();
+            let id = fields[0].to_string();
+            if flags.contains(&"myself") {
+                self.myself = id.clone();
+            }
+            let mut node = ClusterNode::new(id.clone(), ip, port, cport);
+            node.master = (fields[3] != "-").then(|| fields[3].to_string());
+            node.config_epoch = fields[6].parse().map_err(|_| invalid(line))?;
+            node.fail = flags.contains(&"fail");
+            node.pfail = flags.contains(&"fail?");
+            for range in &fields[8..] {
+                // open migrations are written in brackets, they are not ownership
+                if let Some(open) = range.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+                    let (slot, migrating, peer) = if let Some((slot, peer)) = open.split_once("->-") {
+                        (slot, true, peer)
+                    } else if let Some((slot, peer)) = open.split_once("-<-") {
+                        (slot, false, peer)
+                    } else {
+                        return Err(invalid(line));
+                    };
+                    let slot: usize = slot.parse().map_err(|_| invalid(line))?;
+                    if slot >= SLOTS {
+                        return Err(invalid(line));
+                    }
+                    let open_slots = if migrating { &mut self.migrating } else { &mut self.importing };
+                    open_slots.insert(slot, peer.to_string());
+                    continue;
+                }
+                let (start, end) = parse_range(range).ok_or_else(|| invalid(line))?;
+                for slot in start..=end {
+                    self.slots[slot] = Some(id.clone());
+                }
+            }
+            self.nodes.insert(id, node);
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let mut out = String::new();
+        for node in self.nodes.values() {
+            out.push_str(&self.node_line(node));
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "vars currentEpoch {} lastVoteEpoch {}\n",
+            self.current_epoch, self.last_vote_epoch
+        ));
+        let tmp = self.config_file.with_extension("tmp");
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, &self.config_file)?;
+        debug!(file = ?self.config_file, "Saved cluster config");
+        Ok(())
+    }
+
+    pub fn myself(&self) -> &ClusterNode {
+        &self.nodes[&self.myself]
+    }
+
+    pub fn myself_mut(&mut self) -> &mut ClusterNode {
+        self.nodes.get_mut(&self.myself).expect("myself is always known")
+    }
+
+    /// masters serving at least one slot, the ones that vote and report failures
+    pub fn size(&self) -> usize {
+        self.nodes
+            .values()
+            .filter(|n| n.is_master() && self.slots.iter().any(|s| s.as_deref() == Some(&n.id)))
+            .count()
+    }
+
+    pub fn quorum(&self) -> usize {
+        self.size() / 2 + 1
+    }
+
+    pub fn owns_slots(&self, id: &str) -> bool {
+        self.slots.iter().any(|s| s.as_deref() == Some(id))
+    }
+
+    /// contiguous slot ranges served by a node
+    pub fn slot_ranges(&self, id: &str) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_deref() != Some(id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    pub fn replicas_of<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a ClusterNode> {
+        self.nodes
+            .values()
+            .filter(move |n| n.master.as_deref() == Some(id))
+    }
+
+    pub fn slots_assigned(&self) -> usize {
+        self.slots.iter().filter(|s| s.is_some()).count()
+    }
+
+    fn slots_with_owner(&self, failing: impl Fn(&ClusterNode) -> bool) -> usize {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|owner| self.nodes.get(*owner).is_some_and(&failing))
+            .count()
+    }
+
+    /// with full coverage required, the cluster only serves when every slot has an owner
+    /// that is not failing
+    pub fn is_ok(&self) -> bool {
+        self.slots_assigned() == SLOTS && self.slots_with_owner(|n| n.fail) == 0
+    }
+
+    fn node_flags(&self, node: &ClusterNode) -> String {
+        let mut flags = Vec::new();
+        if node.id == self.myself {
+            flags.push("myself");
+        }
+        flags.push(if node.is_master() { "master" } else { "slave" });
+        if node.fail {
+            flags.push("fail");
+        } else if node.pfail {
+            flags.push("fail?");
+        }
+        flags.join(",")
+    }
+
+    /// the master whose slots this node serves: itself, or the one it replicates
+    pub fn shard_master(&self) -> &str {
+        self.myself().master.as_deref().unwrap_or(&self.myself)
+    }
+
+    /// takes a new config epoch without asking the other masters, so a slot we just
+    /// imported wins over the previous owner's claim
+    pub fn bump_config_epoch(&mut self) {
+        let max_epoch = self.nodes.values().map(|n| n.config_epoch).max().unwrap_or(0);
+        let myself = self.myself();
+        if myself.config_epoch == 0 || myself.config_epoch != max_epoch {
+            self.current_epoch = self.current_epoch.max(max_epoch) + 1;
+            let epoch = self.current_epoch;
+            self.myself_mut().config_epoch = epoch;
+            info!(epoch, "Config epoch bumped without consensus");
+        }
+    }
+
+    pub fn node_line(&self, node: &ClusterNode) -> String {
+        let connected = node.id == self.myself || node.link_connected;
+        let mut line = format!(
+            "{} {}:{}@{} {} {} {} {} {} {}",
+            node.id,
+            node.ip,
+            node.port,
+            node.cport,
+            self.node_flags(node),
+            node.master.as_deref().unwrap_or("-"),
+            node.ping_sent,
+            node.pong_received,
+            node.config_epoch,
+            if connected { "connected" } else { "disconnected" },
+        );
+        for (start, end) in self.slot_ranges(&node.id) {
+            if start == end {
+                line.push_str(&format!(" {start}"));
+            } else {
+                line.push_str(&format!(" {start}-{end}"));
+            }
+        }
+        if node.id == self.myself {
+            for (slot, target) in &self.migrating {
+                line.push_str(&format!(" [{slot}->-{target}]"));
+            }
+            for (slot, source) in &self.importing {
+                line.push_str(&format!(" [{slot}-<-{source}]"));
+            }
+        }
+        line
+    }
+}
+
+fn parse_address(field: &str) -> Option<(String, u16, u16)> {
+    let addr = field.split(',').next()?;
+    let (host_port, cport) = addr.split_once('@')?;
+    let (ip, port) = host_port.rsplit_once(':')?;
+    Some((ip.to_string(), port.parse().ok()?, cport.parse().ok()?))
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let slot = range.parse().ok()?;
+            (slot, slot)
+        }
+    };
+    (start <= end && end < SLOTS).then_some((start, end))
+}
+
+/// decides whether this node may serve a command: every key must hash to the same slot,
+/// and that slot must be ours. otherwise the client gets the error telling it where to go.
+/// a slot in transit sends missing keys to the target with ASK, and the target only serves
+/// them to clients that said ASKING first
+pub fn redirect(
+    server: &Server,
+    command: &Command,
+    items: &[RespOrig],
+    asking: bool,
+) -> Option<RespOrig> {
+    let cluster = server.cluster.as_ref()?;
+    let mut slot = None;
+    let mut keys = Vec::new();
+    for index in command.key_indexes(items) {
+        let key = items[index].as_bytes()?;
+        let key_slot = key_slot(key);
+        match slot {
+            None => slot = Some(key_slot),
+            Some(s) if s != key_slot => {
+                return Some(error("CROSSSLOT Keys in request don't hash to the same slot"));
+            }
+            Some(_) => {}
+        }
+        keys.push(key);
+    }
+    let slot = slot? as usize;
+
+    let state = cluster.lock().unwrap();
+    if !state.is_ok() {
+        return Some(error("CLUSTERDOWN The cluster is down"));
+    }
+    if command.has_shard_channels() {
+        // any node of the owning shard serves its channels, migrations do not apply
+        let owner = state.slots[slot].as_ref()?;
+        if *owner == state.myself || state.myself().master.as_ref() == Some(owner) {
+            return None;
+        }
+        let node = &state.nodes[owner];
+        return Some(error(&format!("MOVED {slot} {}:{}", node.ip, node.port)));
+    }
+    let migrating = state.migrating.get(&slot);
+    let importing = state.importing.get(&slot);
+    let missing = if migrating.is_some() || importing.is_some() {
+        // cluster mode only has database 0
+        let mut db = server.db(0);
+        keys.iter().filter(|key| db.get(key).is_none()).count()
+    } else {
+        0
+    };
+
+    // while a slot is in transit, keys we no longer have are looked up on the target
+    if let (Some(target), true) = (migrating, missing > 0) {
+        if missing < keys.len() {
+            return Some(error("TRYAGAIN Multiple keys request during rehashing of slot"));
+        }
+        let node = state.nodes.get(target)?;
+        debug!(slot, target = %node.id, "Asking client to retry on migration target");
+        return Some(error(&format!("ASK {slot} {}:{}", node.ip, node.port)));
+    }
+    if importing.is_some() && (asking || command.is_asking()) {
+        if keys.len() > 1 && missing > 0 {
+            return Some(error("TRYAGAIN Multiple keys request during rehashing of slot"));
+        }
+        return None;
+    }
+
+    let owner = state.slots[slot].as_ref()?;
+    if *owner == state.myself {
+        return None;
+    }
+    let node = &state.nodes[owner];
+    debug!(slot, owner = %node.id, "Redirecting client");
+    Some(error(&format!("MOVED {slot} {}:{}", node.ip, node.port)))
+}
+
+/// ASKING only flags the next command of the connection
+pub fn is_asking(frame: &RespOrig) -> bool {
+    match frame {
+        RespOrig::Array(items) if items.len() == 1 => {
+            arg_str(&items[0]).is_some_and(|name| name.eq_ignore_ascii_case("ASKING"))
+        }
+        _ => false,
+    }
+}
+
+/// CLUSTER SETSLOT <slot> IMPORTING <id> | MIGRATING <id> | STABLE | NODE <id>
+fn setslot(args: &[RespOrig], server: &Server, state: &mut ClusterState) -> RespOrig {
+    let (slot, action, id) = match args {
+        [slot, action] => (slot, action, None),
+        [slot, action, id] => (slot, action, arg_str(id)),
+        _ => return wrong_arity("cluster|setslot"),
+    };
+    let slot = match parse_slot(slot) {
+        Ok(slot) => slot,
+        Err(e) => return e,
+    };
+    if !state.myself().is_master() {
+        return error("ERR Please use SETSLOT only with masters.");
+    }
+    let action = arg_str(action).map(str::to_uppercase);
+    let node = match (action.as_deref(), id) {
+        (Some("STABLE"), None) => None,
+        (Some("IMPORTING" | "MIGRATING" | "NODE"), Some(id)) => match state.nodes.get(id) {
+            Some(node) => Some(node.id.clone()),
+            None => return error(&format!("ERR I don't know about node {id}")),
+        },
+        _ => return error("ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"),
+    };
+    let owner = state.slots[slot].clone();
+    let mine = owner.as_deref() == Some(state.myself.as_str());
+
+    match (action.as_deref(), node) {
+        (Some("MIGRATING"), Some(target)) => {
+            if !mine {
+                return error(&format!("ERR I'm not the owner of hash slot {slot}"));
+            }
+            if target == state.myself {
+                return error("ERR Target node is myself, can't migrate the slot to it.");
+            }
+            if !state.nodes[&target].is_master() {
+                return error("ERR Target node is not a master");
+            }
+            state.migrating.insert(slot, target);
+        }
+        (Some("IMPORTING"), Some(source)) => {
+            if mine {
+                return error(&format!("ERR I'm already the owner of hash slot {slot}"));
+            }
+            if source == state.myself {
+                return error("ERR Source node is myself, can't import the slot from it.");
+            }
+            if !state.nodes[&source].is_master() {
+                return error("ERR Source node is not a master");
+            }
+            state.importing.insert(slot, source);
+        }
+        (Some("STABLE"), None) => {
+            state.migrating.remove(&slot);
+            state.importing.remove(&slot);
+        }
+        (Some("NODE"), Some(id)) => {
+            if !state.nodes[&id].is_master() {
+                return error("ERR Target node is not a master");
+            }
+            if mine && id != state.myself && !keys_in_slot(server, slot, 1).is_empty() {
+                return error(&format!(
+                    "ERR Can't assign hashslot {slot} to a different node while I still hold keys for this hash slot."
+                ));
+            }
+            if state.migrating.get(&slot) == Some(&id) {
+                state.migrating.remove(&slot);
+            }
+            let imported = id == state.myself && state.importing.remove(&slot).is_some();
+            if owner.as_deref() == Some(state.shard_master()) && id != state.shard_master() {
+                pubsub::slots_lost(server, &[slot]);
+            }
+            state.slots[slot] = Some(id);
+            if imported {
+                state.bump_config_epoch();
+            }
+        }
+        _ => unreachable!("validated above"),
+    }
+    info!(slot, action = ?action, "Slot state changed");
+    state.dirty = true;
+    if let Err(e) = state.save() {
+        warn!(error = ?e, "Failed to save cluster config");
+    }
+    state.dirty = false;
+    ok()
+}
+
+fn bulk(s: impl Into<String>) -> RespOrig {
+    RespOrig::BulkString(Bytes::from(s.into()))
+}
+
+fn parse_slot(arg: &RespOrig) -> Result<usize, RespOrig> {
+    match arg_int(arg) {
+        Some(slot) if (0..SLOTS as i64).contains(&slot) => Ok(slot as usize),
+        _ => Err(error("ERR Invalid or out of range slot")),
+    }
+}
+
+/// slots named by ADDSLOTS / DELSLOTS or by the *RANGE variants
+fn parse_slots(args: &[RespOrig], ranges: bool) -> Result<Vec<usize>, RespOrig> {
+    if ranges {
+        let mut slots = Vec::new();
+        for pair in args.chunks(2) {
+            let [start, end] = pair else {
+                return Err(error("ERR wrong number of arguments for 'cluster|addslotsrange' command"));
+            };
+            let (start, end) = (parse_slot(start)?, parse_slot(end)?);
+            if start > end {
+                return Err(error(&format!(
+                    "ERR start slot number {start} is greater than end slot number {end}"
+                )));
+            }
+            slots.extend(start..=end);
+        }
+        Ok(slots)
+    } else {
+        args.iter().map(parse_slot).collect()
+    }
+}
+
+/// CLUSTER <subcommand> [args]
+pub fn command(args: &[RespOrig], server: &Arc<Server>) -> RespOrig {
+    let Some(cluster) = server.cluster.as_ref() else {
+        return error("ERR This instance has cluster support disabled");
+    };
+    let Some(sub) = args.first().and_then(arg_str).map(str::to_uppercase) else {
+        return wrong_arity("cluster");
+    };
+    let args = &args[1..];
+
+    match sub.as_str() {
+        "KEYSLOT" => match args {
+            [key] => match key.as_bytes() {
+                Some(key) => RespOrig::Int(key_slot(key) as i64),
+                None => wrong_arity("cluster|keyslot"),
+            },
+            _ => wrong_arity("cluster|keyslot"),
+        },
+        "COUNTKEYSINSLOT" => {
+            let [slot] = args else {
+                return wrong_arity("cluster|countkeysinslot");
+            };
+            match parse_slot(slot) {
+                Ok(slot) => RespOrig::Int(keys_in_slot(server, slot, usize::MAX).len() as i64),
+                Err(e) => e,
+            }
+        }
+        "GETKEYSINSLOT" => {
+            let [slot, count] = args else {
+                return wrong_arity("cluster|getkeysinslot");
+            };
+            let slot = match parse_slot(slot) {
+                Ok(slot) => slot,
+                Err(e) => return e,
+            };
+            match arg_int(count) {
+                Some(count) if count >= 0 => RespOrig::Array(
+                    keys_in_slot(server, slot, count as usize)
+                        .into_iter()
+                        .map(RespOrig::BulkString)
+                        .collect(),
+                ),
+                _ => error("ERR Invalid number of keys"),
+            }
+        }
+        "MYID" => bulk(cluster.lock().unwrap().myself.clone()),
+        "INFO" => bulk(info(&cluster.lock().unwrap())),
+        "NODES" => {
+            let state = cluster.lock().unwrap();
+            let mut out = String::new();
+            for node in state.nodes.values() {
+                out.push_str(&state.node_line(node));
+                out.push('\n');
+            }
+            bulk(out)
+        }
+        "SLOTS" => slots(&cluster.lock().unwrap()),
+        "SHARDS" => shards(server, &cluster.lock().unwrap()),
+        "ADDSLOTS" | "ADDSLOTSRANGE" | "DELSLOTS" | "DELSLOTSRANGE" => {
+            if args.is_empty() {
+                return wrong_arity(&format!("cluster|{}", sub.to_lowercase()));
+            }
+            let slots = match parse_slots(args, sub.ends_with("RANGE")) {
+                Ok(slots) => slots,
+                Err(e) => return e,
+            };
+            let mut state = cluster.lock().unwrap();
+            let adding = sub.starts_with("ADD");
+            for &slot in &slots {
+                match (&state.slots[slot], adding) {
+                    (Some(_), true) => return error(&format!("ERR Slot {slot} is already busy")),
+                    (None, false) => {
+                        return error(&format!("ERR Slot {slot} is already unassigned"))
+                    }
+                    _ => {}
+                }
+            }
+            if !adding {
+                let shard_master = state.shard_master();
+                let lost: Vec<usize> = slots
+                    .iter()
+                    .copied()
+                    .filter(|&slot| state.slots[slot].as_deref() == Some(shard_master))
+                    .collect();
+                pubsub::slots_lost(server, &lost);
+            }
+            let owner = adding.then(|| state.myself.clone());
+            for slot in slots {
+                state.slots[slot] = owner.clone();
+            }
+            info!(command = %sub, "Slot ownership changed");
+            if let Err(e) = state.save() {
+                warn!(error = ?e, "Failed to save cluster config");
+            }
+            ok()
+        }
+        "MEET" => {
+            let (ip, port, cport) = match args {
+                [ip, port] | [ip, port, _] => {
+                    let port = arg_int(port).and_then(|p| u16::try_from(p).ok());
+                    let cport = match args.get(2) {
+                        Some(cport) => arg_int(cport).and_then(|p| u16::try_from(p).ok()),
+                        None => port.and_then(|p| p.checked_add(BUS_PORT_OFFSET)),
+                    };
+                    match (arg_str(ip), port, cport) {
+                        (Some(ip), Some(port), Some(cport)) => (ip.to_string(), port, cport),
+                        _ => return error("ERR Invalid node address specified"),
+                    }
+                }
+                _ => return wrong_arity("cluster|meet"),
+            };
+            cluster_bus::meet(server, &mut cluster.lock().unwrap(), ip, port, cport);
+            ok()
+        }
+        "FORGET" => {
+            let [id] = args else {
+                return wrong_arity("cluster|forget");
+            };
+            let mut state = cluster.lock().unwrap();
+            match cluster_bus::forget(&mut state, arg_str(id).unwrap_or_default()) {
+                Ok(()) => ok(),
+                Err(e) => error(e),
+            }
+        }
+        "REPLICATE" => {
+            let [id] = args else {
+                return wrong_arity("cluster|replicate");
+            };
+            let target = cluster_bus::replicate(&mut cluster.lock().unwrap(), arg_str(id).unwrap_or_default());
+            match target {
+                Ok((host, port)) => {
+                    info!(%host, port, "Replicating cluster master");
+                    replication::replicaof(server, host, port);
+                    ok()
+                }
+                Err(e) => error(e),
+            }
+        }
+        "REPLICAS" | "SLAVES" => {
+            let [id] = args else {
+                return wrong_arity(&format!("cluster|{}", sub.to_lowercase()));
+            };
+            let state = cluster.lock().unwrap();
+            let id = arg_str(id).unwrap_or_default();
+            match state.nodes.get(id) {
+                Some(node) if node.is_master() => RespOrig::Array(
+                    state.replicas_of(id).map(|r| bulk(state.node_line(r))).collect(),
+                ),
+                Some(_) => error("ERR The specified node is not a master"),
+                None => error(&format!("ERR Unknown node {id}")),
+            }
+        }
+        "COUNT-FAILURE-REPORTS" => {
+            let [id] = args else {
+                return wrong_arity("cluster|count-failure-reports");
+            };
+            let state = cluster.lock().unwrap();
+            let id = arg_str(id).unwrap_or_default();
+            match state.nodes.get(id) {
+                Some(node) => RespOrig::Int(node.fail_reports.len() as i64),
+                None => error(&format!("ERR Unknown node {id}")),
+            }
+        }
+        "SETSLOT" => setslot(args, server, &mut cluster.lock().unwrap()),
+        "SAVECONFIG" => match cluster.lock().unwrap().save() {
+            Ok(()) => ok(),
+            Err(e) => error(&format!("ERR error saving the cluster node config: {e}")),
+        },
+        _ => error(&format!(
+            "ERR unknown subcommand '{}'. Try CLUSTER HELP.",
+            sub.to_lowercase()
+        )),
+    }
+}
+
+fn keys_in_slot(server: &Server, slot: usize, count: usize) -> Vec<Bytes> {
+    server
+        .db(0)
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| key_slot(key) as usize == slot)
+        .take(count)
+        .cloned()
+        .collect()
+}
+
+fn info(state: &ClusterState) -> String {
+    let assigned = state.slots_assigned();
+    let pfail = state.slots_with_owner(|n| n.pfail && !n.fail);
+    let fail = state.slots_with_owner(|n| n.fail);
+    format!(
+        "cluster_enabled:1\r\n\
+         cluster_state:{}\r\n\
+         cluster_slots_assigned:{assigned}\r\n\
+         cluster_slots_ok:{}\r\n\
+         cluster_slots_pfail:{pfail}\r\n\
+         cluster_slots_fail:{fail}\r\n\
+         cluster_known_nodes:{}\r\n\
+         cluster_size:{}\r\n\
+         cluster_current_epoch:{}\r\n\
+         cluster_my_epoch:{}\r\n",
+        if state.is_ok() { "ok" } else { "fail" },
+        assigned - pfail - fail,
+        state.nodes.len(),
+        state.size(),
+        state.current_epoch,
+        state.myself().config_epoch,
+    )
+}
+
+fn node_endpoint(node: &ClusterNode) -> Vec<RespOrig> {
+    vec![bulk(node.ip.clone()), RespOrig::Int(node.port as i64), bulk(node.id.clone())]
+}
+
+/// CLUSTER SLOTS: every slot range with its master followed by the master's replicas
+fn slots(state: &ClusterState) -> RespOrig {
+    let mut ranges = Vec::new();
+    for node in state.nodes.values().filter(|n| n.is_master()) {
+        for (start, end) in state.slot_ranges(&node.id) {
+            let mut entry = vec![
+                RespOrig::Int(start as i64),
+                RespOrig::Int(end as i64),
+                RespOrig::Array(node_endpoint(node)),
+            ];
+            entry.extend(state.replicas_of(&node.id).map(|r| RespOrig::Array(node_endpoint(r))));
+            ranges.push((start, RespOrig::Array(entry)));
+        }
+    }
+    ranges.sort_by_key(|(start, _)| *start);
+    RespOrig::Array(ranges.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// CLUSTER SHARDS: one entry per master with its slot ranges and every node of the shard
+fn shards(server: &Server, state: &ClusterState) -> RespOrig {
+    let my_offset = server.replication.lock().unwrap().offset;
+    let describe = |node: &ClusterNode| {
+        let offset = if node.id == state.myself { my_offset } else { 0 };
+        RespOrig::Array(vec![
+            bulk("id"),
+            bulk(node.id.clone()),
+            bulk("port"),
+            RespOrig::Int(node.port as i64),
+            bulk("ip"),
+            bulk(node.ip.clone()),
+            bulk("endpoint"),
+            bulk(node.ip.clone()),
+            bulk("role"),
+            bulk(if node.is_master() { "master" } else { "replica" }),
+            bulk("replication-offset"),
+            RespOrig::Int(offset as i64),
+            bulk("health"),
+            bulk("online"),
+        ])
+    };
+
+    let shards = state
+        .nodes
+        .values()
+        .filter(|n| n.is_master())
+        .map(|master| {
+            let slots = state
+                .slot_ranges(&master.id)
+                .into_iter()
+                .flat_map(|(start, end)| [RespOrig::Int(start as i64), RespOrig::Int(end as i64)])
+                .collect();
+            let mut nodes = vec![describe(master)];
+            nodes.extend(state.replicas_of(&master.id).map(describe));
+            RespOrig::Array(vec![
+                bulk("slots"),
+                RespOrig::Array(slots),
+                bulk("nodes"),
+                RespOrig::Array(nodes),
+            ])
+        })
+        .collect();
+    RespOrig::Array(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_matches_the_xmodem_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn key_slot_hashes_the_tag() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"{user1000}.following"));
+        // only the first tag counts, and an empty one is no tag at all
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS as u16);
+        assert_eq!(key_slot(b"foo{bar"), crc16(b"foo{bar") % SLOTS as u16);
+    }
+}
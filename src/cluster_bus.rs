@@ -0,0 +1,1063 @@
+use crate::cluster::{ClusterNode, ClusterState, Election, Handshake, SLOTS};
+use crate::db::now_ms;
+use crate::pubsub;
+use crate::replication;
+use crate::server::{random_u64, Server};
+use crate::tls::{self, Stream};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, error, info, span, trace, warn, Instrument, Level};
+
+/// node-to-node messages. every message carries the sender's view of itself in the header:
+/// epochs, the slots it serves and who its master is. ping/pong/meet also gossip about
+/// other nodes so failures are noticed by everyone
+const SIGNATURE: &[u8; 4] = b"RCmb";
+const VERSION: u16 = 1;
+const ID_LEN: usize = 40;
+const IP_LEN: usize = 46;
+const SLOTS_BYTES: usize = SLOTS / 8;
+const HEADER_LEN: usize = 4 + 4 + 2 + 2 + 2 + 2 + 8 + 8 + 8 + ID_LEN + SLOTS_BYTES + ID_LEN + IP_LEN + 2 + 2;
+const GOSSIP_LEN: usize = ID_LEN + IP_LEN + 2 + 2 + 2;
+/// largest message we buffer, room for a published message as big as a bulk string can be
+const MAX_MESSAGE_LEN: usize = 512 * 1024 * 1024 + HEADER_LEN;
+
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(1000);
+/// failure reports older than node timeout times this are ignored
+const FAIL_REPORT_VALIDITY_MULT: u64 = 2;
+/// a master flagged FAIL that still serves slots is cleared after node timeout times this
+const FAIL_UNDO_TIME_MULT: u64 = 2;
+
+const FLAG_MASTER: u16 = 1;
+const FLAG_SLAVE: u16 = 1 << 1;
+const FLAG_PFAIL: u16 = 1 << 2;
+const FLAG_FAIL: u16 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u16)]
+enum MessageType {
+    Ping = 0,
+    Pong = 1,
+    Meet = 2,
+    Fail = 3,
+    Publish = 4,
+    AuthRequest = 5,
+    AuthAck = 6,
+    PublishShard = 7,
+}
+
+impl MessageType {
+    fn from_u16(value: u16) -> Option<MessageType> {
+        Some(match value {
+            0 => MessageType::Ping,
+            1 => MessageType::Pong,
+            2 => MessageType::Meet,
+            3 => MessageType::Fail,
+            4 => MessageType::Publish,
+            5 => MessageType::AuthRequest,
+            6 => MessageType::AuthAck,
+            7 => MessageType::PublishShard,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Gossip {
+    id: String,
+    ip: String,
+    port: u16,
+    cport: u16,
+    flags: u16,
+}
+
+#[derive(Debug)]
+struct Message {
+    kind: MessageType,
+    port: u16,
+    cport: u16,
+    flags: u16,
+    current_epoch: u64,
+    config_epoch: u64,
+    offset: u64,
+    sender: String,
+    slots: Vec<u8>,
+    master: Option<String>,
+    ip: String,
+    gossip: Vec<Gossip>,
+    /// node declared failed, FAIL messages only
+    failed: Option<String>,
+    /// channel and message, PUBLISH messages only
+    publish: Option<(Bytes, Bytes)>,
+}
+
+impl Message {
+    fn claims_slot(&self, slot: usize) -> bool {
+        self.slots[slot / 8] & (1 << (slot & 7)) != 0
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + self.gossip.len() * GOSSIP_LEN);
+        buf.put_slice(SIGNATURE);
+        buf.put_u32(0); // total length, patched below
+        buf.put_u16(VERSION);
+        buf.put_u16(self.kind as u16);
+        buf.put_u16(self.gossip.len() as u16);
+        buf.put_u16(self.port);
+        buf.put_u64(self.current_epoch);
+        buf.put_u64(self.config_epoch);
+        buf.put_u64(self.offset);
+        put_fixed(&mut buf, self.sender.as_bytes(), ID_LEN);
+        buf.put_slice(&self.slots);
+        put_fixed(&mut buf, self.master.as_deref().unwrap_or("").as_bytes(), ID_LEN);
+        put_fixed(&mut buf, self.ip.as_bytes(), IP_LEN);
+        buf.put_u16(self.cport);
+        buf.put_u16(self.flags);
+        for g in &self.gossip {
+            put_fixed(&mut buf, g.id.as_bytes(), ID_LEN);
+            put_fixed(&mut buf, g.ip.as_bytes(), IP_LEN);
+            buf.put_u16(g.port);
+            buf.put_u16(g.cport);
+            buf.put_u16(g.flags);
+        }
+        if let Some(failed) = &self.failed {
+            put_fixed(&mut buf, failed.as_bytes(), ID_LEN);
+        }
+        if let Some((channel, message)) = &self.publish {
+            buf.put_u32(channel.len() as u32);
+            buf.put_u32(message.len() as u32);
+            buf.put_slice(channel);
+            buf.put_slice(message);
+        }
+        let len = buf.len() as u32;
+        buf[4..8].copy_from_slice(&len.to_be_bytes());
+        buf.freeze()
+    }
+
+    /// `data` is one complete message as delimited by its length field
+    fn decode(mut data: &[u8]) -> Result<Message, Error> {
+        let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("bad bus message: {what}"));
+        if data.len() < HEADER_LEN || &data[..4] != SIGNATURE {
+            return Err(invalid("header"));
+        }
+        data.advance(8);
+        if data.get_u16() != VERSION {
+            return Err(invalid("version"));
+        }
+        let kind = MessageType::from_u16(data.get_u16()).ok_or_else(|| invalid("type"))?;
+        let count = data.get_u16() as usize;
+        let port = data.get_u16();
+        let current_epoch = data.get_u64();
+        let config_epoch = data.get_u64();
+        let offset = data.get_u64();
+        let sender = get_fixed(&mut data, ID_LEN);
+        let slots = data[..SLOTS_BYTES].to_vec();
+        data.advance(SLOTS_BYTES);
+        let master = Some(get_fixed(&mut data, ID_LEN)).filter(|m| !m.is_empty());
+        let ip = get_fixed(&mut data, IP_LEN);
+        let cport = data.get_u16();
+        let flags = data.get_u16();
+
+        if data.len() < count * GOSSIP_LEN {
+            return Err(invalid("gossip section"));
+        }
+        let mut gossip = Vec::with_capacity(count);
+        for _ in 0..count {
+            gossip.push(Gossip {
+                id: get_fixed(&mut data, ID_LEN),
+                ip: get_fixed(&mut data, IP_LEN),
+                port: data.get_u16(),
+                cport: data.get_u16(),
+                flags: data.get_u16(),
+            });
+        }
+        let failed = if kind == MessageType::Fail {
+            if data.len() < ID_LEN {
+                return Err(invalid("fail body"));
+            }
+            Some(get_fixed(&mut data, ID_LEN))
+        } else {
+            None
+        };
+        let publish = if matches!(kind, MessageType::Publish | MessageType::PublishShard) {
+            if data.len() < 8 {
+                return Err(invalid("publish body"));
+            }
+            let channel_len = data.get_u32() as usize;
+            let message_len = data.get_u32() as usize;
+            if data.len() < channel_len + message_len {
+                return Err(invalid("publish body"));
+            }
+            let channel = Bytes::copy_from_slice(&data[..channel_len]);
+            let message = Bytes::copy_from_slice(&data[channel_len..channel_len + message_len]);
+            Some((channel, message))
+        } else {
+            None
+        };
+
+        Ok(Message {
+            kind,
+            port,
+            cport,
+            flags,
+            current_epoch,
+            config_epoch,
+            offset,
+            sender,
+            slots,
+            master,
+            ip,
+            gossip,
+            failed,
+            publish,
+        })
+    }
+}
+
+fn put_fixed(buf: &mut BytesMut, value: &[u8], len: usize) {
+    let value = &value[..value.len().min(len)];
+    buf.put_slice(value);
+    buf.put_bytes(0, len - value.len());
+}
+
+fn get_fixed(data: &mut &[u8], len: usize) -> String {
+    let field = &data[..len];
+    let end = memchr::memchr(0, field).unwrap_or(len);
+    let value = String::from_utf8_lossy(&field[..end]).into_owned();
+    data.advance(len);
+    value
+}
+
+fn node_flags(node: &ClusterNode) -> u16 {
+    let mut flags = if node.is_master() { FLAG_MASTER } else { FLAG_SLAVE };
+    if node.pfail {
+        flags |= FLAG_PFAIL;
+    }
+    if node.fail {
+        flags |= FLAG_FAIL;
+    }
+    flags
+}
+
+/// a message describing ourselves. auth requests advertise the failed master's slots and
+/// config epoch instead, since that is what the replica asks to take over
+fn build(state: &ClusterState, server: &Server, kind: MessageType) -> Message {
+    let myself = state.myself();
+    let mut claimed = &myself.id;
+    let mut config_epoch = myself.config_epoch;
+    if let (MessageType::AuthRequest, Some(master)) = (kind, &myself.master) {
+        claimed = master;
+        config_epoch = state.nodes.get(master).map_or(0, |m| m.config_epoch);
+    }
+    let mut slots = vec![0; SLOTS_BYTES];
+    for (slot, owner) in state.slots.iter().enumerate() {
+        if owner.as_ref() == Some(claimed) {
+            slots[slot / 8] |= 1 << (slot & 7);
+        }
+    }
+    let gossip = match kind {
+        MessageType::Ping | MessageType::Pong | MessageType::Meet => state
+            .nodes
+            .values()
+            .filter(|n| n.id != state.myself)
+            .map(|n| Gossip {
+                id: n.id.clone(),
+                ip: n.ip.clone(),
+                port: n.port,
+                cport: n.cport,
+                flags: node_flags(n),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Message {
+        kind,
+        port: myself.port,
+        cport: myself.cport,
+        flags: node_flags(myself),
+        current_epoch: state.current_epoch,
+        config_epoch,
+        offset: server.replication.lock().unwrap().offset,
+        sender: myself.id.clone(),
+        slots,
+        master: myself.master.clone(),
+        ip: myself.ip.clone(),
+        gossip,
+        failed: None,
+        publish: None,
+    }
+}
+
+fn broadcast(state: &ClusterState, message: &Bytes) {
+    for node in state.nodes.values() {
+        if let Some(link) = &node.link {
+            let _ = link.send(message.clone());
+        }
+    }
+}
+
+/// work that must happen once the cluster lock is released
+#[derive(Debug)]
+enum Action {
+    ReplicateFrom(String, u16),
+    Promote,
+    Publish { channel: Bytes, message: Bytes, shard: bool },
+    /// slots that left our shard, their shard channels are dropped
+    SlotsLost(Vec<usize>),
+}
+
+fn perform(server: &Arc<Server>, actions: Vec<Action>) {
+    for action in actions {
+        match action {
+            Action::ReplicateFrom(host, port) => replication::replicaof(server, host, port),
+            Action::Promote => replication::promote(server),
+            Action::Publish { channel, message, shard: false } => {
+                pubsub::publish(server, &channel, &message);
+            }
+            Action::Publish { channel, message, shard: true } => {
+                pubsub::publish_shard(server, &channel, &message);
+            }
+            Action::SlotsLost(slots) => pubsub::slots_lost(server, &slots),
+        }
+    }
+}
+
+/// serves the bus on `listener` and starts the cron. resumes replication when the saved
+/// config says we are a replica
+pub fn start(server: Arc<Server>, listener: TcpListener) {
+    let Some(cluster) = server.cluster.as_ref() else {
+        return;
+    };
+    let master = {
+        let state = cluster.lock().unwrap();
+        let myself = state.myself();
+        myself
+            .master
+            .as_ref()
+            .and_then(|id| state.nodes.get(id))
+            .map(|m| (m.ip.clone(), m.port))
+    };
+    if let Some((host, port)) = master {
+        replication::replicaof(&server, host, port);
+    }
+
+    tokio::spawn(
+        accept_loop(server.clone(), listener).instrument(span!(Level::INFO, "cluster_bus")),
+    );
+    tokio::spawn(cron(server).instrument(span!(Level::INFO, "cluster_cron")));
+}
+
+async fn accept_loop(server: Arc<Server>, listener: TcpListener) {
+    let tls_cluster = server.config().tls_cluster;
+    let acceptor = server.tls.as_ref().filter(|_| tls_cluster).map(|tls| tls.acceptor().clone());
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!(%peer, "Inbound cluster bus connection");
+                let server = server.clone();
+                let acceptor = acceptor.clone();
+                tokio::spawn(
+                    async move {
+                        let result = match acceptor {
+                            Some(acceptor) => match acceptor.accept(stream).await {
+                                Ok(stream) => serve_inbound(server, stream, peer).await,
+                                Err(e) => Err(e),
+                            },
+                            None => serve_inbound(server, stream, peer).await,
+                        };
+                        if let Err(e) = result {
+                            debug!(error = ?e, "Inbound bus link closed");
+                        }
+                    }
+                    .instrument(span!(Level::DEBUG, "bus_inbound", %peer)),
+                );
+            }
+            Err(e) => error!(error = ?e, "Failed to accept cluster bus connection"),
+        }
+    }
+}
+
+/// reads one length-delimited message, `None` on a clean close
+async fn read_message<S: Stream>(stream: &mut S, buf: &mut BytesMut) -> Result<Option<Message>, Error> {
+    loop {
+        if buf.len() >= 8 {
+            let len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+            if len < HEADER_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "bus message too short"));
+            }
+            if len > MAX_MESSAGE_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "bus message too long"));
+            }
+            if buf.len() >= len {
+                let data = buf.split_to(len);
+                return Message::decode(&data).map(Some);
+            }
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// other nodes ping us here; answers go back on the same connection
+async fn serve_inbound<S: Stream>(server: Arc<Server>, mut stream: S, peer: SocketAddr) -> Result<(), Error> {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN * 2);
+    while let Some(message) = read_message(&mut stream, &mut buf).await? {
+        for reply in process(&server, message, peer.ip().to_string(), None) {
+            stream.write_all(&reply).await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum LinkTarget {
+    Node(String),
+    Handshake,
+}
+
+/// outbound link to one node: sends what the cron queues and processes the replies,
+/// reconnecting until the node is forgotten (the sender is dropped)
+async fn run_link(
+    server: Arc<Server>,
+    target: LinkTarget,
+    ip: String,
+    cport: u16,
+    mut rx: UnboundedReceiver<Bytes>,
+) {
+    loop {
+        let secure = server.config().tls_cluster;
+        let connect = tokio::time::timeout(CONNECT_TIMEOUT, tls::connect(&server, &ip, cport, secure));
+        let mut stream = match connect.await {
+            Ok(Ok(stream)) => stream,
+            _ => {
+                trace!(%ip, cport, "Cluster bus connect failed");
+                // drop what was queued for the dead link, the cron queues fresh pings
+                while rx.try_recv().is_ok() {}
+                if rx.is_closed() {
+                    return;
+                }
+                tokio::time::sleep(CRON_INTERVAL).await;
+                continue;
+            }
+        };
+        set_link_connected(&server, &target, true);
+        debug!(%ip, cport, "Cluster bus link connected");
+
+        let mut buf = BytesMut::with_capacity(HEADER_LEN * 2);
+        let result: Result<(), Error> = async {
+            loop {
+                tokio::select! {
+                    queued = rx.recv() => match queued {
+                        Some(data) => stream.write_all(&data).await?,
+                        None => return Ok(()),
+                    },
+                    message = read_message(&mut stream, &mut buf) => match message? {
+                        Some(message) => {
+                            for reply in process(&server, message, ip.clone(), Some(&target)) {
+                                stream.write_all(&reply).await?;
+                            }
+                        }
+                        None => return Err(Error::new(ErrorKind::UnexpectedEof, "peer closed")),
+                    },
+                }
+            }
+        }
+        .await;
+
+        set_link_connected(&server, &target, false);
+        match result {
+            Ok(()) => return,
+            Err(e) => debug!(error = ?e, %ip, cport, "Cluster bus link lost"),
+        }
+    }
+}
+
+fn set_link_connected(server: &Server, target: &LinkTarget, connected: bool) {
+    let Some(cluster) = server.cluster.as_ref() else {
+        return;
+    };
+    if let LinkTarget::Node(id) = target {
+        if let Some(node) = cluster.lock().unwrap().nodes.get_mut(id) {
+            node.link_connected = connected;
+        }
+    }
+}
+
+fn spawn_link(server: &Arc<Server>, target: LinkTarget, ip: String, cport: u16) -> UnboundedSender<Bytes> {
+    let (tx, rx) = unbounded_channel();
+    let span = span!(Level::DEBUG, "bus_link", %ip, cport);
+    tokio::spawn(run_link(server.clone(), target, ip, cport, rx).instrument(span));
+    tx
+}
+
+/// CLUSTER MEET: the id of the other node is learnt from its pong
+pub fn meet(server: &Arc<Server>, state: &mut ClusterState, ip: String, port: u16, cport: u16) {
+    let known = state.nodes.values().any(|n| n.ip == ip && n.port == port)
+        || state.handshakes.iter().any(|h| h.ip == ip && h.port == port);
+    if known {
+        return;
+    }
+    info!(%ip, port, cport, "Starting handshake with node");
+    let link = spawn_link(server, LinkTarget::Handshake, ip.clone(), cport);
+    let _ = link.send(build(state, server, MessageType::Meet).encode());
+    state.handshakes.push(Handshake {
+        ip,
+        port,
+        cport,
+        started: now_ms(),
+        link,
+    });
+}
+
+/// applies one message to our view of the cluster and returns the replies to send back
+fn process(
+    server: &Arc<Server>,
+    message: Message,
+    peer_ip: String,
+    link: Option<&LinkTarget>,
+) -> Vec<Bytes> {
+    let Some(cluster) = server.cluster.as_ref() else {
+        return Vec::new();
+    };
+    let mut actions = Vec::new();
+    let replies = {
+        let mut state = cluster.lock().unwrap();
+        let replies = apply(server, &mut state, message, peer_ip, link, &mut actions);
+        if state.dirty {
+            state.dirty = false;
+            if let Err(e) = state.save() {
+                warn!(error = ?e, "Failed to save cluster config");
+            }
+        }
+        replies
+    };
+    perform(server, actions);
+    replies
+}
+
+fn apply(
+    server: &Arc<Server>,
+    state: &mut ClusterState,
+    message: Message,
+    peer_ip: String,
+    link: Option<&LinkTarget>,
+    actions: &mut Vec<Action>,
+) -> Vec<Bytes> {
+    let now = now_ms();
+    trace!(kind = ?message.kind, sender = %message.sender, "Bus message");
+    if message.sender == state.myself {
+        return Vec::new();
+    }
+    if message.current_epoch > state.current_epoch {
+        state.current_epoch = message.current_epoch;
+        state.dirty = true;
+    }
+
+    let ip = if message.ip.is_empty() { peer_ip } else { message.ip.clone() };
+    if !state.nodes.contains_key(&message.sender) {
+        let handshake = matches!(link, Some(LinkTarget::Handshake))
+            && message.kind == MessageType::Pong;
+        if message.kind != MessageType::Meet && !handshake {
+            debug!(sender = %message.sender, "Ignoring message from unknown node");
+            return Vec::new();
+        }
+        info!(node = %message.sender, %ip, port = message.port, "Node joined the cluster");
+        state.handshakes.retain(|h| !(h.ip == ip && h.port == message.port));
+        state.nodes.insert(
+            message.sender.clone(),
+            ClusterNode::new(message.sender.clone(), ip.clone(), message.port, message.cport),
+        );
+        state.dirty = true;
+    }
+
+    let mut replies = Vec::new();
+    let is_heartbeat = matches!(message.kind, MessageType::Ping | MessageType::Pong | MessageType::Meet);
+    if is_heartbeat {
+        update_sender(state, &message, ip, now);
+        if message.flags & FLAG_MASTER != 0 {
+            update_slots(state, &message, actions);
+        }
+        process_gossip(server, state, &message, now);
+    }
+
+    match message.kind {
+        MessageType::Ping | MessageType::Meet => {
+            replies.push(build(state, server, MessageType::Pong).encode());
+        }
+        MessageType::Pong => {
+            if let Some(node) = state.nodes.get_mut(&message.sender) {
+                node.ping_sent = 0;
+                node.pong_received = now;
+                if node.pfail {
+                    debug!(node = %node.id, "Node reachable again, clearing PFAIL");
+                    node.pfail = false;
+                }
+            }
+            clear_fail_if_needed(state, &message.sender, now);
+        }
+        MessageType::Fail => {
+            if let Some(failed) = message.failed.as_ref().filter(|f| **f != state.myself) {
+                if let Some(node) = state.nodes.get_mut(failed) {
+                    if !node.fail {
+                        warn!(node = %failed, reporter = %message.sender, "Node marked FAIL by peer");
+                        node.fail = true;
+                        node.fail_time = now;
+                        state.dirty = true;
+                    }
+                }
+            }
+        }
+        MessageType::Publish | MessageType::PublishShard => {
+            let shard = message.kind == MessageType::PublishShard;
+            if let Some((channel, message)) = message.publish {
+                actions.push(Action::Publish { channel, message, shard });
+            }
+        }
+        MessageType::AuthRequest => {
+            if let Some(ack) = vote(server, state, &message, now) {
+                replies.push(ack);
+            }
+        }
+        MessageType::AuthAck => {
+            let is_voter = state
+                .nodes
+                .get(&message.sender)
+                .is_some_and(|n| n.is_master() && state.owns_slots(&n.id));
+            if let Some(election) = state.election.as_mut() {
+                if is_voter && election.sent && message.current_epoch >= election.epoch {
+                    debug!(voter = %message.sender, epoch = election.epoch, "Got failover vote");
+                    election.votes.insert(message.sender.clone());
+                }
+            }
+        }
+    }
+    replies
+}
+
+/// address, role and offset as the sender advertises them
+fn update_sender(state: &mut ClusterState, message: &Message, ip: String, now: u64) {
+    let myself = state.myself.clone();
+    let Some(node) = state.nodes.get_mut(&message.sender) else {
+        return;
+    };
+    if node.ip != ip || node.port != message.port || node.cport != message.cport {
+        info!(node = %node.id, %ip, port = message.port, "Node address updated");
+        node.ip = ip;
+        node.port = message.port;
+        node.cport = message.cport;
+        // the link reconnects to the new address
+        node.link = None;
+        state.dirty = true;
+    }
+    node.repl_offset = message.offset;
+    if node.pong_received == 0 {
+        node.pong_received = now;
+    }
+    if node.master != message.master {
+        info!(node = %node.id, master = ?message.master, "Node role changed");
+        node.master = message.master.clone();
+        state.dirty = true;
+    }
+    if message.flags & FLAG_MASTER != 0 && node.config_epoch != message.config_epoch {
+        node.config_epoch = message.config_epoch;
+        state.dirty = true;
+    }
+
+    // two masters with the same config epoch: the one with the smaller id moves on
+    let my_epoch = state.nodes[&myself].config_epoch;
+    let i_am_master = state.nodes[&myself].is_master();
+    if i_am_master
+        && message.flags & FLAG_MASTER != 0
+        && message.config_epoch == my_epoch
+        && my_epoch > 0
+        && myself < message.sender
+    {
+        state.current_epoch += 1;
+        let epoch = state.current_epoch;
+        state.myself_mut().config_epoch = epoch;
+        state.dirty = true;
+        warn!(epoch, "Config epoch collision resolved");
+    }
+}
+
+/// a master with a newer config epoch wins every slot it claims. losing all our slots
+/// that way means we were failed over, so we follow the new owner as its replica
+fn update_slots(state: &mut ClusterState, message: &Message, actions: &mut Vec<Action>) {
+    let had_slots = state.owns_slots(&state.myself);
+    let shard_master = state.shard_master().to_string();
+    let mut changed = false;
+    let mut lost_to_sender = false;
+    let mut shard_lost = Vec::new();
+    for slot in 0..SLOTS {
+        if !message.claims_slot(slot)
+            || state.slots[slot].as_deref() == Some(message.sender.as_str())
+            || state.importing.contains_key(&slot)
+        {
+            continue;
+        }
+        let owner_epoch = state.slots[slot]
+            .as_ref()
+            .and_then(|owner| state.nodes.get(owner))
+            .map(|owner| owner.config_epoch);
+        if owner_epoch.is_none_or(|epoch| epoch < message.config_epoch) {
+            if state.slots[slot].as_deref() == Some(state.myself.as_str()) {
+                lost_to_sender = true;
+            }
+            if state.slots[slot].as_deref() == Some(shard_master.as_str()) {
+                shard_lost.push(slot);
+            }
+            state.slots[slot] = Some(message.sender.clone());
+            changed = true;
+        }
+    }
+    if !changed {
+        return;
+    }
+    info!(owner = %message.sender, epoch = message.config_epoch, "Slot ownership updated from bus");
+    state.dirty = true;
+    if had_slots && lost_to_sender && !state.owns_slots(&state.myself) {
+        let sender = &state.nodes[&message.sender];
+        warn!(new_master = %sender.id, "Lost all slots, turning into a replica");
+        let (ip, port) = (sender.ip.clone(), sender.port);
+        state.myself_mut().master = Some(message.sender.clone());
+        state.election = None;
+        actions.push(Action::ReplicateFrom(ip, port));
+        // following the new owner keeps the slots in our shard
+        return;
+    }
+    if !shard_lost.is_empty() {
+        actions.push(Action::SlotsLost(shard_lost));
+    }
+}
+
+/// masters tell us which nodes they see failing; that is what promotes PFAIL to FAIL
+fn process_gossip(server: &Arc<Server>, state: &mut ClusterState, message: &Message, now: u64) {
+    let sender_is_master = state
+        .nodes
+        .get(&message.sender)
+        .is_some_and(|n| n.is_master());
+    for gossip in &message.gossip {
+        if gossip.id == state.myself {
+            continue;
+        }
+        match state.nodes.get_mut(&gossip.id) {
+            Some(node) => {
+                if sender_is_master {
+                    if gossip.flags & (FLAG_PFAIL | FLAG_FAIL) != 0 {
+                        node.fail_reports.insert(message.sender.clone(), now);
+                    } else {
+                        node.fail_reports.remove(&message.sender);
+                    }
+                }
+                mark_failing_if_needed(server, state, &gossip.id, now);
+            }
+            None => {
+                let blacklisted = state.blacklist.get(&gossip.id).is_some_and(|until| *until > now);
+                if !blacklisted && gossip.flags & FLAG_FAIL == 0 && !gossip.ip.is_empty() {
+                    info!(node = %gossip.id, ip = %gossip.ip, port = gossip.port, "Learnt about node from gossip");
+                    state.nodes.insert(
+                        gossip.id.clone(),
+                        ClusterNode::new(gossip.id.clone(), gossip.ip.clone(), gossip.port, gossip.cport),
+                    );
+                    state.dirty = true;
+                }
+            }
+        }
+    }
+}
+
+fn mark_failing_if_needed(server: &Arc<Server>, state: &mut ClusterState, id: &str, now: u64) {
+    let validity = state.node_timeout * FAIL_REPORT_VALIDITY_MULT;
+    let quorum = state.quorum();
+    let i_am_master = state.myself().is_master();
+    let Some(node) = state.nodes.get_mut(id) else {
+        return;
+    };
+    node.fail_reports.retain(|_, at| now.saturating_sub(*at) <= validity);
+    if !node.pfail || node.fail {
+        return;
+    }
+    let reports = node.fail_reports.len() + i_am_master as usize;
+    if reports < quorum {
+        return;
+    }
+    warn!(node = %id, reports, quorum, "Marking node as FAIL");
+    node.fail = true;
+    node.fail_time = now;
+    state.dirty = true;
+
+    let mut fail = build(state, server, MessageType::Fail);
+    fail.failed = Some(id.to_string());
+    broadcast(state, &fail.encode());
+}
+
+/// replicas and masters without slots come back right away; a master that still serves
+/// slots only after nobody took them over for a while
+fn clear_fail_if_needed(state: &mut ClusterState, id: &str, now: u64) {
+    let undo_after = state.node_timeout * FAIL_UNDO_TIME_MULT;
+    let serves_slots = state.owns_slots(id);
+    let Some(node) = state.nodes.get_mut(id) else {
+        return;
+    };
+    if !node.fail {
+        return;
+    }
+    if !node.is_master() || !serves_slots || now.saturating_sub(node.fail_time) > undo_after {
+        info!(node = %id, "Clearing FAIL state");
+        node.fail = false;
+        node.fail_reports.clear();
+        state.dirty = true;
+    }
+}
+
+/// a master grants at most one vote per epoch, and only to a replica of a failed master
+/// whose claimed slots are not owned by anyone with a newer config
+fn vote(server: &Arc<Server>, state: &mut ClusterState, request: &Message, now: u64) -> Option<Bytes> {
+    let myself = state.myself();
+    if !myself.is_master() || !state.owns_slots(&state.myself) {
+        return None;
+    }
+    if request.current_epoch < state.current_epoch || state.last_vote_epoch == state.current_epoch {
+        debug!(epoch = request.current_epoch, "Refusing vote: stale epoch or already voted");
+        return None;
+    }
+    let master_id = state.nodes.get(&request.sender)?.master.clone()?;
+    let master = state.nodes.get(&master_id)?;
+    if !master.fail {
+        debug!(candidate = %request.sender, "Refusing vote: master is not failing");
+        return None;
+    }
+    if now.saturating_sub(master.voted_time) < state.node_timeout * 2 {
+        debug!(candidate = %request.sender, "Refusing vote: voted for this master recently");
+        return None;
+    }
+    for slot in (0..SLOTS).filter(|s| request.claims_slot(*s)) {
+        let owner_epoch = state.slots[slot]
+            .as_ref()
+            .and_then(|o| state.nodes.get(o))
+            .map_or(0, |o| o.config_epoch);
+        if owner_epoch > request.config_epoch {
+            debug!(slot, "Refusing vote: slot has a newer config");
+            return None;
+        }
+    }
+
+    state.last_vote_epoch = state.current_epoch;
+    if let Some(master) = state.nodes.get_mut(&master_id) {
+        master.voted_time = now;
+    }
+    state.dirty = true;
+    info!(candidate = %request.sender, epoch = state.current_epoch, "Voting for failover");
+    Some(build(state, server, MessageType::AuthAck).encode())
+}
+
+/// runs every 100ms: keeps links up, pings, detects failures and drives elections
+async fn cron(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(CRON_INTERVAL);
+    let mut iteration: u64 = 0;
+    loop {
+        interval.tick().await;
+        iteration += 1;
+        let Some(cluster) = server.cluster.as_ref() else {
+            return;
+        };
+        let mut actions = Vec::new();
+        {
+            let mut state = cluster.lock().unwrap();
+            tick(&server, &mut state, iteration, &mut actions);
+            if state.dirty {
+                state.dirty = false;
+                if let Err(e) = state.save() {
+                    warn!(error = ?e, "Failed to save cluster config");
+                }
+            }
+        }
+        perform(&server, actions);
+    }
+}
+
+fn tick(server: &Arc<Server>, state: &mut ClusterState, iteration: u64, actions: &mut Vec<Action>) {
+    let now = now_ms();
+    let timeout = state.node_timeout;
+    state.handshakes.retain(|h| now.saturating_sub(h.started) <= timeout);
+    state.blacklist.retain(|_, until| *until > now);
+    if iteration.is_multiple_of(10) && !state.handshakes.is_empty() {
+        // the first meet may have been dropped while the link was still connecting
+        let meet = build(state, server, MessageType::Meet).encode();
+        for handshake in &state.handshakes {
+            let _ = handshake.link.send(meet.clone());
+        }
+    }
+
+    let myself = state.myself.clone();
+    let ids: Vec<String> = state.nodes.keys().filter(|id| **id != myself).cloned().collect();
+    for id in &ids {
+        let node = &state.nodes[id];
+        if node.link.is_none() {
+            let link = spawn_link(server, LinkTarget::Node(id.clone()), node.ip.clone(), node.cport);
+            state.nodes.get_mut(id).unwrap().link = Some(link);
+        }
+    }
+
+    // every node gets pinged at least every half timeout, plus one random node per second
+    let random = (iteration.is_multiple_of(10) && !ids.is_empty())
+        .then(|| ids[(random_u64() % ids.len() as u64) as usize].clone());
+    let ping = build(state, server, MessageType::Ping).encode();
+    for id in &ids {
+        let node = state.nodes.get_mut(id).unwrap();
+        let overdue = now.saturating_sub(node.pong_received) > timeout / 2;
+        if node.ping_sent == 0 && (overdue || random.as_ref() == Some(id)) {
+            node.ping_sent = now;
+            if let Some(link) = &node.link {
+                let _ = link.send(ping.clone());
+            }
+        }
+        if node.ping_sent != 0 && now.saturating_sub(node.ping_sent) > timeout && !node.pfail {
+            warn!(node = %id, "No pong within node timeout, marking PFAIL");
+            node.pfail = true;
+        }
+    }
+    for id in &ids {
+        mark_failing_if_needed(server, state, id, now);
+    }
+
+    failover_if_needed(server, state, now, actions);
+}
+
+fn failover_if_needed(server: &Arc<Server>, state: &mut ClusterState, now: u64, actions: &mut Vec<Action>) {
+    let myself = state.myself();
+    let Some(master_id) = myself.master.clone() else {
+        state.election = None;
+        return;
+    };
+    let master_failed = state.nodes.get(&master_id).is_some_and(|m| m.fail);
+    if !master_failed || !state.owns_slots(&master_id) {
+        state.election = None;
+        return;
+    }
+
+    let retry_after = (state.node_timeout * 2).max(2000);
+    let restart = match &state.election {
+        None => true,
+        Some(e) => now > e.start_at + retry_after,
+    };
+    if restart {
+        // replicas with more data go first
+        let my_offset = server.replication.lock().unwrap().offset;
+        let rank = state
+            .replicas_of(&master_id)
+            .filter(|r| r.id != state.myself && r.repl_offset > my_offset)
+            .count() as u64;
+        let delay = 500 + random_u64() % 500 + rank * 1000;
+        info!(rank, delay, "Master failed, scheduling failover election");
+        state.election = Some(Election {
+            start_at: now + delay,
+            epoch: 0,
+            sent: false,
+            votes: Default::default(),
+        });
+    }
+
+    let quorum = state.quorum();
+    let election = state.election.as_ref().unwrap();
+    if !election.sent && now >= election.start_at {
+        state.current_epoch += 1;
+        let epoch = state.current_epoch;
+        let request = build(state, server, MessageType::AuthRequest).encode();
+        let election = state.election.as_mut().unwrap();
+        election.epoch = epoch;
+        election.sent = true;
+        state.dirty = true;
+        info!(epoch, "Requesting failover votes");
+        broadcast(state, &request);
+        return;
+    }
+
+    if election.sent && election.votes.len() >= quorum {
+        let epoch = election.epoch;
+        warn!(epoch, votes = election.votes.len(), "Failover won, taking over master slots");
+        for owner in state.slots.iter_mut() {
+            if owner.as_deref() == Some(master_id.as_str()) {
+                *owner = Some(state.myself.clone());
+            }
+        }
+        let myself = state.myself_mut();
+        myself.master = None;
+        myself.config_epoch = myself.config_epoch.max(epoch);
+        state.election = None;
+        state.dirty = true;
+        actions.push(Action::Promote);
+        broadcast(state, &build(state, server, MessageType::Pong).encode());
+    }
+}
+
+/// PUBLISH goes to every node, SPUBLISH only to the master and replicas of our shard
+pub fn publish(server: &Server, channel: &Bytes, message: &Bytes, shard: bool) {
+    let Some(cluster) = server.cluster.as_ref() else {
+        return;
+    };
+    let state = cluster.lock().unwrap();
+    let kind = if shard { MessageType::PublishShard } else { MessageType::Publish };
+    let mut publish = build(&state, server, kind);
+    publish.publish = Some((channel.clone(), message.clone()));
+    let encoded = publish.encode();
+    let shard_master = state.shard_master();
+    for node in state.nodes.values().filter(|n| n.id != state.myself) {
+        if shard && node.id != shard_master && node.master.as_deref() != Some(shard_master) {
+            continue;
+        }
+        if let Some(link) = &node.link {
+            let _ = link.send(encoded.clone());
+        }
+    }
+}
+
+/// CLUSTER REPLICATE <master-id>
+pub fn replicate(state: &mut ClusterState, master_id: &str) -> Result<(String, u16), &'static str> {
+    if master_id == state.myself {
+        return Err("ERR Can't replicate myself");
+    }
+    let Some(master) = state.nodes.get(master_id) else {
+        return Err("ERR Unknown node");
+    };
+    if !master.is_master() {
+        return Err("ERR I can only replicate a master, not a replica.");
+    }
+    if state.myself().is_master() && state.owns_slots(&state.myself) {
+        return Err("ERR To set a master the node must be empty and without assigned slots.");
+    }
+    let target = (master.ip.clone(), master.port);
+    state.myself_mut().master = Some(master_id.to_string());
+    state.dirty = true;
+    Ok(target)
+}
+
+/// CLUSTER FORGET <node-id>: also keeps gossip from re-adding it for a minute
+pub fn forget(state: &mut ClusterState, id: &str) -> Result<(), &'static str> {
+    if id == state.myself {
+        return Err("ERR I tried hard but I can't forget myself...");
+    }
+    if state.myself().master.as_deref() == Some(id) {
+        return Err("ERR Can't forget my master!");
+    }
+    if state.nodes.remove(id).is_none() {
+        return Err("ERR Unknown node");
+    }
+    for owner in state.slots.iter_mut() {
+        if owner.as_deref() == Some(id) {
+            *owner = None;
+        }
+    }
+    state.migrating.retain(|_, target| target != id);
+    state.importing.retain(|_, source| source != id);
+    state.blacklist.insert(id.to_string(), now_ms() + 60_000);
+    state.dirty = true;
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use crate::parser::RespOrig;
+use std::collections::HashMap;
+
+/// static command metadata, looked up by upper-cased name.
+/// arity follows redis: positive means exact argc, negative means at least that many
+#[derive(Debug)]
+pub struct Command {
+    pub name: &'static str,
+    pub arity: i32,
+    pub flags: u32,
+    /// ACL categories besides the ones the flags imply, see `categories`
+    pub acl: u32,
+    pub keys: KeySpec,
+}
+
+/// positions of key arguments: first, last (negative counts from the end) and step.
+/// with `numkeys` set, that argument holds how many keys follow it instead. with
+/// `keyword` present in the call after `first`, the keys are every argument after it
+#[derive(Debug, Clone, Copy)]
+pub struct KeySpec {
+    pub first: i32,
+    pub last: i32,
+    pub step: i32,
+    pub numkeys: i32,
+    pub keyword: Option<&'static str>,
+}
+
+pub const NO_KEYS: KeySpec = KeySpec { first: 0, last: 0, step: 0, numkeys: 0, keyword: None };
+const ONE_KEY: KeySpec = KeySpec { first: 1, last: 1, step: 1, numkeys: 0, keyword: None };
+const ALL_KEYS: KeySpec = KeySpec { first: 1, last: -1, step: 1, numkeys: 0, keyword: None };
+/// `EVAL script numkeys key [key ...] arg [arg ...]`
+const SCRIPT_KEYS: KeySpec = KeySpec { first: 0, last: 0, step: 0, numkeys: 2, keyword: None };
+/// `MIGRATE host port key|"" db timeout ... [KEYS key [key ...]]`
+const MIGRATE_KEYS: KeySpec = KeySpec { first: 3, last: 3, step: 1, numkeys: 0, keyword: Some("KEYS") };
+
+pub const WRITE: u32 = 1;
+pub const READONLY: u32 = 1 << 1;
+pub const ADMIN: u32 = 1 << 2;
+/// allowed on a replica whose master link is down and `replica-serve-stale-data` is off
+pub const STALE: u32 = 1 << 3;
+/// served on an importing slot as if the client had sent ASKING
+pub const ASKING: u32 = 1 << 4;
+/// the key arguments are shard channels: routed by slot, never looked up in the keyspace
+pub const SHARD_CHANNELS: u32 = 1 << 5;
+/// not a write itself, but may run writes (scripts)
+pub const MAY_REPLICATE: u32 = 1 << 6;
+/// refused by `redis.call`
+pub const NO_SCRIPT: u32 = 1 << 7;
+/// runs before the client authenticated, and without any ACL check
+pub const NO_AUTH: u32 = 1 << 8;
+/// the first argument names a subcommand, which ACL rules such as `+config|get` refer to
+pub const SUBCOMMANDS: u32 = 1 << 9;
+/// the keys are read and written, so ACL wants a pattern granting both, not just the
+/// write access other writes need
+pub const RW_KEYS: u32 = 1 << 10;
+/// refused while MULTI is queueing, such a command takes over the connection
+pub const NO_MULTI: u32 = 1 << 11;
+
+/// ACL category names, as in `+@read`. a category's bit is `1 << ` its index
+pub const CATEGORIES: &[&str] = &[
+    "keyspace", "read", "write", "set", "sortedset", "list", "hash", "string", "bitmap", "hyperloglog",
+    "geo", "stream", "pubsub", "admin", "fast", "slow", "blocking", "dangerous", "connection",
+    "transaction", "scripting",
+];
+const CAT_KEYSPACE: u32 = 1;
+const CAT_READ: u32 = 1 << 1;
+const CAT_WRITE: u32 = 1 << 2;
+const CAT_STRING: u32 = 1 << 7;
+const CAT_PUBSUB: u32 = 1 << 12;
+const CAT_ADMIN: u32 = 1 << 13;
+const CAT_FAST: u32 = 1 << 14;
+const CAT_SLOW: u32 = 1 << 15;
+const CAT_DANGEROUS: u32 = 1 << 17;
+const CAT_CONNECTION: u32 = 1 << 18;
+const CAT_TRANSACTION: u32 = 1 << 19;
+const CAT_SCRIPTING: u32 = 1 << 20;
+
+const COMMANDS: &[Command] = &[
+    Command { name: "PING", arity: -1, flags: STALE, acl: CAT_FAST | CAT_CONNECTION, keys: NO_KEYS },
+    Command { name: "ECHO", arity: 2, flags: 0, acl: CAT_FAST | CAT_CONNECTION, keys: NO_KEYS },
+    Command { name: "GET", arity: 2, flags: READONLY, acl: CAT_STRING | CAT_FAST, keys: ONE_KEY },
+    Command { name: "SET", arity: -3, flags: WRITE, acl: CAT_STRING, keys: ONE_KEY },
+    Command { name: "DEL", arity: -2, flags: WRITE, acl: CAT_KEYSPACE, keys: ALL_KEYS },
+    Command { name: "INFO", arity: -1, flags: STALE, acl: CAT_DANGEROUS, keys: NO_KEYS },
+    Command { name: "REPLCONF", arity: -1, flags: ADMIN | STALE, acl: 0, keys: NO_KEYS },
+    Command { name: "PSYNC", arity: -3, flags: ADMIN | NO_MULTI, acl: 0, keys: NO_KEYS },
+    Command { name: "REPLICAOF", arity: 3, flags: ADMIN | STALE, acl: 0, keys: NO_KEYS },
+    Command { name: "SLAVEOF", arity: 3, flags: ADMIN | STALE, acl: 0, keys: NO_KEYS },
+    Command { name: "WAIT", arity: 3, flags: NO_SCRIPT, acl: CAT_CONNECTION, keys: NO_KEYS },
+    Command { name: "WAITAOF", arity: 4, flags: NO_SCRIPT, acl: CAT_CONNECTION, keys: NO_KEYS },
+    Command { name: "CLUSTER", arity: -2, flags: STALE | SUBCOMMANDS, acl: 0, keys: NO_KEYS },
+    Command { name: "SUBSCRIBE", arity: -2, flags: STALE | NO_SCRIPT, acl: CAT_PUBSUB, keys: NO_KEYS },
+    Command { name: "UNSUBSCRIBE", arity: -1, flags: STALE | NO_SCRIPT, acl: CAT_PUBSUB, keys: NO_KEYS },
+    Command { name: "PSUBSCRIBE", arity: -2, flags: STALE | NO_SCRIPT, acl: CAT_PUBSUB, keys: NO_KEYS },
+    Command { name: "PUNSUBSCRIBE", arity: -1, flags: STALE | NO_SCRIPT, acl: CAT_PUBSUB, keys: NO_KEYS },
+    Command { name: "PUBLISH", arity: 3, flags: STALE, acl: CAT_PUBSUB | CAT_FAST, keys: NO_KEYS },
+    Command { name: "PUBSUB", arity: -2, flags: STALE | SUBCOMMANDS, acl: CAT_PUBSUB, keys: NO_KEYS },
+    Command { name: "SSUBSCRIBE", arity: -2, flags: STALE | SHARD_CHANNELS | NO_SCRIPT, acl: CAT_PUBSUB, keys: ALL_KEYS },
+    Command { name: "SUNSUBSCRIBE", arity: -1, flags: STALE | SHARD_CHANNELS | NO_SCRIPT, acl: CAT_PUBSUB, keys: ALL_KEYS },
+    Command { name: "SPUBLISH", arity: 3, flags: STALE | SHARD_CHANNELS, acl: CAT_PUBSUB | CAT_FAST, keys: ONE_KEY },
+    Command { name: "ASKING", arity: 1, flags: 0, acl: CAT_FAST | CAT_CONNECTION, keys: NO_KEYS },
+    Command { name: "DUMP", arity: 2, flags: READONLY, acl: CAT_KEYSPACE, keys: ONE_KEY },
+    Command { name: "RESTORE", arity: -4, flags: WRITE, acl: CAT_KEYSPACE | CAT_DANGEROUS, keys: ONE_KEY },
+    Command { name: "RESTORE-ASKING", arity: -4, flags: WRITE | ASKING, acl: CAT_KEYSPACE | CAT_DANGEROUS, keys: ONE_KEY },
+    Command { name: "MIGRATE", arity: -6, flags: WRITE | RW_KEYS | NO_SCRIPT, acl: CAT_KEYSPACE | CAT_DANGEROUS, keys: MIGRATE_KEYS },
+    Command { name: "MULTI", arity: 1, flags: STALE | NO_SCRIPT, acl: CAT_TRANSACTION | CAT_FAST, keys: NO_KEYS },
+    Command { name: "EXEC", arity: 1, flags: STALE | NO_SCRIPT, acl: CAT_TRANSACTION, keys: NO_KEYS },
+    Command { name: "DISCARD", arity: 1, flags: STALE | NO_SCRIPT, acl: CAT_TRANSACTION | CAT_FAST, keys: NO_KEYS },
+    Command { name: "WATCH", arity: -2, flags: STALE | NO_SCRIPT, acl: CAT_TRANSACTION | CAT_FAST, keys: ALL_KEYS },
+    Command { name: "UNWATCH", arity: 1, flags: STALE | NO_SCRIPT, acl: CAT_TRANSACTION | CAT_FAST, keys: NO_KEYS },
+    Command { name: "FLUSHALL", arity: -1, flags: WRITE, acl: CAT_KEYSPACE | CAT_DANGEROUS, keys: NO_KEYS },
+    Command { name: "FLUSHDB", arity: -1, flags: WRITE, acl: CAT_KEYSPACE | CAT_DANGEROUS, keys: NO_KEYS },
+    Command { name: "SELECT", arity: 2, flags: STALE, acl: CAT_CONNECTION | CAT_FAST, keys: NO_KEYS },
+    Command { name: "SWAPDB", arity: 3, flags: WRITE, acl: CAT_KEYSPACE | CAT_DANGEROUS | CAT_FAST, keys: NO_KEYS },
+    Command { name: "MOVE", arity: 3, flags: WRITE, acl: CAT_KEYSPACE | CAT_FAST, keys: ONE_KEY },
+    Command { name: "DBSIZE", arity: 1, flags: READONLY, acl: CAT_KEYSPACE | CAT_FAST, keys: NO_KEYS },
+    Command { name: "SAVE", arity: 1, flags: ADMIN | NO_SCRIPT, acl: 0, keys: NO_KEYS },
+    Command { name: "BGSAVE", arity: -1, flags: ADMIN | NO_SCRIPT, acl: 0, keys: NO_KEYS },
+    Command { name: "LASTSAVE", arity: 1, flags: STALE, acl: CAT_ADMIN | CAT_DANGEROUS | CAT_FAST, keys: NO_KEYS },
+    Command { name: "SHUTDOWN", arity: -1, flags: ADMIN | STALE | NO_SCRIPT, acl: 0, keys: NO_KEYS },
+    Command { name: "CONFIG", arity: -2, flags: ADMIN | STALE | NO_SCRIPT | SUBCOMMANDS, acl: 0, keys: NO_KEYS },
+    Command { name: "ACL", arity: -2, flags: ADMIN | STALE | NO_SCRIPT | SUBCOMMANDS, acl: 0, keys: NO_KEYS },
+    Command { name: "AUTH", arity: -2, flags: STALE | NO_SCRIPT | NO_AUTH, acl: CAT_CONNECTION | CAT_FAST, keys: NO_KEYS },
+    Command { name: "HELLO", arity: -1, flags: STALE | NO_SCRIPT | NO_AUTH, acl: CAT_CONNECTION | CAT_FAST, keys: NO_KEYS },
+    Command { name: "QUIT", arity: -1, flags: STALE | NO_SCRIPT | NO_AUTH, acl: CAT_CONNECTION | CAT_FAST, keys: NO_KEYS },
+    Command { name: "RESET", arity: 1, flags: STALE | NO_SCRIPT | NO_AUTH, acl: CAT_CONNECTION | CAT_FAST, keys: NO_KEYS },
+    // KILL and PAUSE are as @connection as ID and SETNAME here, restrict them with rules
+    // like `-client|kill`
+    Command { name: "CLIENT", arity: -2, flags: STALE | NO_SCRIPT | SUBCOMMANDS, acl: CAT_CONNECTION, keys: NO_KEYS },
+    Command { name: "EVAL", arity: -3, flags: STALE | MAY_REPLICATE | RW_KEYS | NO_SCRIPT, acl: CAT_SCRIPTING, keys: SCRIPT_KEYS },
+    Command { name: "EVALSHA", arity: -3, flags: STALE | MAY_REPLICATE | RW_KEYS | NO_SCRIPT, acl: CAT_SCRIPTING, keys: SCRIPT_KEYS },
+    Command { name: "SCRIPT", arity: -2, flags: NO_SCRIPT | SUBCOMMANDS, acl: CAT_SCRIPTING, keys: NO_KEYS },
+    // LOAD, DELETE, FLUSH and RESTORE are guarded and propagated by `functions::command`
+    Command { name: "FUNCTION", arity: -2, flags: MAY_REPLICATE | NO_SCRIPT | SUBCOMMANDS, acl: CAT_SCRIPTING, keys: NO_KEYS },
+    Command { name: "FCALL", arity: -3, flags: STALE | MAY_REPLICATE | RW_KEYS | NO_SCRIPT, acl: CAT_SCRIPTING, keys: SCRIPT_KEYS },
+    Command { name: "FCALL_RO", arity: -3, flags: STALE | READONLY | NO_SCRIPT, acl: CAT_SCRIPTING, keys: SCRIPT_KEYS },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// every command, in table order
+pub fn all() -> &'static [Command] {
+    COMMANDS
+}
+
+/// the names clients use after `rename-command`, upper-cased. a new name maps to the command
+/// it runs, an original name that was renamed or disabled maps to `None`
+pub fn renames(pairs: &[(String, String)]) -> HashMap<String, Option<&'static str>> {
+    let mut names = HashMap::new();
+    for (from, to) in pairs {
+        let Some(command) = lookup(&from.to_uppercase()) else {
+            continue;
+        };
+        names.entry(command.name.to_string()).or_insert(None);
+        if !to.is_empty() {
+            names.insert(to.to_uppercase(), Some(command.name));
+        }
+    }
+    names
+}
+
+impl Command {
+    pub fn is_write(&self) -> bool {
+        self.flags & WRITE != 0
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.flags & STALE != 0
+    }
+
+    pub fn is_asking(&self) -> bool {
+        self.flags & ASKING != 0
+    }
+
+    pub fn has_shard_channels(&self) -> bool {
+        self.flags & SHARD_CHANNELS != 0
+    }
+
+    pub fn may_replicate(&self) -> bool {
+        self.flags & (WRITE | MAY_REPLICATE) != 0
+    }
+
+    pub fn is_no_script(&self) -> bool {
+        self.flags & NO_SCRIPT != 0
+    }
+
+    pub fn is_no_auth(&self) -> bool {
+        self.flags & NO_AUTH != 0
+    }
+
+    pub fn is_no_multi(&self) -> bool {
+        self.flags & NO_MULTI != 0
+    }
+
+    pub fn has_subcommands(&self) -> bool {
+        self.flags & SUBCOMMANDS != 0
+    }
+
+    /// the key access ACL checks, as (read, write)
+    pub fn key_access(&self) -> (bool, bool) {
+        if self.flags & RW_KEYS != 0 {
+            (true, true)
+        } else {
+            (!self.is_write(), self.is_write())
+        }
+    }
+
+    /// ACL category bits: the listed ones, `@write`, `@read` and `@admin` from the flags,
+    /// admin commands are `@dangerous` as well, and everything not `@fast` is `@slow`
+    pub fn categories(&self) -> u32 {
+        let mut categories = self.acl;
+        if self.flags & WRITE != 0 {
+            categories |= CAT_WRITE;
+        }
+        if self.flags & READONLY != 0 {
+            categories |= CAT_READ;
+        }
+        if self.flags & ADMIN != 0 {
+            categories |= CAT_ADMIN | CAT_DANGEROUS;
+        }
+        if categories & CAT_FAST == 0 {
+            categories |= CAT_SLOW;
+        }
+        categories
+    }
+
+    /// indexes of the key arguments in a call (command name included)
+    pub fn key_indexes(&self, items: &[RespOrig]) -> Vec<usize> {
+        let KeySpec { first, last, step, numkeys, keyword } = self.keys;
+        let argc = items.len();
+        if let Some(keyword) = keyword {
+            let position = items
+                .iter()
+                .skip(first as usize + 1)
+                .position(|arg| arg.as_bytes().is_some_and(|a| a.eq_ignore_ascii_case(keyword.as_bytes())));
+            if let Some(position) = position {
+                return (first as usize + 2 + position..argc).collect();
+            }
+        }
+        if numkeys > 0 {
+            let count = items
+                .get(numkeys as usize)
+                .and_then(RespOrig::as_bytes)
+                .and_then(|n| std::str::from_utf8(n).ok()?.parse::<usize>().ok())
+                .unwrap_or(0);
+            let start = numkeys as usize + 1;
+            return (start..(start + count).min(argc)).collect();
+        }
+        if first == 0 || argc as i32 <= first {
+            return Vec::new();
+        }
+        let last = if last < 0 { argc as i32 + last } else { last.min(argc as i32 - 1) };
+        (first..=last).step_by(step as usize).map(|i| i as usize).collect()
+    }
+}
@@ -0,0 +1,1069 @@
+use crate::cluster::BUS_PORT_OFFSET;
+use crate::commands;
+use crate::glob;
+use crate::handler::{arg_str, error, ok, wrong_arity};
+use crate::notify;
+use crate::parser::RespOrig;
+use crate::server::Server;
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use tracing_forest::ForestLayer;
+use tracing_subscriber::{prelude::*, reload, Registry};
+
+pub const DEFAULT_PORT: u16 = 6379;
+pub const DEFAULT_BIND: &str = "127.0.0.1";
+
+/// how deep `include` directives may nest
+const MAX_INCLUDE_DEPTH: usize = 16;
+const LOG_LEVELS: &[&str] = &["debug", "verbose", "notice", "warning", "nothing"];
+
+/// how much output may queue up for a client before it is disconnected: `hard` bytes at
+/// once, or `soft` bytes for `soft_seconds` on end. 0 disables either limit
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutputLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputLimits {
+    pub normal: OutputLimit,
+    pub replica: OutputLimit,
+    pub pubsub: OutputLimit,
+}
+
+/// server options. CONFIG SET changes the mutable ones while running, see `PARAMS`
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// the file the options were read from, absolute, for CONFIG REWRITE
+    pub config_file: Option<PathBuf>,
+    pub port: u16,
+    /// addresses to listen on. one starting with `-` is skipped when it cannot be bound
+    pub bind: Vec<String>,
+    /// path of a unix socket to listen on as well
+    pub unixsocket: Option<PathBuf>,
+    /// mode the unix socket file gets, 0 to leave it to the umask
+    pub unixsocketperm: u32,
+    /// port for TLS connections, 0 for none
+    pub tls_port: u16,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    /// verifies client certificates, and the servers we connect to over TLS
+    pub tls_ca_cert_file: Option<PathBuf>,
+    /// `yes`, `no` or `optional`: whether TLS clients must present a certificate
+    pub tls_auth_clients: String,
+    /// the link to our master uses TLS
+    pub tls_replication: bool,
+    /// cluster bus links use TLS
+    pub tls_cluster: bool,
+    /// working directory, entered before anything is read or written. absolute once entered
+    pub dir: PathBuf,
+    /// snapshot file name, relative to `dir`
+    pub dbfilename: String,
+    pub maxclients: usize,
+    /// seconds a client may stay idle before it is closed, 0 for never
+    pub timeout: u64,
+    /// seconds of silence before TCP keepalive probes start on a client socket, 0 for none
+    pub tcp_keepalive: u64,
+    /// one of `LOG_LEVELS`
+    pub loglevel: String,
+    /// only loopback and unix socket clients are served while the default user has no
+    /// password
+    pub protected_mode: bool,
+    /// the default user's password, `None` leaves it `nopass`
+    pub requirepass: Option<String>,
+    /// commands and the names clients call them by instead, an empty name disables one
+    pub rename_commands: Vec<(String, String)>,
+    /// users are loaded from and saved to this file, see `acl`
+    pub aclfile: Option<PathBuf>,
+    /// ACL LOG keeps at most this many entries
+    pub acllog_max_len: usize,
+    /// `client-output-buffer-limit` of each client class. output really queues up only for
+    /// pubsub clients and replicas: a normal client's reply is written before its next
+    /// command is read, so the normal limit only ever sees the one reply in flight
+    pub output_limits: OutputLimits,
+    pub replicaof: Option<(String, u16)>,
+    /// password sent to our master's `requirepass`
+    pub masterauth: Option<String>,
+    pub replica_read_only: bool,
+    pub replica_serve_stale_data: bool,
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
+    pub cluster_enabled: bool,
+    pub cluster_config_file: String,
+    /// milliseconds without a pong before a node is considered failing
+    pub cluster_node_timeout: u64,
+    /// parsed `notify-keyspace-events` classes, see `notify`
+    pub notify_keyspace_events: u32,
+    /// milliseconds a script may run before other clients get BUSY and SCRIPT KILL works
+    pub busy_reply_threshold: u64,
+    /// number of logical databases, SELECT takes 0 up to one less
+    pub databases: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            config_file: None,
+            port: DEFAULT_PORT,
+            bind: vec![DEFAULT_BIND.to_string()],
+            unixsocket: None,
+            unixsocketperm: 0,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: "yes".to_string(),
+            tls_replication: false,
+            tls_cluster: false,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            maxclients: 10000,
+            timeout: 0,
+            tcp_keepalive: 300,
+            loglevel: "notice".to_string(),
+            protected_mode: true,
+            requirepass: None,
+            rename_commands: Vec::new(),
+            aclfile: None,
+            acllog_max_len: 128,
+            output_limits: OutputLimits {
+                normal: OutputLimit::default(),
+                replica: OutputLimit { hard: 256 << 20, soft: 64 << 20, soft_seconds: 60 },
+                pubsub: OutputLimit { hard: 32 << 20, soft: 8 << 20, soft_seconds: 60 },
+            },
+            replicaof: None,
+            masterauth: None,
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_string(),
+            cluster_node_timeout: 15000,
+            notify_keyspace_events: 0,
+            busy_reply_threshold: 5000,
+            databases: 16,
+        }
+    }
+}
+
+/// a directive as it appears in redis.conf, `--name` on the command line and in CONFIG
+struct Param {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    /// CONFIG SET may change it while running
+    mutable: bool,
+    apply: fn(&mut Config, &[String]) -> Result<(), String>,
+    /// the value as the words of its directive, none when it is unset
+    get: fn(&Config) -> Vec<String>,
+}
+
+const PARAMS: &[Param] = &[
+    Param {
+        name: "port",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.port = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.port.to_string()],
+    },
+    Param {
+        name: "bind",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let addrs: Vec<String> = v.iter().flat_map(|s| s.split_whitespace()).map(str::to_string).collect();
+            if addrs.is_empty() {
+                return Err("wrong number of arguments".to_string());
+            }
+            c.bind = addrs;
+            Ok(())
+        },
+        get: |c| c.bind.clone(),
+    },
+    Param {
+        name: "unixsocket",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let path = single(v)?;
+            c.unixsocket = (!path.is_empty()).then(|| PathBuf::from(path));
+            Ok(())
+        },
+        get: |c| c.unixsocket.iter().map(|p| p.display().to_string()).collect(),
+    },
+    Param {
+        name: "unixsocketperm",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let value = single(v)?;
+            c.unixsocketperm = u32::from_str_radix(value, 8)
+                .ok()
+                .filter(|&mode| mode <= 0o777)
+                .ok_or_else(|| format!("invalid octal permissions '{value}'"))?;
+            Ok(())
+        },
+        get: |c| vec![format!("{:o}", c.unixsocketperm)],
+    },
+    Param {
+        name: "tls-port",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.tls_port = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.tls_port.to_string()],
+    },
+    Param {
+        name: "tls-cert-file",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let path = single(v)?;
+            c.tls_cert_file = (!path.is_empty()).then(|| PathBuf::from(path));
+            Ok(())
+        },
+        get: |c| c.tls_cert_file.iter().map(|p| p.display().to_string()).collect(),
+    },
+    Param {
+        name: "tls-key-file",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let path = single(v)?;
+            c.tls_key_file = (!path.is_empty()).then(|| PathBuf::from(path));
+            Ok(())
+        },
+        get: |c| c.tls_key_file.iter().map(|p| p.display().to_string()).collect(),
+    },
+    Param {
+        name: "tls-ca-cert-file",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let path = single(v)?;
+            c.tls_ca_cert_file = (!path.is_empty()).then(|| PathBuf::from(path));
+            Ok(())
+        },
+        get: |c| c.tls_ca_cert_file.iter().map(|p| p.display().to_string()).collect(),
+    },
+    Param {
+        name: "tls-auth-clients",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let mode = single(v)?.to_lowercase();
+            if !["yes", "no", "optional"].contains(&mode.as_str()) {
+                return Err("tls-auth-clients must be 'yes', 'no' or 'optional'".to_string());
+            }
+            c.tls_auth_clients = mode;
+            Ok(())
+        },
+        get: |c| vec![c.tls_auth_clients.clone()],
+    },
+    Param {
+        name: "tls-replication",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.tls_replication = yes_no(v)?;
+            Ok(())
+        },
+        get: |c| yes_no_value(c.tls_replication),
+    },
+    Param {
+        name: "tls-cluster",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.tls_cluster = yes_no(v)?;
+            Ok(())
+        },
+        get: |c| yes_no_value(c.tls_cluster),
+    },
+    Param {
+        name: "dir",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            c.dir = PathBuf::from(single(v)?);
+            Ok(())
+        },
+        get: |c| vec![c.dir.display().to_string()],
+    },
+    Param {
+        name: "dbfilename",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            let name = single(v)?;
+            if name.contains('/') {
+                return Err("dbfilename can't be a path, just a filename".to_string());
+            }
+            c.dbfilename = name.to_string();
+            Ok(())
+        },
+        get: |c| vec![c.dbfilename.clone()],
+    },
+    Param {
+        name: "maxclients",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            c.maxclients = number(v)?;
+            if c.maxclients == 0 {
+                return Err("maxclients must be at least 1".to_string());
+            }
+            Ok(())
+        },
+        get: |c| vec![c.maxclients.to_string()],
+    },
+    Param {
+        name: "timeout",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            c.timeout = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.timeout.to_string()],
+    },
+    Param {
+        name: "tcp-keepalive",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            c.tcp_keepalive = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.tcp_keepalive.to_string()],
+    },
+    Param {
+        name: "loglevel",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            let level = single(v)?.to_lowercase();
+            if !LOG_LEVELS.contains(&level.as_str()) {
+                return Err(format!("loglevel must be one of {}", LOG_LEVELS.join(", ")));
+            }
+            c.loglevel = level;
+            Ok(())
+        },
+        get: |c| vec![c.loglevel.clone()],
+    },
+    Param {
+        name: "protected-mode",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            c.protected_mode = yes_no(v)?;
+            Ok(())
+        },
+        get: |c| yes_no_value(c.protected_mode),
+    },
+    Param {
+        name: "requirepass",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            let password = single(v)?;
+            c.requirepass = (!password.is_empty()).then(|| password.to_string());
+            Ok(())
+        },
+        get: |c| vec![c.requirepass.clone().unwrap_or_default()],
+    },
+    Param {
+        // may be given several times, each adds to the earlier ones
+        name: "rename-command",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            if v.is_empty() || !v.len().is_multiple_of(2) {
+                return Err("wrong number of arguments".to_string());
+            }
+            for pair in v.chunks(2) {
+                if commands::lookup(&pair[0].to_uppercase()).is_none() {
+                    return Err(format!("No such command in rename-command: {}", pair[0]));
+                }
+                c.rename_commands.push((pair[0].clone(), pair[1].clone()));
+            }
+            Ok(())
+        },
+        get: |c| c.rename_commands.iter().flat_map(|(from, to)| [from.clone(), to.clone()]).collect(),
+    },
+    Param {
+        // `<class> <hard> <soft> <soft seconds>`, for any number of classes
+        name: "client-output-buffer-limit",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            // CONFIG SET passes all of it as one value
+            let words: Vec<&str> = v.iter().flat_map(|w| w.split_whitespace()).collect();
+            if words.is_empty() || !words.len().is_multiple_of(4) {
+                return Err("Wrong number of arguments in buffer limit configuration.".to_string());
+            }
+            let mut limits = c.output_limits;
+            for group in words.chunks(4) {
+                let limit = match group[0].to_lowercase().as_str() {
+                    "normal" => &mut limits.normal,
+                    "replica" | "slave" => &mut limits.replica,
+                    "pubsub" => &mut limits.pubsub,
+                    _ => return Err("Invalid client class specified in buffer limit configuration.".to_string()),
+                };
+                let invalid = |_| "Error in hard, soft or soft_seconds setting in buffer limit configuration.".to_string();
+                *limit = OutputLimit {
+                    hard: memory(group[1]).map_err(invalid)?,
+                    soft: memory(group[2]).map_err(invalid)?,
+                    soft_seconds: group[3].parse().map_err(|_| invalid(()))?,
+                };
+            }
+            c.output_limits = limits;
+            Ok(())
+        },
+        get: |c| {
+            let OutputLimits { normal, replica, pubsub } = c.output_limits;
+            [("normal", normal), ("slave", replica), ("pubsub", pubsub)]
+                .iter()
+                .flat_map(|(class, l)| {
+                    [class.to_string(), l.hard.to_string(), l.soft.to_string(), l.soft_seconds.to_string()]
+                })
+                .collect()
+        },
+    },
+    Param {
+        name: "aclfile",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            let path = single(v)?;
+            c.aclfile = (!path.is_empty()).then(|| PathBuf::from(path));
+            Ok(())
+        },
+        get: |c| vec![c.aclfile.as_ref().map(|p| p.display().to_string()).unwrap_or_default()],
+    },
+    Param {
+        name: "acllog-max-len",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            c.acllog_max_len = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.acllog_max_len.to_string()],
+    },
+    Param {
+        name: "replicaof",
+        aliases: &["slaveof"],
+        mutable: false,
+        apply: |c, v| {
+            // host and port, or both quoted into one argument
+            let parts: Vec<&str> = v.iter().flat_map(|s| s.split_whitespace()).collect();
+            let [host, port] = parts[..] else {
+                return Err("replicaof requires host and port".to_string());
+            };
+            c.replicaof = Some(parse_replicaof(host, port)?);
+            Ok(())
+        },
+        get: |c| c.replicaof.iter().flat_map(|(host, port)| [host.clone(), port.to_string()]).collect(),
+    },
+    Param {
+        name: "masterauth",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            let password = single(v)?;
+            c.masterauth = (!password.is_empty()).then(|| password.to_string());
+            Ok(())
+        },
+        get: |c| vec![c.masterauth.clone().unwrap_or_default()],
+    },
+    Param {
+        name: "replica-read-only",
+        aliases: &["slave-read-only"],
+        mutable: true,
+        apply: |c, v| {
+            c.replica_read_only = yes_no(v)?;
+            Ok(())
+        },
+        get: |c| yes_no_value(c.replica_read_only),
+    },
+    Param {
+        name: "replica-serve-stale-data",
+        aliases: &["slave-serve-stale-data"],
+        mutable: true,
+        apply: |c, v| {
+            c.replica_serve_stale_data = yes_no(v)?;
+            Ok(())
+        },
+        get: |c| yes_no_value(c.replica_serve_stale_data),
+    },
+    Param {
+        name: "min-replicas-to-write",
+        aliases: &["min-slaves-to-write"],
+        mutable: true,
+        apply: |c, v| {
+            c.min_replicas_to_write = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.min_replicas_to_write.to_string()],
+    },
+    Param {
+        name: "min-replicas-max-lag",
+        aliases: &["min-slaves-max-lag"],
+        mutable: true,
+        apply: |c, v| {
+            c.min_replicas_max_lag = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.min_replicas_max_lag.to_string()],
+    },
+    Param {
+        name: "cluster-enabled",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.cluster_enabled = yes_no(v)?;
+            Ok(())
+        },
+        get: |c| yes_no_value(c.cluster_enabled),
+    },
+    Param {
+        name: "cluster-config-file",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.cluster_config_file = single(v)?.to_string();
+            Ok(())
+        },
+        get: |c| vec![c.cluster_config_file.clone()],
+    },
+    Param {
+        name: "cluster-node-timeout",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.cluster_node_timeout = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.cluster_node_timeout.to_string()],
+    },
+    Param {
+        name: "notify-keyspace-events",
+        aliases: &[],
+        mutable: true,
+        apply: |c, v| {
+            let value = single(v)?;
+            c.notify_keyspace_events =
+                notify::parse_flags(value).ok_or_else(|| format!("invalid event class in '{value}'"))?;
+            Ok(())
+        },
+        get: |c| vec![notify::flags_to_string(c.notify_keyspace_events)],
+    },
+    Param {
+        name: "busy-reply-threshold",
+        aliases: &["lua-time-limit"],
+        mutable: true,
+        apply: |c, v| {
+            c.busy_reply_threshold = number(v)?;
+            Ok(())
+        },
+        get: |c| vec![c.busy_reply_threshold.to_string()],
+    },
+    Param {
+        name: "databases",
+        aliases: &[],
+        mutable: false,
+        apply: |c, v| {
+            c.databases = number(v)?;
+            if c.databases == 0 {
+                return Err("databases must be at least 1".to_string());
+            }
+            Ok(())
+        },
+        get: |c| vec![c.databases.to_string()],
+    },
+];
+
+impl Config {
+    /// `[config-file] [--<option> <value> ...]`, as redis-server takes them: the file is
+    /// read first, options given on the command line override it
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Config, String> {
+        let mut args = args.peekable();
+        let mut config = Config::default();
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.load_file(&path, &mut Vec::new())?;
+            // made absolute before `dir` is entered
+            let path = fs::canonicalize(&path).map_err(|e| format!("can't open config file '{path}': {e}"))?;
+            config.config_file = Some(path);
+        }
+
+        let mut directive: Option<(String, Vec<String>)> = None;
+        for arg in args {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    if let Some((name, values)) = directive.take() {
+                        config.set(&name, &values).map_err(|e| format!("--{name}: {e}"))?;
+                    }
+                    directive = Some((name.to_string(), Vec::new()));
+                }
+                None => match directive.as_mut() {
+                    Some((_, values)) => values.push(arg),
+                    None => return Err(format!("unexpected argument '{arg}'")),
+                },
+            }
+        }
+        if let Some((name, values)) = directive {
+            config.set(&name, &values).map_err(|e| format!("--{name}: {e}"))?;
+        }
+        if config.cluster_enabled && config.bus_port().is_none() {
+            return Err(format!(
+                "port {} is too high for cluster mode, the bus port is {BUS_PORT_OFFSET} above it and must stay below 65536",
+                config.port
+            ));
+        }
+        Ok(config)
+    }
+
+    /// applies a redis.conf file. `include` pulls in another file at that point, `including`
+    /// holds the files being read around this one, so an include cycle is an error
+    fn load_file(&mut self, path: &str, including: &mut Vec<PathBuf>) -> Result<(), String> {
+        let canonical = fs::canonicalize(path).map_err(|e| format!("can't open config file '{path}': {e}"))?;
+        if including.contains(&canonical) {
+            return Err(format!("config file '{path}' includes itself"));
+        }
+        if including.len() >= MAX_INCLUDE_DEPTH {
+            return Err(format!("config file '{path}' is included more than {MAX_INCLUDE_DEPTH} levels deep"));
+        }
+        let text = fs::read_to_string(path).map_err(|e| format!("can't open config file '{path}': {e}"))?;
+        including.push(canonical);
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let context = |e: String| format!("{path}:{}: '{line}': {e}", number + 1);
+            let words = split_args(line).map_err(context)?;
+            let Some((name, values)) = words.split_first() else {
+                continue;
+            };
+            if name.eq_ignore_ascii_case("include") {
+                self.load_file(single(values).map_err(context)?, including)?;
+            } else {
+                self.set(name, values).map_err(context)?;
+            }
+        }
+        including.pop();
+        Ok(())
+    }
+
+    /// applies one directive by name or alias, case insensitive
+    pub fn set(&mut self, name: &str, values: &[String]) -> Result<(), String> {
+        let param = find(name).ok_or_else(|| "bad directive or wrong number of arguments".to_string())?;
+        (param.apply)(self, values)
+    }
+
+    /// makes `dir` the working directory, and `dir` absolute
+    pub fn enter_dir(&mut self) -> std::io::Result<()> {
+        std::env::set_current_dir(&self.dir)?;
+        self.dir = std::env::current_dir()?;
+        Ok(())
+    }
+
+    /// the cluster bus listens this far above the client port
+    pub fn bus_port(&self) -> Option<u16> {
+        self.port.checked_add(BUS_PORT_OFFSET)
+    }
+
+    /// the address other nodes reach us on: the first bound one, unless that is a wildcard
+    pub fn announce_ip(&self) -> &str {
+        match self.bind.first().map(|a| a.trim_start_matches('-')) {
+            Some("*" | "0.0.0.0" | "::" | "::*") | None => DEFAULT_BIND,
+            Some(addr) => addr,
+        }
+    }
+
+    pub fn log_filter(&self) -> LevelFilter {
+        match self.loglevel.as_str() {
+            "debug" => LevelFilter::TRACE,
+            "verbose" => LevelFilter::DEBUG,
+            "warning" => LevelFilter::WARN,
+            "nothing" => LevelFilter::OFF,
+            _ => LevelFilter::INFO,
+        }
+    }
+}
+
+fn find(name: &str) -> Option<&'static Param> {
+    let name = name.to_lowercase();
+    PARAMS.iter().find(|p| p.name == name || p.aliases.contains(&name.as_str()))
+}
+
+static LOG_LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// installs the global subscriber at the configured `loglevel`, which CONFIG SET can change
+pub fn init_logging(config: &Config) {
+    let (level, handle) = reload::Layer::new(config.log_filter());
+    Registry::default().with(level).with(ForestLayer::default()).init();
+    let _ = LOG_LEVEL.set(handle);
+}
+
+/// CONFIG GET pattern [pattern ...] | SET name value [name value ...] | RESETSTAT | REWRITE
+pub fn command(args: &[RespOrig], server: &Server) -> RespOrig {
+    let Some(sub) = args.first().and_then(arg_str).map(str::to_uppercase) else {
+        return wrong_arity("config");
+    };
+    let args = &args[1..];
+    match sub.as_str() {
+        "GET" if !args.is_empty() => config_get(args, &server.config()),
+        "SET" if !args.is_empty() && args.len().is_multiple_of(2) => config_set(args, server),
+        "GET" | "SET" => wrong_arity(&format!("config|{}", sub.to_lowercase())),
+        "RESETSTAT" => {
+            server.stats.reset();
+            info!("Statistics reset");
+            ok()
+        }
+        "REWRITE" => {
+            let config = server.config().clone();
+            match rewrite(&config) {
+                Ok(()) => ok(),
+                Err(e) => error(&format!("ERR {e}")),
+            }
+        }
+        _ => error(&format!(
+            "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+            sub.to_lowercase()
+        )),
+    }
+}
+
+/// name and value of every parameter matching one of the patterns. an alias is only
+/// reported when asked for by its exact name
+fn config_get(patterns: &[RespOrig], config: &Config) -> RespOrig {
+    let patterns: Vec<&[u8]> = patterns.iter().filter_map(RespOrig::as_bytes).map(|p| &p[..]).collect();
+    let mut out = Vec::new();
+    let mut seen = HashSet::new();
+    for param in PARAMS {
+        let names = std::iter::once(param.name).chain(param.aliases.iter().copied());
+        for (i, name) in names.enumerate() {
+            let wanted = patterns.iter().any(|p| {
+                if i == 0 {
+                    glob::matches(p, name.as_bytes(), true)
+                } else {
+                    p.eq_ignore_ascii_case(name.as_bytes())
+                }
+            });
+            if wanted && seen.insert(name) {
+                out.push(RespOrig::BulkString(Bytes::from_static(name.as_bytes())));
+                out.push(RespOrig::BulkString(Bytes::from((param.get)(config).join(" "))));
+            }
+        }
+    }
+    RespOrig::Array(out)
+}
+
+/// applies every pair or none of them
+fn config_set(args: &[RespOrig], server: &Server) -> RespOrig {
+    let failed = |name: &str, reason: &str| {
+        error(&format!("ERR CONFIG SET failed (possibly related to argument '{name}') - {reason}"))
+    };
+    let mut config = server.config().clone();
+    let mut seen = HashSet::new();
+    for pair in args.chunks(2) {
+        let (Some(name), Some(value)) = (arg_str(&pair[0]), arg_str(&pair[1])) else {
+            return error("ERR CONFIG SET failed - arguments must be valid utf-8");
+        };
+        let Some(param) = find(name) else {
+            return error(&format!("ERR Unknown option or number of arguments for CONFIG SET - '{name}'"));
+        };
+        if !param.mutable {
+            return failed(name, "can't set immutable config");
+        }
+        if !seen.insert(param.name) {
+            return failed(name, "duplicate parameter");
+        }
+        if let Err(e) = (param.apply)(&mut config, &[value.to_string()]) {
+            return failed(name, &e);
+        }
+    }
+
+    let mut current = server.config_mut();
+    // the only change that can still fail, done before anything is committed
+    if config.dir != current.dir {
+        if let Err(e) = config.enter_dir() {
+            return failed("dir", &e.to_string());
+        }
+    }
+    if config.loglevel != current.loglevel {
+        if let Some(handle) = LOG_LEVEL.get() {
+            let _ = handle.modify(|level| *level = config.log_filter());
+        }
+    }
+    // the default user's password follows `requirepass`
+    if config.requirepass != current.requirepass {
+        server.acl.lock().unwrap().set_requirepass(config.requirepass.as_deref());
+    }
+    if config.output_limits != current.output_limits {
+        server.clients.set_limits(config.output_limits);
+    }
+    info!(params = seen.len(), "Configuration changed");
+    *current = config;
+    ok()
+}
+
+/// writes the current configuration into the file it came from. lines of parameters are
+/// updated in place, or dropped when the parameter is no longer set. comments and
+/// unknown lines stay, and changed parameters the file does not mention go at the end
+fn rewrite(config: &Config) -> Result<(), String> {
+    let Some(path) = &config.config_file else {
+        return Err("The server is running without a config file".to_string());
+    };
+    let old = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Rewriting config file: {e}")),
+    };
+
+    let directive = |param: &Param| {
+        let words = (param.get)(config);
+        (!words.is_empty()).then(|| {
+            let values: Vec<String> = words.iter().map(|w| quote(w)).collect();
+            format!("{} {}", param.name, values.join(" "))
+        })
+    };
+    let mut lines = Vec::new();
+    let mut written = HashSet::new();
+    for line in old.lines() {
+        let trimmed = line.trim();
+        let param = split_args(trimmed)
+            .ok()
+            .filter(|_| !trimmed.starts_with('#'))
+            .and_then(|words| words.first().and_then(|name| find(name)));
+        match param {
+            // later occurrences would override the rewritten one, so they go
+            Some(param) if written.insert(param.name) => lines.extend(directive(param)),
+            Some(_) => {}
+            None => lines.push(line.to_string()),
+        }
+    }
+    let defaults = Config::default();
+    let mut marked = lines.iter().any(|l| l == REWRITE_MARKER);
+    for param in PARAMS.iter().filter(|p| !written.contains(p.name)) {
+        if (param.get)(config) == (param.get)(&defaults) {
+            continue;
+        }
+        if let Some(line) = directive(param) {
+            if !marked {
+                lines.push(REWRITE_MARKER.to_string());
+                marked = true;
+            }
+            lines.push(line);
+        }
+    }
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+    fs::write(&tmp, text)
+        .and_then(|()| fs::rename(&tmp, path))
+        .map_err(|e| format!("Rewriting config file: {e}"))?;
+    info!(path = ?path, "Config file rewritten");
+    Ok(())
+}
+
+const REWRITE_MARKER: &str = "# Generated by CONFIG REWRITE";
+
+/// a word as `split_args` reads it back, quoted when it has to be
+fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word.chars().all(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\'' | '\\' | '#'));
+    if plain {
+        return word.to_string();
+    }
+    let mut out = String::from("\"");
+    for c in word.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                    out.push_str(&format!("\\x{byte:02x}"));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// splits a config line into words. double quotes allow `\n`, `\t`, `\"`, `\\` and `\xHH`
+/// escapes, single quotes only `\'`. `\xHH` is one byte, the bytes of a word must be UTF-8
+pub(crate) fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(words);
+        };
+        let mut word = String::new();
+        match first {
+            '"' => {
+                let mut bytes = Vec::new();
+                loop {
+                    match chars.next().ok_or("unbalanced quotes")? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or("unbalanced quotes")? {
+                            'n' => push_char(&mut bytes, '\n'),
+                            'r' => push_char(&mut bytes, '\r'),
+                            't' => push_char(&mut bytes, '\t'),
+                            'x' => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                let byte = u8::from_str_radix(&hex, 16).map_err(|_| "invalid \\x escape")?;
+                                bytes.push(byte);
+                            }
+                            c => push_char(&mut bytes, c),
+                        },
+                        c => push_char(&mut bytes, c),
+                    }
+                }
+                word = String::from_utf8(bytes).map_err(|_| "\\x escapes must form valid UTF-8")?;
+            }
+            '\'' => loop {
+                match chars.next().ok_or("unbalanced quotes")? {
+                    '\'' => break,
+                    '\\' if chars.peek() == Some(&'\'') => word.push(chars.next().unwrap_or('\'')),
+                    c => word.push(c),
+                }
+            },
+            c => {
+                word.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    word.push(c);
+                }
+                words.push(word);
+                continue;
+            }
+        }
+        // a closing quote must end the word
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return Err("closing quote must be followed by a space".to_string());
+        }
+        words.push(word);
+    }
+}
+
+fn push_char(bytes: &mut Vec<u8>, c: char) {
+    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+fn single(values: &[String]) -> Result<&str, String> {
+    match values {
+        [value] => Ok(value),
+        _ => Err("wrong number of arguments".to_string()),
+    }
+}
+
+fn yes_no(values: &[String]) -> Result<bool, String> {
+    match single(values)?.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn yes_no_value(value: bool) -> Vec<String> {
+    vec![if value { "yes" } else { "no" }.to_string()]
+}
+
+/// a byte count with an optional unit: `k`, `m` and `g` are powers of 1000, `kb`, `mb` and
+/// `gb` powers of 1024
+fn memory(value: &str) -> Result<u64, ()> {
+    let lower = value.to_lowercase();
+    let split = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1 << 10,
+        "m" => 1000 * 1000,
+        "mb" => 1 << 20,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1 << 30,
+        _ => return Err(()),
+    };
+    digits.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)).ok_or(())
+}
+
+fn number<T: std::str::FromStr>(values: &[String]) -> Result<T, String> {
+    let value = single(values)?;
+    value.parse().map_err(|_| format!("invalid value '{value}'"))
+}
+
+pub fn parse_replicaof(host: &str, port: &str) -> Result<(String, u16), String> {
+    let port = port
+        .parse()
+        .map_err(|_| format!("invalid master port '{port}'"))?;
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        split_args(line).unwrap()
+    }
+
+    #[test]
+    fn split_args_handles_quotes_and_escapes() {
+        assert_eq!(words("  save 900   1 "), ["save", "900", "1"]);
+        assert_eq!(words(r#"requirepass "a b" 'c d'"#), ["requirepass", "a b", "c d"]);
+        assert_eq!(words(r#""tab\there" 'it\'s'"#), ["tab\there", "it's"]);
+        assert_eq!(words(r#""\x41\xc3\xa9""#), ["Aé"]);
+        assert!(split_args(r#""\xff""#).is_err());
+        assert!(split_args(r#""open"#).is_err());
+        assert!(split_args(r#""a"b"#).is_err());
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        for word in ["plain", "", "a b", "quote\"back\\slash", "line\nbreak", "bell\u{7}", "c1\u{85}", "é"] {
+            assert_eq!(words(&quote(word)), [word]);
+        }
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.conf");
+        let b = dir.path().join("b.conf");
+        fs::write(&a, format!("port 7000\ninclude {}\n", b.display())).unwrap();
+        fs::write(&b, format!("include {}\n", a.display())).unwrap();
+        let err = Config::default().load_file(a.to_str().unwrap(), &mut Vec::new()).unwrap_err();
+        assert!(err.contains("includes itself"), "{err}");
+
+        // including the same file twice, one after the other, is fine
+        fs::write(&b, "maxclients 5\n").unwrap();
+        fs::write(&a, format!("include {0}\ninclude {0}\n", b.display())).unwrap();
+        let mut config = Config::default();
+        config.load_file(a.to_str().unwrap(), &mut Vec::new()).unwrap();
+        assert_eq!(config.maxclients, 5);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::acl::DEFAULT_USER;
+use crate::clients::{OutputBuffer, Registration};
+use crate::parser::RespOrig;
+use crate::pubsub::{Push, Subscriber};
+use crate::server::Server;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// per-connection state, threaded through command execution
+#[derive(Debug)]
+pub struct Connection {
+    /// CLIENT ID, 0 for connections that are not in the client table, like a script's
+    pub id: u64,
+    /// CLIENT SETNAME
+    pub name: Option<String>,
+    /// set by ASKING, applies to the next command only
+    pub asking: bool,
+    /// the SELECTed database
+    pub db: usize,
+    /// passed AUTH, or the default user needed no password when the client connected
+    pub authenticated: bool,
+    /// whose ACL permissions apply
+    pub user: String,
+    /// QUIT was sent, the connection closes once the reply is written
+    pub quit: bool,
+    /// CLIENT NO-EVICT and NO-TOUCH. there is no eviction or LRU clock, so they are only shown
+    pub no_evict: bool,
+    pub no_touch: bool,
+    /// what is queued for the client, see `attach`
+    pub output: Arc<OutputBuffer>,
+    pub subscriber: Subscriber,
+    /// open between MULTI and EXEC / DISCARD
+    pub transaction: Option<Transaction>,
+    /// EXEC is running the queue and already holds the exclusive lock
+    pub(crate) in_exec: bool,
+    /// WATCHed keys with their database, `dirty` is raised by the keyspace once any of
+    /// them changes
+    watched: Vec<(usize, Bytes)>,
+    dirty: Arc<AtomicBool>,
+    server: Arc<Server>,
+}
+
+#[derive(Debug, Default)]
+pub struct Transaction {
+    pub queue: Vec<RespOrig>,
+    /// a command was rejected while queueing, EXEC will abort
+    pub failed: bool,
+}
+
+impl Connection {
+    /// the receiver yields the subscriber's pushes, see `Subscriber::deliver`
+    pub fn new(server: Arc<Server>) -> (Connection, UnboundedReceiver<Push>) {
+        let output = Arc::new(OutputBuffer::default());
+        let (subscriber, pushes) = Subscriber::new(server.clone(), output.clone());
+        let authenticated = server.acl.lock().unwrap().default_authenticates();
+        let connection = Connection {
+            id: 0,
+            name: None,
+            asking: false,
+            db: 0,
+            authenticated,
+            user: DEFAULT_USER.to_string(),
+            quit: false,
+            no_evict: false,
+            no_touch: false,
+            output,
+            subscriber,
+            transaction: None,
+            in_exec: false,
+            watched: Vec::new(),
+            dirty: Arc::new(AtomicBool::new(false)),
+            server,
+        };
+        (connection, pushes)
+    }
+
+    /// RESET: back to how a new connection starts out, keeping only the client id
+    pub fn reset(&mut self) {
+        self.transaction = None;
+        self.unwatch();
+        self.subscriber.unsubscribe_all();
+        self.name = None;
+        self.asking = false;
+        self.db = 0;
+        self.user = DEFAULT_USER.to_string();
+        self.authenticated = self.server.acl.lock().unwrap().default_authenticates();
+        self.no_evict = false;
+        self.no_touch = false;
+    }
+
+    /// makes this the connection of a client in the table, before it runs any command
+    pub fn attach(&mut self, client: &Registration) {
+        self.id = client.id;
+        self.output = client.output.clone();
+        self.subscriber.output = client.output.clone();
+    }
+
+    /// commands are being queued rather than run
+    pub fn in_multi(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// a rejected command inside MULTI makes the whole transaction fail
+    pub(crate) fn flag_transaction(&mut self) {
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.failed = true;
+        }
+    }
+
+    /// watches keys of the selected database
+    pub fn watch(&mut self, keys: impl IntoIterator<Item = Bytes>) {
+        let mut db = self.server.db(self.db);
+        for key in keys {
+            let watched = (self.db, key);
+            if !self.watched.contains(&watched) {
+                db.watch(watched.1.clone(), &self.dirty);
+                self.watched.push(watched);
+            }
+        }
+    }
+
+    pub fn watch_count(&self) -> usize {
+        self.watched.len()
+    }
+
+    pub fn unwatch(&mut self) {
+        if self.watched.is_empty() {
+            return;
+        }
+        for (index, key) in self.watched.drain(..) {
+            self.server.db(index).unwatch(&key, &self.dirty);
+        }
+        self.dirty.store(false, Ordering::Relaxed);
+    }
+
+    /// whether a watched key changed since WATCH. keys whose ttl ran out meanwhile count as
+    /// changed even if nobody removed them yet
+    pub(crate) fn watch_dirty(&self) -> bool {
+        for (index, key) in &self.watched {
+            // removes the key if it is due, which raises `dirty`
+            self.server.db(*index).contains(key);
+        }
+        self.dirty.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
@@ -0,0 +1,180 @@
+use bytes::Bytes;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, trace};
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub value: Bytes,
+    /// absolute unix time in milliseconds
+    pub expires_at: Option<u64>,
+}
+
+impl Entry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// keyspace. expired keys are removed lazily when touched, and by the active cycle
+/// through the expiry index
+#[derive(Debug, Default)]
+pub struct Db {
+    entries: HashMap<Bytes, Entry>,
+    /// keys with a ttl, ordered by expiry time
+    expiry: BTreeSet<(u64, Bytes)>,
+    /// removed because they expired, waiting to be announced (see `take_expired`)
+    expired: Vec<Bytes>,
+    /// WATCHed keys and the dirty flags of the connections watching them
+    watched: HashMap<Bytes, Vec<Arc<AtomicBool>>>,
+}
+
+impl Db {
+    pub fn get(&mut self, key: &[u8]) -> Option<&Entry> {
+        self.expire_if_needed(key);
+        self.entries.get(key)
+    }
+
+    pub fn set(&mut self, key: Bytes, entry: Entry) {
+        trace!(key = ?key, "Setting key");
+        let expires_at = entry.expires_at;
+        self.touch(&key);
+        if let Some(old) = self.entries.insert(key.clone(), entry) {
+            self.unindex(&key, &old);
+        }
+        if let Some(at) = expires_at {
+            self.expiry.insert((at, key));
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.expire_if_needed(key);
+        let entry = self.entries.remove(key)?;
+        self.unindex(key, &entry);
+        self.touch(key);
+        Some(entry)
+    }
+
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// empties the keyspace and hands back what it held, so a large one can be dropped
+    /// off the command path (FLUSHDB ASYNC)
+    pub fn clear(&mut self) -> HashMap<Bytes, Entry> {
+        let keys: Vec<Bytes> = self.entries.keys().cloned().collect();
+        for key in keys {
+            self.touch(&key);
+        }
+        self.expiry.clear();
+        std::mem::take(&mut self.entries)
+    }
+
+    /// SWAPDB: exchanges the data but not the watchers, which belong to the index.
+    /// a watched key that exists on either side changes
+    pub fn swap(&mut self, other: &mut Db) {
+        for (db, peer) in [(&*self, &*other), (&*other, &*self)] {
+            for (key, watchers) in &db.watched {
+                if db.entries.contains_key(key) || peer.entries.contains_key(key) {
+                    for dirty in watchers {
+                        dirty.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        std::mem::swap(&mut self.entries, &mut other.entries);
+        std::mem::swap(&mut self.expiry, &mut other.expiry);
+        debug!(keys = self.entries.len(), other = other.entries.len(), "Swapped databases");
+    }
+
+    /// live entries, used when taking a snapshot
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Entry)> {
+        let now = now_ms();
+        self.entries.iter().filter(move |(_, e)| !e.is_expired(now))
+    }
+
+    /// removes up to `limit` keys whose time has come, returns how many went
+    pub fn expire_due(&mut self, now: u64, limit: usize) -> usize {
+        let mut removed = 0;
+        while removed < limit {
+            let Some((at, key)) = self.expiry.first().cloned() else {
+                break;
+            };
+            if at > now {
+                break;
+            }
+            self.expiry.pop_first();
+            trace!(key = ?key, "Key expired by active cycle");
+            self.entries.remove(&key);
+            self.touch(&key);
+            self.expired.push(key);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// keys that expired since the last call
+    pub fn take_expired(&mut self) -> Vec<Bytes> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// `dirty` is raised once the key is modified, expired or flushed
+    pub fn watch(&mut self, key: Bytes, dirty: &Arc<AtomicBool>) {
+        // a key that already expired must not fail the transaction when it gets removed
+        self.expire_if_needed(&key);
+        trace!(key = ?key, "Watching key");
+        self.watched.entry(key).or_default().push(dirty.clone());
+    }
+
+    pub fn unwatch(&mut self, key: &[u8], dirty: &Arc<AtomicBool>) {
+        if let Some(watchers) = self.watched.get_mut(key) {
+            watchers.retain(|w| !Arc::ptr_eq(w, dirty));
+            if watchers.is_empty() {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(watchers) = self.watched.get(key) {
+            trace!(key = ?key, watchers = watchers.len(), "Touched watched key");
+            for dirty in watchers {
+                dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn expire_if_needed(&mut self, key: &[u8]) {
+        if self.entries.get(key).is_some_and(|e| e.is_expired(now_ms())) {
+            debug!(key = ?key, "Key expired, removing");
+            if let Some((key, entry)) = self.entries.remove_entry(key) {
+                self.unindex(&key, &entry);
+                self.touch(&key);
+                self.expired.push(key);
+            }
+        }
+    }
+
+    fn unindex(&mut self, key: &[u8], entry: &Entry) {
+        if let Some(at) = entry.expires_at {
+            self.expiry.remove(&(at, Bytes::copy_from_slice(key)));
+        }
+    }
+}
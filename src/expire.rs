@@ -0,0 +1,56 @@
+use crate::clients;
+use crate::notify;
+use crate::parser::RespOrig;
+use crate::replication;
+use crate::server::{Server, Stats};
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+const CYCLE_INTERVAL: Duration = Duration::from_millis(100);
+/// keys removed per run before yielding, so a mass expiry cannot stall clients
+const KEYS_PER_CYCLE: usize = 200;
+
+/// announces keys the keyspaces dropped because they expired: an `expired` event for each,
+/// and a DEL so the replicas drop them too
+pub fn flush_expired(server: &Server) {
+    for index in 0..server.dbs.len() {
+        let expired = server.db(index).take_expired();
+        if expired.is_empty() {
+            continue;
+        }
+        debug!(db = index, keys = expired.len(), "Announcing expired keys");
+        Stats::incr(&server.stats.expired_keys, expired.len() as u64);
+        for key in &expired {
+            notify::keyspace_event(server, index, notify::EXPIRED, "expired", key);
+        }
+        let mut del = vec![RespOrig::BulkString(Bytes::from_static(b"DEL"))];
+        del.extend(expired.into_iter().map(RespOrig::BulkString));
+        replication::propagate_to_db(server, index, RespOrig::Array(del));
+    }
+}
+
+/// removes expired keys nobody touches. replicas leave that to their master's DELs, and
+/// nothing expires during CLIENT PAUSE
+pub async fn active_expire_cycle(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(CYCLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if server.replication.lock().unwrap().master.is_some() || clients::is_paused(&server) {
+            continue;
+        }
+        let now = crate::db::now_ms();
+        let removed = {
+            // never in the middle of a transaction or script, the next tick will do
+            let Ok(_shared) = server.exec_lock.try_read() else {
+                continue;
+            };
+            server.dbs.iter().map(|db| db.lock().unwrap().expire_due(now, KEYS_PER_CYCLE)).sum::<usize>()
+        };
+        if removed > 0 {
+            debug!(removed, "Active expire cycle removed keys");
+            flush_expired(&server);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use crate::commands::{Command, NO_KEYS, WRITE};
+use crate::connection::Connection;
+use crate::glob;
+use crate::handler::{arg_str, error, ok, wrong_arity};
+use crate::parser::RespOrig;
+use crate::rdb;
+use crate::replication;
+use crate::scripting::{self, Invocation};
+use crate::server::Server;
+use bytes::Bytes;
+use mlua::{Lua, MultiValue, RegistryKey, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+const FLAGS: &[&str] = &["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+/// what the replica guard sees for FUNCTION LOAD, DELETE, FLUSH and RESTORE
+const MUTATION: Command = Command {
+    name: "FUNCTION",
+    arity: -2,
+    flags: WRITE,
+    acl: 0,
+    keys: NO_KEYS,
+};
+
+/// the loaded libraries, with a lua VM of their own
+#[derive(Debug)]
+pub struct Functions {
+    lua: Lua,
+    libraries: BTreeMap<String, Library>,
+    /// function name to the library defining it
+    index: HashMap<String, String>,
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        Functions { lua: scripting::new_vm(), libraries: BTreeMap::new(), index: HashMap::new() }
+    }
+}
+
+#[derive(Debug)]
+struct Library {
+    code: Bytes,
+    functions: BTreeMap<String, Function>,
+}
+
+#[derive(Debug)]
+struct Function {
+    callback: RegistryKey,
+    description: Option<String>,
+    flags: Vec<String>,
+}
+
+impl Function {
+    fn no_writes(&self) -> bool {
+        self.flags.iter().any(|f| f == "no-writes")
+    }
+}
+
+impl Functions {
+    /// compiles every library into a fresh VM, all or nothing
+    fn build(codes: impl IntoIterator<Item = Bytes>) -> Result<Functions, RespOrig> {
+        let mut functions = Functions::default();
+        for code in codes {
+            let name = metadata(&code)?;
+            if functions.libraries.contains_key(&name) {
+                return Err(error(&format!("ERR Library '{name}' already exists")));
+            }
+            let library = functions.compile(code)?;
+            for function in library.functions.keys() {
+                if functions.index.contains_key(function) {
+                    return Err(error(&format!("ERR Function {function} already exists")));
+                }
+                functions.index.insert(function.clone(), name.clone());
+            }
+            functions.libraries.insert(name, library);
+        }
+        Ok(functions)
+    }
+
+    /// runs the library code, which may do nothing but `redis.register_function`
+    fn compile(&self, code: Bytes) -> Result<Library, RespOrig> {
+        let lua = &self.lua;
+        // the metadata line is not lua. keep the line count for error messages
+        let body = code.iter().position(|&b| b == b'\n').map_or(&code[..0], |nl| &code[nl..]);
+        let chunk = lua
+            .load(body)
+            .set_name("@user_function")
+            .into_function()
+            .map_err(|e| scripting::compile_error(&e))?;
+
+        let registered = RefCell::new(BTreeMap::new());
+        lua.scope(|scope| {
+            let redis = lua.create_table()?;
+            redis.set(
+                "register_function",
+                scope.create_function(|lua, args: MultiValue| {
+                    let (name, function) = registration(lua, args)?;
+                    let mut registered = registered.borrow_mut();
+                    if registered.contains_key(&name) {
+                        return Err(mlua::Error::RuntimeError(
+                            "Function already exists in the library".to_string(),
+                        ));
+                    }
+                    registered.insert(name, function);
+                    Ok(())
+                })?,
+            )?;
+            lua.globals().set("redis", redis)?;
+            chunk.call::<_, ()>(())
+        })
+        .map_err(|e| scripting::script_error(&e, ""))?;
+
+        let functions = registered.into_inner();
+        if functions.is_empty() {
+            return Err(error("ERR No functions registered"));
+        }
+        Ok(Library { code, functions })
+    }
+
+    fn codes(&self) -> impl Iterator<Item = Bytes> + '_ {
+        self.libraries.values().map(|library| library.code.clone())
+    }
+}
+
+/// `redis.register_function(name, callback)` or
+/// `redis.register_function{function_name=..., callback=..., flags={...}, description=...}`
+fn registration(lua: &Lua, args: MultiValue) -> mlua::Result<(String, Function)> {
+    let invalid = |msg: &str| mlua::Error::RuntimeError(msg.to_string());
+    let args: Vec<Value> = args.into_iter().collect();
+    let (name, callback, flags, description) = match args.as_slice() {
+        [Value::String(name), Value::Function(callback)] => {
+            (name.to_str()?.to_string(), callback.clone(), Vec::new(), None)
+        }
+        [Value::Table(table)] => {
+            let name: String = table
+                .get::<_, Option<String>>("function_name")?
+                .ok_or_else(|| invalid("redis.register_function must get a function name argument"))?;
+            let callback: mlua::Function = table
+                .get::<_, Option<mlua::Function>>("callback")?
+                .ok_or_else(|| invalid("redis.register_function must get a callback argument"))?;
+            let flags = table.get::<_, Option<Vec<String>>>("flags")?.unwrap_or_default();
+            let description = table.get::<_, Option<String>>("description")?;
+            (name, callback, flags, description)
+        }
+        _ => return Err(invalid("wrong number of arguments to redis.register_function")),
+    };
+    if !valid_name(&name) {
+        return Err(invalid(
+            "Function names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+        ));
+    }
+    if let Some(flag) = flags.iter().find(|f| !FLAGS.contains(&f.as_str())) {
+        return Err(invalid(&format!("unknown flag given: {flag}")));
+    }
+    let callback = lua.create_registry_value(callback)?;
+    Ok((name, Function { callback, description, flags }))
+}
+
+/// the library name from the `#!lua name=<library>` first line
+fn metadata(code: &[u8]) -> Result<String, RespOrig> {
+    let first_line = code.split(|&b| b == b'\n').next().unwrap_or_default();
+    let Some(shebang) = std::str::from_utf8(first_line).ok().and_then(|l| l.strip_prefix("#!")) else {
+        return Err(error("ERR Missing library metadata"));
+    };
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(error(&format!("ERR Engine '{engine}' not found")));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some(("name", value)) => name = Some(value.to_string()),
+            _ => return Err(error(&format!("ERR Invalid metadata value given: {part}"))),
+        }
+    }
+    let name = name.ok_or_else(|| error("ERR Library name was not given"))?;
+    if !valid_name(&name) {
+        return Err(error(
+            "ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+        ));
+    }
+    Ok(name)
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// library sources, as saved into rdb snapshots
+pub fn sources(functions: &Functions) -> Vec<Bytes> {
+    functions.codes().collect()
+}
+
+/// replaces every library with the ones from an rdb snapshot
+pub fn load_all(server: &Server, codes: Vec<Bytes>) {
+    match Functions::build(codes) {
+        Ok(functions) => {
+            info!(libraries = functions.libraries.len(), "Loaded function libraries");
+            *server.functions.lock().unwrap() = functions;
+        }
+        Err(e) => warn!(error = ?e, "Failed to load function libraries from rdb"),
+    }
+}
+
+/// `FUNCTION LOAD|DELETE|FLUSH|LIST|DUMP|RESTORE|KILL`. libraries changed on a master
+/// reach its replicas as the same FUNCTION command
+pub fn command(items: &[RespOrig], server: &Server, guarded: bool) -> RespOrig {
+    let Some(sub) = items.get(1).and_then(arg_str).map(str::to_uppercase) else {
+        return wrong_arity("function");
+    };
+    let args = &items[2..];
+    let mutation = matches!(sub.as_str(), "LOAD" | "DELETE" | "FLUSH" | "RESTORE");
+    if mutation && guarded {
+        if let Some(denied) = replication::guard(server, &MUTATION) {
+            return denied;
+        }
+    }
+
+    // a running FCALL holds the libraries
+    if sub == "KILL" {
+        return match args {
+            [] => scripting::kill(server),
+            _ => wrong_arity("function|kill"),
+        };
+    }
+
+    // held until the change is propagated, so a full resync sees the libraries and the
+    // replication stream in the same order
+    let mut functions = server.functions.lock().unwrap();
+    let reply = match sub.as_str() {
+        "LOAD" => load(args, &mut functions),
+        "DELETE" => {
+            let [name] = args else {
+                return wrong_arity("function|delete");
+            };
+            let name = arg_str(name).unwrap_or_default();
+            match functions.libraries.remove(name) {
+                Some(library) => {
+                    for function in library.functions.keys() {
+                        functions.index.remove(function);
+                    }
+                    debug!(library = name, "Function library deleted");
+                    ok()
+                }
+                None => error("ERR Library not found"),
+            }
+        }
+        "FLUSH" => {
+            match args {
+                [] => {}
+                [mode] if arg_str(mode).is_some_and(|m| m.eq_ignore_ascii_case("SYNC") || m.eq_ignore_ascii_case("ASYNC")) => {}
+                _ => return error("ERR FUNCTION FLUSH only supports SYNC|ASYNC option"),
+            }
+            info!(libraries = functions.libraries.len(), "Flushing function libraries");
+            *functions = Functions::default();
+            ok()
+        }
+        "LIST" => list(args, &functions),
+        "DUMP" if args.is_empty() => {
+            RespOrig::BulkString(rdb::dump_functions(&functions.codes().collect::<Vec<_>>()))
+        }
+        "RESTORE" => restore(args, &mut functions),
+        "DUMP" => wrong_arity("function|dump"),
+        _ => error(&format!(
+            "ERR unknown subcommand '{}'. Try FUNCTION HELP.",
+            sub.to_lowercase()
+        )),
+    };
+    if mutation && !matches!(reply, RespOrig::Error(_)) {
+        replication::propagate(server, RespOrig::Array(items.to_vec()));
+    }
+    reply
+}
+
+/// `FUNCTION LOAD [REPLACE] code`
+fn load(args: &[RespOrig], functions: &mut Functions) -> RespOrig {
+    let (replace, code) = match args {
+        [code] => (false, code),
+        [flag, code] if arg_str(flag).is_some_and(|f| f.eq_ignore_ascii_case("REPLACE")) => (true, code),
+        [_, _] => return error("ERR Unknown option given"),
+        _ => return wrong_arity("function|load"),
+    };
+    let Some(code) = code.as_bytes() else {
+        return wrong_arity("function|load");
+    };
+    let name = match metadata(code) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+
+    if functions.libraries.contains_key(&name) && !replace {
+        return error(&format!("ERR Library '{name}' already exists"));
+    }
+    let codes: Vec<Bytes> = functions
+        .libraries
+        .iter()
+        .filter(|(library, _)| **library != name)
+        .map(|(_, library)| library.code.clone())
+        .chain([code.clone()])
+        .collect();
+    match Functions::build(codes) {
+        Ok(rebuilt) => {
+            *functions = rebuilt;
+            debug!(library = %name, replace, "Function library loaded");
+            RespOrig::BulkString(Bytes::from(name))
+        }
+        Err(e) => e,
+    }
+}
+
+/// `FUNCTION RESTORE payload [FLUSH|APPEND|REPLACE]`
+fn restore(args: &[RespOrig], functions: &mut Functions) -> RespOrig {
+    let (payload, policy) = match args {
+        [payload] => (payload, "APPEND".to_string()),
+        [payload, policy] => (payload, arg_str(policy).unwrap_or_default().to_uppercase()),
+        _ => return wrong_arity("function|restore"),
+    };
+    let Some(codes) = payload.as_bytes().and_then(|p| rdb::restore_functions(p).ok()) else {
+        return error("ERR payload version or checksum are wrong");
+    };
+    let mut names = Vec::with_capacity(codes.len());
+    for code in &codes {
+        match metadata(code) {
+            Ok(name) => names.push(name),
+            Err(e) => return e,
+        }
+    }
+
+    let kept: Vec<Bytes> = match policy.as_str() {
+        "FLUSH" => Vec::new(),
+        "APPEND" => {
+            if let Some(name) = names.iter().find(|n| functions.libraries.contains_key(*n)) {
+                return error(&format!("ERR Library {name} already exists"));
+            }
+            functions.codes().collect()
+        }
+        "REPLACE" => functions
+            .libraries
+            .iter()
+            .filter(|(library, _)| !names.contains(library))
+            .map(|(_, library)| library.code.clone())
+            .collect(),
+        _ => return error("ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE."),
+    };
+    match Functions::build(kept.into_iter().chain(codes)) {
+        Ok(rebuilt) => {
+            info!(libraries = names.len(), %policy, "Function libraries restored");
+            *functions = rebuilt;
+            ok()
+        }
+        Err(e) => e,
+    }
+}
+
+/// `FUNCTION LIST [WITHCODE] [LIBRARYNAME pattern]`
+fn list(args: &[RespOrig], functions: &Functions) -> RespOrig {
+    let mut with_code = false;
+    let mut pattern = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg_str(arg).map(str::to_uppercase).as_deref() {
+            Some("WITHCODE") => with_code = true,
+            Some("LIBRARYNAME") => match rest.next().and_then(RespOrig::as_bytes) {
+                Some(p) => pattern = Some(p.clone()),
+                None => return error("ERR library name argument was not given"),
+            },
+            _ => return error(&format!("ERR Unknown argument {}", arg_str(arg).unwrap_or_default())),
+        }
+    }
+
+    let bulk = |s: &str| RespOrig::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+    let libraries = functions
+        .libraries
+        .iter()
+        .filter(|(name, _)| pattern.as_ref().is_none_or(|p| glob::matches(p, name.as_bytes(), false)))
+        .map(|(name, library)| {
+            let listed = library
+                .functions
+                .iter()
+                .map(|(function, meta)| {
+                    RespOrig::Array(vec![
+                        bulk("name"),
+                        bulk(function),
+                        bulk("description"),
+                        meta.description.as_deref().map_or(RespOrig::NullBulkString, bulk),
+                        bulk("flags"),
+                        RespOrig::Array(meta.flags.iter().map(|f| bulk(f)).collect()),
+                    ])
+                })
+                .collect();
+            let mut out = vec![
+                bulk("library_name"),
+                bulk(name),
+                bulk("engine"),
+                bulk("LUA"),
+                bulk("functions"),
+                RespOrig::Array(listed),
+            ];
+            if with_code {
+                out.push(bulk("library_code"));
+                out.push(RespOrig::BulkString(library.code.clone()));
+            }
+            RespOrig::Array(out)
+        })
+        .collect();
+    RespOrig::Array(libraries)
+}
+
+/// `FCALL` / `FCALL_RO function numkeys key [key ...] arg [arg ...]`. the callback gets the
+/// keys and args tables as its two parameters
+pub fn fcall(items: &[RespOrig], server: &Arc<Server>, conn: &Connection) -> RespOrig {
+    let read_only = items.first().and_then(arg_str).is_some_and(|n| n.eq_ignore_ascii_case("FCALL_RO"));
+    let name = items.get(1).and_then(arg_str).unwrap_or_default();
+    let (keys, argv) = match scripting::split_keys(items) {
+        Ok(split) => split,
+        Err(reply) => return reply,
+    };
+    let _exclusive = match scripting::lock_for(server, conn) {
+        Ok(guard) => guard,
+        Err(busy) => return busy,
+    };
+
+    let functions = server.functions.lock().unwrap();
+    let Some(function) = functions
+        .index
+        .get(name)
+        .and_then(|library| functions.libraries[library].functions.get(name))
+    else {
+        return error("ERR Function not found");
+    };
+    if read_only && !function.no_writes() {
+        return error("ERR Can not execute a script with write flag using *_ro command.");
+    }
+    let callback: mlua::Function = match functions.lua.registry_value(&function.callback) {
+        Ok(callback) => callback,
+        Err(e) => return scripting::script_error(&e, name),
+    };
+    let invocation = Invocation { label: name, keys, argv, read_only: function.no_writes() };
+    scripting::run(&functions.lua, server, conn, invocation, |_, keys, argv| {
+        callback.call((keys, argv))
+    })
+}
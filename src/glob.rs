@@ -0,0 +1,116 @@
+/// redis glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes
+pub fn matches(pattern: &[u8], text: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| if nocase { a.eq_ignore_ascii_case(&b) } else { a == b };
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() && (t < text.len() || pattern[p] == b'*') {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (t..=text.len()).any(|start| matches(&pattern[p + 1..], &text[start..], nocase));
+            }
+            b'?' => t += 1,
+            b'[' => {
+                p += 1;
+                let negate = pattern.get(p) == Some(&b'^');
+                if negate {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    match pattern.get(p) {
+                        // an unterminated class ends with the pattern
+                        None => {
+                            p -= 1;
+                            break;
+                        }
+                        Some(b'\\') if p + 1 < pattern.len() => {
+                            p += 1;
+                            matched |= pattern[p] == text[t];
+                        }
+                        Some(b']') => break,
+                        Some(&start) if p + 2 < pattern.len() && pattern[p + 1] == b'-' => {
+                            let end = pattern[p + 2];
+                            let (lo, hi) = if start <= end { (start, end) } else { (end, start) };
+                            let c = text[t];
+                            matched |= (lo..=hi).contains(&c)
+                                || (nocase && (lo..=hi).contains(&c.to_ascii_lowercase()))
+                                || (nocase && (lo..=hi).contains(&c.to_ascii_uppercase()));
+                            p += 2;
+                        }
+                        Some(&c) => matched |= eq(c, text[t]),
+                    }
+                    p += 1;
+                }
+                if matched == negate {
+                    return false;
+                }
+                t += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if !eq(pattern[p], text[t]) {
+                    return false;
+                }
+                t += 1;
+            }
+            c => {
+                if !eq(c, text[t]) {
+                    return false;
+                }
+                t += 1;
+            }
+        }
+        p += 1;
+        if t == text.len() {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+            break;
+        }
+    }
+    p == pattern.len() && t == text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    fn m(pattern: &str, text: &str) -> bool {
+        matches(pattern.as_bytes(), text.as_bytes(), false)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(m("*", ""));
+        assert!(m("news.*", "news.tech"));
+        assert!(m("h?llo", "hello"));
+        assert!(!m("h?llo", "hllo"));
+        assert!(m("a*b*c", "aXXbYYc"));
+        assert!(!m("a*b*c", "aXXbYY"));
+        assert!(m("**x", "abx"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(m("h[ae]llo", "hallo"));
+        assert!(!m("h[ae]llo", "hillo"));
+        assert!(m("h[^e]llo", "hallo"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("h[a-b]llo", "hbllo"));
+        assert!(m("h[b-a]llo", "hallo"));
+        assert!(m("[\\]]", "]"));
+    }
+
+    #[test]
+    fn escapes_and_case() {
+        assert!(m("a\\*b", "a*b"));
+        assert!(!m("a\\*b", "axb"));
+        assert!(!m("HELLO", "hello"));
+        assert!(matches(b"HEL[L-M]O", b"hello", true));
+    }
+}
@@ -1,5 +1,25 @@
+use crate::acl;
+use crate::auth;
+use crate::clients;
+use crate::cluster;
+use crate::cluster_bus;
+use crate::commands::{self, Command};
+use crate::connection::Connection;
+use crate::db::{now_ms, Entry};
+use crate::expire;
+use crate::functions;
+use crate::migrate;
+use crate::notify;
 use crate::parser::*;
+use crate::persistence;
+use crate::pubsub;
+use crate::replication;
+use crate::scripting;
+use crate::config::{self, parse_replicaof};
+use crate::server::{Server, Stats};
 use bytes::{BufMut, Bytes, BytesMut};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use tracing::*;
 
 pub trait ToResp {
@@ -7,8 +27,47 @@ pub trait ToResp {
 }
 
 impl RespOrig {
-    #[tracing::instrument(level = "debug")]
-    pub fn handle_command(self) -> Option<Bytes> {
+    /// argument value for string-like frames
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match self {
+            RespOrig::String(bytes) | RespOrig::BulkString(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// applies `rename-command` to a client's frame before anything looks at its name. a
+    /// renamed or disabled original is an unknown command, which fails an open transaction
+    pub fn renamed(self, server: &Server, conn: &mut Connection) -> Result<RespOrig, RespOrig> {
+        match self {
+            RespOrig::Array(mut items) => {
+                if !rename_command(server, &mut items) {
+                    debug!("Renamed or disabled command refused");
+                    conn.flag_transaction();
+                    return Err(unknown_command(&items));
+                }
+                Ok(RespOrig::Array(items))
+            },
+            frame => Ok(frame),
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip(server, conn))]
+    pub fn handle_command(self, server: &Arc<Server>, conn: &mut Connection) -> Option<Bytes> {
+        let asking = std::mem::replace(&mut conn.asking, cluster::is_asking(&self));
+        self.execute(server, true, asking, conn)
+    }
+
+    /// commands run by a script through `redis.call`, on the script's own connection
+    pub(crate) fn handle_scripted(self, server: &Arc<Server>, conn: &mut Connection) -> Option<Bytes> {
+        self.execute(server, true, false, conn)
+    }
+
+    /// commands streamed by our master skip the replica guards
+    pub fn handle_replicated(self, server: &Arc<Server>, conn: &mut Connection) -> Option<Bytes> {
+        self.execute(server, false, false, conn)
+    }
+
+    fn execute(self, server: &Arc<Server>, guarded: bool, asking: bool, conn: &mut Connection) -> Option<Bytes> {
         debug!("handling resp command");
         match self {
             RespOrig::String(bytes) => {
@@ -47,7 +106,52 @@ impl RespOrig {
                     _ => None,
                 };
                 
-                match cmd_name.as_deref() {
+                let spec = cmd_name.as_deref().and_then(commands::lookup);
+                let is_write = spec.is_some_and(|c| c.is_write());
+                let queueing = conn.in_multi()
+                    && !matches!(cmd_name.as_deref(), Some("MULTI" | "EXEC" | "DISCARD" | "WATCH" | "QUIT" | "RESET"));
+                if queueing {
+                    if let Some(rejected) = validate(spec, &items) {
+                        debug!(command = ?cmd_name, "Command rejected while queueing");
+                        conn.flag_transaction();
+                        return Some(rejected.to_resp());
+                    }
+                }
+                if guarded {
+                    if let Some(denied) = spec.and_then(|c| replication::guard(server, c)) {
+                        debug!(command = ?cmd_name, "Command refused by replication guard");
+                        conn.flag_transaction();
+                        return Some(denied.to_resp());
+                    }
+                    if let Some(redirect) = spec.and_then(|c| cluster::redirect(server, c, &items, asking)) {
+                        debug!(command = ?cmd_name, "Command redirected to another cluster node");
+                        conn.flag_transaction();
+                        return Some(redirect.to_resp());
+                    }
+                }
+                if queueing {
+                    trace!(command = ?cmd_name, "Command queued");
+                    conn.transaction.as_mut()?.queue.push(RespOrig::Array(items));
+                    return Some(Bytes::from("+QUEUED\r\n"));
+                }
+
+                // EXEC and scripts take the lock exclusively themselves, SCRIPT KILL must get
+                // past a running script
+                let exclusive = matches!(
+                    cmd_name.as_deref(),
+                    Some("EXEC" | "EVAL" | "EVALSHA" | "FCALL" | "FCALL_RO")
+                );
+                let _shared = if exclusive || conn.in_exec || scripting::is_kill(&items) {
+                    None
+                } else {
+                    match scripting::lock_shared(server) {
+                        Ok(guard) => Some(guard),
+                        Err(busy) => return Some(busy.to_resp()),
+                    }
+                };
+                Stats::incr(&server.stats.commands_processed, 1);
+                let mut propagation = Propagate::AsIs;
+                let reply = match cmd_name.as_deref() {
                     Some("PING") => Some(Bytes::from("+PONG\r\n")),
                     Some("ECHO") => {
                         if items.len() > 1 {
@@ -65,16 +169,626 @@ impl RespOrig {
                             None
                         }
                     },
+                    Some("SET") => {
+                        let (reply, effect) = set(&items[1..], server, conn.db);
+                        propagation = effect;
+                        Some(reply.to_resp())
+                    },
+                    Some("GET") => Some(get(&items[1..], server, conn.db).to_resp()),
+                    Some("DEL") => {
+                        let reply = del(&items[1..], server, conn.db);
+                        if matches!(reply, RespOrig::Int(0)) {
+                            propagation = Propagate::Nothing;
+                        }
+                        Some(reply.to_resp())
+                    },
+                    Some("INFO") => Some(info(&items[1..], server).to_resp()),
+                    Some("REPLCONF") => {
+                        // acks from a replica are never answered
+                        let is_ack = items.get(1).and_then(arg_str).is_some_and(|s| s.eq_ignore_ascii_case("ACK"));
+                        (!is_ack).then(|| Bytes::from("+OK\r\n"))
+                    },
+                    Some("REPLICAOF") | Some("SLAVEOF") => Some(replicaof(&items[1..], server).to_resp()),
+                    Some("CLUSTER") => Some(cluster::command(&items[1..], server).to_resp()),
+                    Some("PUBLISH") | Some("SPUBLISH") => Some(publish(&items, server).to_resp()),
+                    Some("PUBSUB") => Some(pubsub::command(&items[1..], server).to_resp()),
+                    Some("ASKING") => Some(match server.cluster {
+                        Some(_) => ok(),
+                        None => error("ERR This instance has cluster support disabled"),
+                    }.to_resp()),
+                    Some("DUMP") => Some(migrate::dump(&items[1..], server, conn.db).to_resp()),
+                    Some("RESTORE") | Some("RESTORE-ASKING") => {
+                        Some(migrate::restore(&items[1..], server, conn.db).to_resp())
+                    },
+                    Some("MULTI") => Some(multi(conn).to_resp()),
+                    Some("EXEC") => Some(exec(server, conn, guarded)),
+                    Some("DISCARD") => Some(discard(conn).to_resp()),
+                    Some("WATCH") => Some(watch(&items[1..], conn).to_resp()),
+                    Some("UNWATCH") => {
+                        conn.unwatch();
+                        Some(ok().to_resp())
+                    },
+                    Some("FLUSHALL") => Some(flushall(&items[1..], server).to_resp()),
+                    Some("FLUSHDB") => Some(flushdb(&items[1..], server, conn.db).to_resp()),
+                    Some("SELECT") => Some(select(&items[1..], server, conn).to_resp()),
+                    Some("SWAPDB") => Some(swapdb(&items[1..], server).to_resp()),
+                    Some("MOVE") => {
+                        let reply = move_key(&items[1..], server, conn.db);
+                        if matches!(reply, RespOrig::Int(0)) {
+                            propagation = Propagate::Nothing;
+                        }
+                        Some(reply.to_resp())
+                    },
+                    Some(name @ ("SAVE" | "BGSAVE" | "LASTSAVE" | "SHUTDOWN")) => {
+                        Some(persistence::command(name, &items[1..], server).to_resp())
+                    },
+                    Some("DBSIZE") => Some(RespOrig::Int(server.db(conn.db).len() as i64).to_resp()),
+                    Some("EVAL") | Some("EVALSHA") => Some(scripting::eval(&items, server, conn).to_resp()),
+                    Some("CONFIG") => Some(config::command(&items[1..], server).to_resp()),
+                    Some("ACL") => Some(acl::command(&items[1..], server, conn).to_resp()),
+                    Some("AUTH") => Some(auth::auth(&items[1..], server, conn).to_resp()),
+                    Some("HELLO") => Some(auth::hello(&items[1..], server, conn).to_resp()),
+                    Some("QUIT") => {
+                        conn.quit = true;
+                        Some(ok().to_resp())
+                    },
+                    Some("RESET") => {
+                        conn.reset();
+                        Some(RespOrig::String(Bytes::from_static(b"RESET")).to_resp())
+                    },
+                    Some("CLIENT") => Some(clients::command(&items[1..], server, conn).to_resp()),
+                    Some("SCRIPT") => Some(scripting::command(&items[1..], server).to_resp()),
+                    Some("FUNCTION") => Some(functions::command(&items, server, guarded).to_resp()),
+                    Some("FCALL") | Some("FCALL_RO") => Some(functions::fcall(&items, server, conn).to_resp()),
+                    // the connection loop runs these outside of transactions, see `main.rs`
+                    Some("WAIT") | Some("WAITAOF") => {
+                        let reply = match replication::wait_request(&RespOrig::Array(items.clone())) {
+                            Some(Ok(request)) => replication::wait_now(server, request),
+                            Some(Err(reply)) => reply,
+                            None => error("ERR syntax error"),
+                        };
+                        Some(reply.to_resp())
+                    },
+                    Some("MIGRATE") => Some(error("ERR MIGRATE is not allowed inside a transaction").to_resp()),
+                    Some("SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE") => {
+                        conn.subscriber.handle(&RespOrig::Array(items.clone()))
+                    },
                     _ => {
                         Some(Bytes::from("-ERR unknown command\r\n"))
                     }
+                };
+
+                if is_write && !reply.as_ref().is_some_and(|r| r.starts_with(b"-")) {
+                    match propagation {
+                        Propagate::AsIs => replication::propagate_to_db(server, conn.db, RespOrig::Array(items)),
+                        Propagate::Rewritten(command) => replication::propagate_to_db(server, conn.db, command),
+                        Propagate::Nothing => trace!(command = ?cmd_name, "Write changed nothing, not propagated"),
+                    }
                 }
+                expire::flush_expired(server);
+                reply
             },
             RespOrig::NullArray => None,
             RespOrig::NullBulkString => None,
         }
     }
 }
+/// what replicas get for a successful write
+enum Propagate {
+    /// the command as the client sent it
+    AsIs,
+    /// the write changed nothing
+    Nothing,
+    /// a form every replica applies the same way, such as an absolute expiry
+    Rewritten(RespOrig),
+}
+
+/// puts the real command name in place of a `rename-command` one. false when the name
+/// was renamed away or disabled
+pub(crate) fn rename_command(server: &Server, items: &mut [RespOrig]) -> bool {
+    if server.renamed_commands.is_empty() {
+        return true;
+    }
+    let Some(name) = items.first().and_then(arg_str).map(str::to_uppercase) else {
+        return true;
+    };
+    match server.renamed_commands.get(&name) {
+        Some(Some(command)) => {
+            items[0] = RespOrig::BulkString(Bytes::from_static(command.as_bytes()));
+            true
+        },
+        Some(None) => false,
+        None => true,
+    }
+}
+
+fn unknown_command(items: &[RespOrig]) -> RespOrig {
+    let name = items.first().and_then(arg_str).unwrap_or_default();
+    let args: String = items
+        .iter()
+        .skip(1)
+        .map(|a| format!("'{}' ", arg_str(a).unwrap_or_default()))
+        .collect();
+    error(&format!("ERR unknown command '{name}', with args beginning with: {args}"))
+}
+
+/// what MULTI refuses to queue: unknown commands, commands flagged `NO_MULTI` and wrong
+/// argument counts
+fn validate(spec: Option<&Command>, items: &[RespOrig]) -> Option<RespOrig> {
+    let Some(spec) = spec else {
+        return Some(unknown_command(items));
+    };
+    if spec.is_no_multi() {
+        return Some(error("ERR Command not allowed inside a transaction"));
+    }
+    let argc = items.len() as i32;
+    let valid = if spec.arity >= 0 { argc == spec.arity } else { argc >= -spec.arity };
+    (!valid).then(|| wrong_arity(&spec.name.to_lowercase()))
+}
+
+fn multi(conn: &mut Connection) -> RespOrig {
+    if conn.in_multi() {
+        return error("ERR MULTI calls can not be nested");
+    }
+    conn.transaction = Some(Default::default());
+    ok()
+}
+
+fn discard(conn: &mut Connection) -> RespOrig {
+    match conn.transaction.take() {
+        Some(_) => {
+            conn.unwatch();
+            ok()
+        }
+        None => error("ERR DISCARD without MULTI"),
+    }
+}
+
+/// runs the queued commands with every other client held off. replicas get the writes
+/// wrapped in MULTI / EXEC so they apply them atomically too
+fn exec(server: &Arc<Server>, conn: &mut Connection, guarded: bool) -> Bytes {
+    let Some(transaction) = conn.transaction.take() else {
+        return error("ERR EXEC without MULTI").to_resp();
+    };
+    if transaction.failed {
+        conn.unwatch();
+        return error("EXECABORT Transaction discarded because of previous errors.").to_resp();
+    }
+
+    let _exclusive = match scripting::lock_exclusive(server) {
+        Ok(guard) => guard,
+        Err(busy) => {
+            conn.unwatch();
+            return busy.to_resp();
+        }
+    };
+    let dirty = conn.watch_dirty();
+    conn.unwatch();
+    if dirty {
+        debug!("Watched key changed, transaction aborted");
+        return RespOrig::NullArray.to_resp();
+    }
+    let writes = transaction.queue.iter().any(|command| {
+        let RespOrig::Array(items) = command else {
+            return false;
+        };
+        items
+            .first()
+            .and_then(arg_str)
+            .and_then(|name| commands::lookup(&name.to_uppercase()))
+            .is_some_and(Command::may_replicate)
+    });
+    let marker = |name: &'static [u8]| RespOrig::Array(vec![RespOrig::BulkString(Bytes::from_static(name))]);
+    if writes {
+        replication::propagate(server, marker(b"MULTI"));
+    }
+
+    debug!(commands = transaction.queue.len(), "Executing transaction");
+    let mut out = BytesMut::new();
+    out.extend_from_slice(format!("*{}\r\n", transaction.queue.len()).as_bytes());
+    conn.in_exec = true;
+    for command in transaction.queue {
+        let reply = command.execute(server, guarded, false, conn);
+        out.extend_from_slice(&reply.unwrap_or_else(|| RespOrig::NullBulkString.to_resp()));
+    }
+    conn.in_exec = false;
+
+    if writes {
+        replication::propagate(server, marker(b"EXEC"));
+    }
+    out.freeze()
+}
+
+fn watch(args: &[RespOrig], conn: &mut Connection) -> RespOrig {
+    if conn.in_multi() {
+        return error("ERR WATCH inside MULTI is not allowed");
+    }
+    conn.watch(args.iter().filter_map(RespOrig::as_bytes).cloned());
+    ok()
+}
+
+/// the optional SYNC / ASYNC of FLUSHALL and FLUSHDB, whether it is ASYNC
+fn flush_mode(args: &[RespOrig]) -> Result<bool, RespOrig> {
+    match args {
+        [] => Ok(false),
+        [mode] => match arg_str(mode).map(str::to_uppercase).as_deref() {
+            Some("SYNC") => Ok(false),
+            Some("ASYNC") => Ok(true),
+            _ => Err(error("ERR syntax error")),
+        },
+        _ => Err(error("ERR syntax error")),
+    }
+}
+
+/// with ASYNC the flushed keys are freed on a blocking thread instead of the caller's
+fn flush(server: &Server, indexes: impl Iterator<Item = usize>, lazy: bool) {
+    let mut dbs: Vec<_> = indexes.map(|index| server.db(index)).collect();
+    let flushed: Vec<_> = dbs.iter_mut().map(|db| db.clear()).collect();
+    drop(dbs);
+    info!(keys = flushed.iter().map(|f| f.len()).sum::<usize>(), lazy, "Flushed keys");
+    if lazy {
+        tokio::task::spawn_blocking(move || drop(flushed));
+    }
+}
+
+/// FLUSHALL [ASYNC | SYNC]
+fn flushall(args: &[RespOrig], server: &Server) -> RespOrig {
+    match flush_mode(args) {
+        Ok(lazy) => {
+            flush(server, 0..server.dbs.len(), lazy);
+            ok()
+        }
+        Err(reply) => reply,
+    }
+}
+
+/// FLUSHDB [ASYNC | SYNC]
+fn flushdb(args: &[RespOrig], server: &Server, index: usize) -> RespOrig {
+    match flush_mode(args) {
+        Ok(lazy) => {
+            flush(server, std::iter::once(index), lazy);
+            ok()
+        }
+        Err(reply) => reply,
+    }
+}
+
+/// a database index argument, `None` when it is out of range
+fn db_index(server: &Server, arg: &RespOrig) -> Option<Result<usize, RespOrig>> {
+    let Some(index) = arg_int(arg) else {
+        return Some(Err(error("ERR value is not an integer or out of range")));
+    };
+    usize::try_from(index).ok().filter(|&i| i < server.dbs.len()).map(Ok)
+}
+
+/// SELECT index. a cluster only has database 0
+fn select(args: &[RespOrig], server: &Server, conn: &mut Connection) -> RespOrig {
+    let [index] = args else {
+        return wrong_arity("select");
+    };
+    let index = match db_index(server, index) {
+        Some(Ok(index)) => index,
+        Some(Err(reply)) => return reply,
+        None => return error("ERR DB index is out of range"),
+    };
+    if server.cluster.is_some() && index != 0 {
+        return error("ERR SELECT is not allowed in cluster mode");
+    }
+    trace!(db = index, "Selected database");
+    conn.db = index;
+    ok()
+}
+
+/// SWAPDB index1 index2. clients on either database see the other's data from now on
+fn swapdb(args: &[RespOrig], server: &Server) -> RespOrig {
+    let [first, second] = args else {
+        return wrong_arity("swapdb");
+    };
+    if server.cluster.is_some() {
+        return error("ERR SWAPDB is not allowed in cluster mode");
+    }
+    let first = match db_index(server, first) {
+        Some(Ok(index)) => index,
+        Some(Err(_)) => return error("ERR invalid first DB index"),
+        None => return error("ERR DB index is out of range"),
+    };
+    let second = match db_index(server, second) {
+        Some(Ok(index)) => index,
+        Some(Err(_)) => return error("ERR invalid second DB index"),
+        None => return error("ERR DB index is out of range"),
+    };
+    if first != second {
+        let (low, high) = (first.min(second), first.max(second));
+        let mut low_db = server.db(low);
+        server.db(high).swap(&mut low_db);
+        info!(first, second, "Swapped databases");
+    }
+    ok()
+}
+
+/// MOVE key db. nothing happens when the key is missing here or already exists there
+fn move_key(args: &[RespOrig], server: &Server, index: usize) -> RespOrig {
+    let [key, target] = args else {
+        return wrong_arity("move");
+    };
+    let Some(key) = key.as_bytes() else {
+        return wrong_arity("move");
+    };
+    if server.cluster.is_some() {
+        return error("ERR MOVE is not allowed in cluster mode");
+    }
+    let target = match db_index(server, target) {
+        Some(Ok(target)) => target,
+        Some(Err(reply)) => return reply,
+        None => return error("ERR DB index is out of range"),
+    };
+    if target == index {
+        return error("ERR source and destination objects are the same");
+    }
+
+    let (mut src, mut dst) = if index < target {
+        let src = server.db(index);
+        (src, server.db(target))
+    } else {
+        let dst = server.db(target);
+        (server.db(index), dst)
+    };
+    if dst.contains(key) {
+        return RespOrig::Int(0);
+    }
+    let Some(entry) = src.remove(key) else {
+        return RespOrig::Int(0);
+    };
+    dst.set(key.clone(), entry);
+    drop((src, dst));
+    debug!(key = ?key, from = index, to = target, "Key moved");
+    notify::keyspace_event(server, index, notify::GENERIC, "move_from", key);
+    notify::keyspace_event(server, target, notify::GENERIC, "move_to", key);
+    RespOrig::Int(1)
+}
+
+pub(crate) fn error(msg: &str) -> RespOrig {
+    RespOrig::Error(Bytes::copy_from_slice(msg.as_bytes()))
+}
+
+pub(crate) fn wrong_arity(cmd: &str) -> RespOrig {
+    error(&format!("ERR wrong number of arguments for '{cmd}' command"))
+}
+
+pub(crate) fn ok() -> RespOrig {
+    RespOrig::String(Bytes::from_static(b"OK"))
+}
+
+pub(crate) fn arg_str(arg: &RespOrig) -> Option<&str> {
+    arg.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+}
+
+pub(crate) fn arg_int(arg: &RespOrig) -> Option<i64> {
+    arg_str(arg).and_then(|s| s.parse().ok())
+}
+
+/// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | EXAT unix-seconds |
+/// PXAT unix-milliseconds | KEEPTTL]. replicas get `SET key value [PXAT ms | KEEPTTL]`, and
+/// nothing when NX or XX prevented the write
+fn set(args: &[RespOrig], server: &Server, index: usize) -> (RespOrig, Propagate) {
+    let (Some(key), Some(value)) = (args.first().and_then(RespOrig::as_bytes), args.get(1).and_then(RespOrig::as_bytes)) else {
+        return (wrong_arity("set"), Propagate::Nothing);
+    };
+    let failed = |reply| (reply, Propagate::Nothing);
+
+    let mut expires_at = None;
+    let mut keep_ttl = false;
+    let mut nx = false;
+    let mut xx = false;
+    let mut get = false;
+    let mut i = 2;
+    while i < args.len() {
+        let opt = arg_str(&args[i]).map(str::to_uppercase);
+        match opt.as_deref() {
+            Some("NX") => nx = true,
+            Some("XX") => xx = true,
+            Some("GET") => get = true,
+            Some("KEEPTTL") => keep_ttl = true,
+            Some(unit @ ("EX" | "PX" | "EXAT" | "PXAT")) => {
+                i += 1;
+                let Some(amount) = args.get(i).and_then(arg_int) else {
+                    return failed(error("ERR value is not an integer or out of range"));
+                };
+                let now = now_ms() as i64;
+                let at = match unit {
+                    _ if amount <= 0 => None,
+                    "EX" => amount.checked_mul(1000).and_then(|ms| now.checked_add(ms)),
+                    "PX" => now.checked_add(amount),
+                    "EXAT" => amount.checked_mul(1000),
+                    _ => Some(amount),
+                };
+                let Some(at) = at else {
+                    return failed(error("ERR invalid expire time in 'set' command"));
+                };
+                expires_at = Some(at as u64);
+            }
+            _ => return failed(error("ERR syntax error")),
+        }
+        i += 1;
+    }
+    if (nx && xx) || (keep_ttl && expires_at.is_some()) {
+        return failed(error("ERR syntax error"));
+    }
+
+    let mut db = server.db(index);
+    let old = db.get(key).cloned();
+    if (nx && old.is_some()) || (xx && old.is_none()) {
+        let reply = if get {
+            old.map_or(RespOrig::NullBulkString, |e| RespOrig::BulkString(e.value))
+        } else {
+            RespOrig::NullBulkString
+        };
+        return failed(reply);
+    }
+    if keep_ttl {
+        expires_at = old.as_ref().and_then(|e| e.expires_at);
+    }
+    db.set(key.clone(), Entry { value: value.clone(), expires_at });
+    drop(db);
+    debug!(key = ?key, ?expires_at, "Key set");
+    if old.is_none() {
+        notify::keyspace_event(server, index, notify::NEW, "new", key);
+    }
+    notify::keyspace_event(server, index, notify::STRING, "set", key);
+    if expires_at.is_some() && !keep_ttl {
+        notify::keyspace_event(server, index, notify::GENERIC, "expire", key);
+    }
+
+    let mut replicated = vec![bulk(b"SET"), RespOrig::BulkString(key.clone()), RespOrig::BulkString(value.clone())];
+    if keep_ttl {
+        replicated.push(bulk(b"KEEPTTL"));
+    } else if let Some(at) = expires_at {
+        replicated.extend([bulk(b"PXAT"), RespOrig::BulkString(Bytes::from(at.to_string()))]);
+    }
+    let reply = if get {
+        old.map_or(RespOrig::NullBulkString, |e| RespOrig::BulkString(e.value))
+    } else {
+        ok()
+    };
+    (reply, Propagate::Rewritten(RespOrig::Array(replicated)))
+}
+
+fn bulk(s: &'static [u8]) -> RespOrig {
+    RespOrig::BulkString(Bytes::from_static(s))
+}
+
+fn get(args: &[RespOrig], server: &Server, index: usize) -> RespOrig {
+    let [key] = args else {
+        return wrong_arity("get");
+    };
+    let Some(key) = key.as_bytes() else {
+        return wrong_arity("get");
+    };
+    let value = server.db(index).get(key).map(|entry| entry.value.clone());
+    match value {
+        Some(value) => {
+            Stats::incr(&server.stats.keyspace_hits, 1);
+            RespOrig::BulkString(value)
+        }
+        None => {
+            Stats::incr(&server.stats.keyspace_misses, 1);
+            notify::keyspace_event(server, index, notify::KEY_MISS, "keymiss", key);
+            RespOrig::NullBulkString
+        }
+    }
+}
+
+fn del(args: &[RespOrig], server: &Server, index: usize) -> RespOrig {
+    if args.is_empty() {
+        return wrong_arity("del");
+    }
+    let removed: Vec<&Bytes> = {
+        let mut db = server.db(index);
+        args.iter()
+            .filter_map(RespOrig::as_bytes)
+            .filter(|key| db.remove(key).is_some())
+            .collect()
+    };
+    for key in &removed {
+        notify::keyspace_event(server, index, notify::GENERIC, "del", key);
+    }
+    RespOrig::Int(removed.len() as i64)
+}
+
+/// INFO [section]. only the sections we actually track are reported
+fn info(args: &[RespOrig], server: &Server) -> RespOrig {
+    let section = args.first().and_then(arg_str).map(str::to_lowercase);
+    let mut out = String::new();
+    match section.as_deref() {
+        None | Some("all") | Some("everything") | Some("default") => {
+            out.push_str(&stats_info(server));
+            out.push_str("\r\n");
+            out.push_str(&keyspace_info(server));
+            out.push_str("\r\n");
+            out.push_str(&persistence::info(server));
+            out.push_str("\r\n");
+            out.push_str(&replication::info(server));
+            out.push_str(&format!("\r\n# Cluster\r\ncluster_enabled:{}\r\n", server.cluster.is_some() as u8));
+        }
+        Some("stats") => out.push_str(&stats_info(server)),
+        Some("replication") => out.push_str(&replication::info(server)),
+        Some("persistence") => out.push_str(&persistence::info(server)),
+        Some("keyspace") => {
+            out.push_str(&keyspace_info(server));
+        }
+        Some("cluster") => {
+            out.push_str(&format!("# Cluster\r\ncluster_enabled:{}\r\n", server.cluster.is_some() as u8));
+        }
+        Some(_) => {}
+    }
+    RespOrig::BulkString(Bytes::from(out))
+}
+
+fn stats_info(server: &Server) -> String {
+    let mut out = String::from("# Stats\r\n");
+    for (name, counter) in server.stats.counters() {
+        out.push_str(&format!("{name}:{}\r\n", counter.load(Ordering::Relaxed)));
+    }
+    out
+}
+
+/// `db<n>:keys=<count>` for every database holding keys
+fn keyspace_info(server: &Server) -> String {
+    let mut out = String::from("# Keyspace\r\n");
+    for index in 0..server.dbs.len() {
+        let keys = server.db(index).len();
+        if keys > 0 {
+            out.push_str(&format!("db{index}:keys={keys}\r\n"));
+        }
+    }
+    out
+}
+
+/// PUBLISH / SPUBLISH channel message. other nodes get the message too, so their
+/// subscribers see it: replicas through the replication stream, or in a cluster every node
+/// (PUBLISH) or the nodes of this shard (SPUBLISH) over the bus
+fn publish(items: &[RespOrig], server: &Server) -> RespOrig {
+    let [name, channel, message] = items else {
+        return wrong_arity("publish");
+    };
+    let (Some(channel), Some(message)) = (channel.as_bytes(), message.as_bytes()) else {
+        return wrong_arity("publish");
+    };
+    let shard = arg_str(name).is_some_and(|n| n.eq_ignore_ascii_case("SPUBLISH"));
+    let receivers = if shard {
+        pubsub::publish_shard(server, channel, message)
+    } else {
+        pubsub::publish(server, channel, message)
+    };
+    if server.cluster.is_some() {
+        cluster_bus::publish(server, channel, message, shard);
+    } else {
+        replication::propagate(server, RespOrig::Array(items.to_vec()));
+    }
+    RespOrig::Int(receivers as i64)
+}
+
+/// REPLICAOF host port | REPLICAOF NO ONE
+fn replicaof(args: &[RespOrig], server: &Arc<Server>) -> RespOrig {
+    let [host, port] = args else {
+        return wrong_arity("replicaof");
+    };
+    let (Some(host), Some(port)) = (arg_str(host), arg_str(port)) else {
+        return error("ERR syntax error");
+    };
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        replication::promote(server);
+        return ok();
+    }
+    match parse_replicaof(host, port) {
+        Ok((host, port)) => {
+            replication::replicaof(server, host, port);
+            ok()
+        }
+        Err(_) => error("ERR Invalid master port"),
+    }
+}
+
 impl ToResp for RespOrig {
     fn to_resp(self) -> Bytes {
         match self {
@@ -1,2 +1,23 @@
+pub mod acl;
+pub mod auth;
+pub mod clients;
+pub mod cluster;
+pub mod cluster_bus;
+pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod db;
+pub mod expire;
+pub mod functions;
+pub mod glob;
 pub mod handler;
+pub mod migrate;
+pub mod notify;
 pub mod parser;
+pub mod persistence;
+pub mod pubsub;
+pub mod rdb;
+pub mod replication;
+pub mod scripting;
+pub mod server;
+pub mod tls;
@@ -1,17 +1,36 @@
 #![allow(unused_imports)]
 use bytes::BytesMut;
+use codecrafters_redis::acl;
+use codecrafters_redis::auth;
+use codecrafters_redis::clients::{self, Registration, Type};
 use codecrafters_redis::parser::{RespParser, RespOrig};
 use codecrafters_redis::handler::ToResp;
-use tracing_forest::init;
+use codecrafters_redis::cluster_bus;
+use codecrafters_redis::expire;
+use codecrafters_redis::migrate;
+use codecrafters_redis::persistence;
+use codecrafters_redis::connection::Connection;
+use codecrafters_redis::replication;
+use codecrafters_redis::config::{self, Config};
+use codecrafters_redis::server::{Server, Stats};
+use codecrafters_redis::tls::{Stream, Tls};
 use std::{
-    io::{Error, Read, Write},
+    future::{ready, Future},
+    io::{Error, ErrorKind, Read, Write},
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::Arc,
     thread,
+    time::Duration,
 };
 use tokio::io::BufReader;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UnixListener},
 };
+use socket2::{SockRef, TcpKeepalive};
+use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::Decoder;
 use tracing::{debug, error, info, span, trace, warn, Level, Instrument};
 use tracing_subscriber::{EnvFilter, prelude::*};
@@ -19,43 +38,159 @@ use tracing_subscriber::{EnvFilter, prelude::*};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    init();
+    // logging is set up by the configuration, so errors in it go straight to stderr
+    let mut config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("*** FATAL CONFIG ERROR *** {e}");
+            std::process::exit(1);
+        }
+    };
+    config::init_logging(&config);
     
     info!("Starting Redis server...");
-    
-    let addr = "127.0.0.1:6379";
-    info!(address = %addr, "Binding TCP listener");
-    let listener = match TcpListener::bind(addr).await {
-        Ok(l) => {
-            info!(address = %addr, "Successfully bound to address");
-            l
-        },
-        Err(e) => {
-            error!(error = ?e, address = %addr, "Failed to bind to address");
-            return Err(e);
+    debug!(?config, "Parsed configuration");
+    config.enter_dir().inspect_err(|e| {
+        error!(error = ?e, dir = ?config.dir, "Can't chdir to the configured directory");
+    })?;
+
+    let tls = Tls::load(&config).inspect_err(|e| {
+        error!(error = %e, "Invalid TLS configuration");
+    })?;
+
+    // bound before anything else starts, so a bad address fails the start right away.
+    // the tls port listens on the same addresses
+    let ports: Vec<(u16, bool)> = [(config.port, false), (config.tls_port, true)]
+        .into_iter()
+        .filter(|&(port, _)| port != 0)
+        .collect();
+    let mut listeners = Vec::new();
+    for (addr, &(port, secure)) in config.bind.iter().flat_map(|addr| ports.iter().map(move |p| (addr, p))) {
+        let (optional, host) = match addr.strip_prefix('-') {
+            Some(host) => (true, host),
+            None => (false, addr.as_str()),
+        };
+        let host = match host {
+            "*" => "0.0.0.0",
+            "::*" => "::",
+            host => host,
+        };
+        info!(address = %host, port, secure, "Binding TCP listener");
+        match TcpListener::bind((host, port)).await {
+            Ok(l) => {
+                info!(address = %host, port, "Successfully bound to address");
+                listeners.push((l, secure));
+            },
+            Err(e) if optional => {
+                warn!(error = ?e, address = %host, port, "Skipping optional bind address");
+            },
+            Err(e) => {
+                error!(error = ?e, address = %host, port, "Failed to bind to address");
+                return Err(e);
+            }
         }
+    }
+    let unix_listener = match &config.unixsocket {
+        Some(path) => Some(bind_unix(path, config.unixsocketperm)?),
+        None => None,
     };
+    if listeners.is_empty() && unix_listener.is_none() {
+        error!("No bind address could be used");
+        return Err(Error::new(ErrorKind::AddrNotAvailable, "no bind address could be used"));
+    }
+
+    let server = Server::new(config, tls).inspect_err(|e| {
+        error!(error = ?e, "Failed to initialize server");
+    })?;
+    let exposed = {
+        let config = server.config();
+        config.protected_mode
+            && config.bind.iter().any(|a| !matches!(a.trim_start_matches('-'), "127.0.0.1" | "::1" | "localhost"))
+    };
+    if exposed && server.acl.lock().unwrap().default_authenticates() {
+        warn!("Protected mode is on and the default user has no password, only loopback clients will be served");
+    }
+    persistence::load(&server).inspect_err(|e| {
+        error!(error = ?e, "Failed loading the dataset from disk");
+    })?;
+    tokio::spawn(persistence::shutdown_on_signal(server.clone()).instrument(span!(Level::DEBUG, "signals")));
+    let replicaof = server.config().replicaof.clone();
+    if let Some((host, port)) = replicaof {
+        replication::replicaof(&server, host, port);
+    }
+    tokio::spawn(expire::active_expire_cycle(server.clone()).instrument(span!(Level::DEBUG, "active_expire")));
+    tokio::spawn(clients::close_idle_cycle(server.clone()).instrument(span!(Level::DEBUG, "close_idle")));
     
+    if server.cluster.is_some() {
+        let bus_addr = {
+            let config = server.config();
+            let bus_port = config.bus_port().ok_or_else(|| Error::other("port leaves no room for the cluster bus port"))?;
+            (config.announce_ip().to_string(), bus_port)
+        };
+        info!(address = ?bus_addr, "Binding cluster bus listener");
+        let bus_listener = TcpListener::bind(&bus_addr).await.inspect_err(|e| {
+            error!(error = ?e, address = ?bus_addr, "Failed to bind cluster bus");
+        })?;
+        cluster_bus::start(server.clone(), bus_listener);
+    }
+
     info!("Waiting for client connections");
-    
+    let mut accepting: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, secure)| {
+            let acceptor = server.tls.as_ref().filter(|_| secure).map(|tls| tls.acceptor().clone());
+            tokio::spawn(accept_loop(listener, acceptor, server.clone()))
+        })
+        .collect();
+    if let Some(listener) = unix_listener {
+        accepting.push(tokio::spawn(accept_unix_loop(listener, server.clone())));
+    }
+    for task in accepting {
+        task.await.map_err(Error::other)?;
+    }
+    Ok(())
+}
+
+/// a stale socket file from an earlier run is replaced
+fn bind_unix(path: &Path, perm: u32) -> Result<UnixListener, Error> {
+    info!(path = ?path, "Binding unix socket listener");
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            error!(error = ?e, path = ?path, "Failed to remove old unix socket");
+            return Err(e);
+        },
+        _ => {}
+    }
+    let listener = UnixListener::bind(path).inspect_err(|e| {
+        error!(error = ?e, path = ?path, "Failed to bind unix socket");
+    })?;
+    if perm != 0 {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(perm)).inspect_err(|e| {
+            error!(error = ?e, path = ?path, "Failed to set unix socket permissions");
+        })?;
+    }
+    Ok(listener)
+}
+
+/// with an `acceptor` every connection starts with a TLS handshake
+async fn accept_loop(listener: TcpListener, acceptor: Option<TlsAcceptor>, server: Arc<Server>) {
     loop {
         let accept_span = span!(Level::INFO, "accept_connection");
         let connection = listener.accept().instrument(accept_span).await;
         
         match connection {
             Ok((stream, addr)) => {
-                info!(client = %addr, "New client connected");
-                
-                tokio::spawn(
-                    async move {
-                        debug!(client = %addr, "Starting client handler task");
-                        if let Err(e) = handle_client(stream).await {
-                            error!(client = %addr, error = ?e, "Error handling client");
-                        }
-                        info!(client = %addr, "Client disconnected");
+                let keepalive = server.config().tcp_keepalive;
+                if keepalive > 0 {
+                    if let Err(e) = set_keepalive(&stream, keepalive) {
+                        warn!(client = %addr, error = ?e, "Failed to enable TCP keepalive");
                     }
-                    .instrument(span!(Level::INFO, "client", %addr))
-                );
+                }
+                let laddr = stream.local_addr().map(|a| a.to_string()).unwrap_or_default();
+                match &acceptor {
+                    Some(acceptor) => spawn_client(acceptor.accept(stream), Some(addr), addr.to_string(), laddr, &server),
+                    None => spawn_client(ready(Ok(stream)), Some(addr), addr.to_string(), laddr, &server),
+                }
             },
             Err(e) => {
                 error!(error = ?e, "Failed to accept connection");
@@ -64,85 +199,250 @@ async fn main() -> Result<(), Error> {
     }
 }
 
-async fn handle_client(mut stream: TcpStream) -> Result<(), Error> {
-    info!("Client handler started");
+/// probes start after `secs` of silence and repeat every third of that, the connection
+/// is dropped after three unanswered ones
+fn set_keepalive(stream: &TcpStream, secs: u64) -> Result<(), Error> {
+    let idle = Duration::from_secs(secs);
+    let keepalive = TcpKeepalive::new()
+        .with_time(idle)
+        .with_interval((idle / 3).max(Duration::from_secs(1)))
+        .with_retries(3);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+async fn accept_unix_loop(listener: UnixListener, server: Arc<Server>) {
+    let path = listener.local_addr().ok().and_then(|a| a.as_pathname().map(Path::to_path_buf));
+    // the way redis shows unix socket clients
+    let label = format!("{}:0", path.unwrap_or_default().display());
+    loop {
+        let accept_span = span!(Level::INFO, "accept_connection");
+        match listener.accept().instrument(accept_span).await {
+            Ok((stream, _)) => spawn_client(ready(Ok(stream)), None, label.clone(), label.clone(), &server),
+            Err(e) => {
+                error!(error = ?e, "Failed to accept unix socket connection");
+            }
+        }
+    }
+}
+
+/// serves one client on its own task, once `handshake` yields its stream. `peer` is
+/// `None` on a unix socket. CLIENT KILL drops the task wherever it is
+fn spawn_client<S, F>(handshake: F, peer: Option<SocketAddr>, addr: String, laddr: String, server: &Arc<Server>)
+where
+    S: Stream + 'static,
+    F: Future<Output = Result<S, Error>> + Send + 'static,
+{
+    info!(client = %addr, "New client connected");
+    Stats::incr(&server.stats.connections_received, 1);
     
-    let addr = match stream.peer_addr() {
-        Ok(addr) => addr.to_string(),
-        Err(_) => "unknown".to_string(),
+    let client = match clients::register(server, Type::Normal, addr.clone(), laddr, peer.is_none()) {
+        Ok(client) => client,
+        Err(refused) => {
+            tokio::spawn(async move {
+                if let Ok(mut stream) = handshake.await {
+                    let _ = stream.write_all(&refused.to_resp()).await;
+                }
+            });
+            return;
+        }
     };
+    let server = server.clone();
+    let span = span!(Level::INFO, "client", %addr, id = client.id);
+    tokio::spawn(
+        async move {
+            let serve = async {
+                let stream = match handshake.await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!(client = %addr, error = %e, "TLS handshake failed");
+                        return;
+                    }
+                };
+                debug!(client = %addr, "Starting client handler task");
+                if let Err(e) = handle_client(stream, peer, &client, server).await {
+                    error!(client = %addr, error = ?e, "Error handling client");
+                }
+            };
+            tokio::select! {
+                _ = serve => {},
+                _ = client.killed() => info!(client = %addr, "Client killed"),
+            }
+            info!(client = %addr, "Client disconnected");
+        }
+        .instrument(span)
+    );
+}
+
+async fn handle_client<S: Stream>(
+    mut stream: S,
+    peer: Option<SocketAddr>,
+    client: &Registration,
+    server: Arc<Server>,
+) -> Result<(), Error> {
+    info!("Client handler started");
+    if let Some(denied) = auth::protected_mode(&server, peer) {
+        stream.write_all(&denied.to_resp()).await?;
+        return Ok(());
+    }
+    
+    // kept across reads: a frame may arrive in pieces, or several frames in one read
+    let mut buf = BytesMut::with_capacity(512);
+    let mut resp: RespParser = Default::default();
+    // announced by a replica before it sends PSYNC
+    let mut replica_port = None;
+    // published messages arrive on `pushes` while the client is subscribed
+    let (mut conn, mut pushes) = Connection::new(server.clone());
+    conn.attach(client);
     
     loop {
-        let mut buf = BytesMut::with_capacity(512);
-        
         let read_span = span!(Level::DEBUG, "read_from_socket");
-        let bytes_read = stream
-            .read_buf(&mut buf)
-            .instrument(read_span)
-            .await;
+        let bytes_read = tokio::select! {
+            read = stream.read_buf(&mut buf).instrument(read_span) => read,
+            Some(push) = pushes.recv() => {
+                let push = conn.subscriber.deliver(push);
+                trace!(size = push.len(), "Delivering pubsub message");
+                stream.write_all(&push).await?;
+                continue;
+            }
+        };
         
         match bytes_read {
             Ok(0) => {
                 debug!("Client closed connection (read 0 bytes)");
                 break;
             },
+            // many TLS clients hang up without a close_notify
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                debug!(error = %e, "Client closed connection abruptly");
+                break;
+            },
             Ok(n) => {
                 debug!(bytes = n, "Read data from client");
                 trace!(data = ?buf, "Raw input data");
                 
                 let parse_span = span!(Level::DEBUG, "parse_command");
                 let result = async {
-                    let mut resp: RespParser = Default::default();
-                    match resp.decode(&mut buf) {
-                        Ok(Some(resp_value)) => {
-                            debug!(command = ?resp_value, "Successfully parsed command");
-                            
-                            let handle_span = span!(Level::DEBUG, "handle_command");
-                            let res = resp_value
-                                .handle_command()
-                                .instrument(handle_span);
-                            let response = res.inner();
-                            
-                            match response {
-                                Some(bytes) => {
-                                    debug!(response_size = bytes.len(), "Command produced response");
-                                    trace!(response = ?bytes, "Response data");
-                                    let write_span = span!(Level::DEBUG, "write_response");
-                                    match stream.write_all(bytes).instrument(write_span).await {
-                                        Ok(_) => {
-                                            debug!("Response sent successfully");
-                                            Ok(())
-                                        },
-                                        Err(e) => {
+                    loop {
+                        match resp.decode(&mut buf) {
+                            Ok(Some(resp_value)) => {
+                                debug!(command = ?resp_value, "Successfully parsed command");
+                                
+                                let resp_value = match resp_value.renamed(&server, &mut conn) {
+                                    Ok(frame) => frame,
+                                    Err(unknown) => {
+                                        stream.write_all(&unknown.to_resp()).await?;
+                                        continue;
+                                    }
+                                };
+                                clients::record(&server, &conn, Some(&resp_value), &buf);
+                                let denied = auth::check(&resp_value, &conn)
+                                    .or_else(|| acl::check(&resp_value, &server, &mut conn));
+                                if let Some(denied) = denied {
+                                    stream.write_all(&denied.to_resp()).await?;
+                                    continue;
+                                }
+                                clients::wait_unpaused(&server, &resp_value, &conn).await;
+                                // inside MULTI everything is queued, see `handler.rs`
+                                let queueing = conn.in_multi();
+                                if let Some(reply) = (!queueing).then(|| conn.subscriber.handle(&resp_value)).flatten() {
+                                    conn.asking = false;
+                                    stream.write_all(&reply).await?;
+                                    continue;
+                                }
+                                // queued, PSYNC is refused like any command a transaction cannot hold
+                                if !queueing && replication::is_psync(&resp_value) {
+                                    return Ok(Some(resp_value));
+                                }
+                                if let Some(port) = replication::listening_port(&resp_value) {
+                                    replica_port = Some(port);
+                                }
+                                if let Some(request) = replication::wait_request(&resp_value).filter(|_| !queueing) {
+                                    conn.asking = false;
+                                    let _blocked = clients::blocked(&server, conn.id);
+                                    let reply = match request {
+                                        Ok(request) => replication::wait(&server, request)
+                                            .instrument(span!(Level::DEBUG, "blocked_wait"))
+                                            .await,
+                                        Err(reply) => reply,
+                                    };
+                                    stream.write_all(&reply.to_resp()).await?;
+                                    continue;
+                                }
+                                if let Some(request) = migrate::request(&resp_value).filter(|_| !queueing) {
+                                    conn.asking = false;
+                                    let _blocked = clients::blocked(&server, conn.id);
+                                    let reply = match request {
+                                        Ok(request) => migrate::migrate(&server, conn.db, request)
+                                            .instrument(span!(Level::DEBUG, "migrate"))
+                                            .await,
+                                        Err(reply) => reply,
+                                    };
+                                    stream.write_all(&reply.to_resp()).await?;
+                                    continue;
+                                }
+                                
+                                let handle_span = span!(Level::DEBUG, "handle_command");
+                                let response = handle_span.in_scope(|| resp_value.handle_command(&server, &mut conn));
+                                
+                                match response {
+                                    Some(bytes) => {
+                                        debug!(response_size = bytes.len(), "Command produced response");
+                                        trace!(response = ?bytes, "Response data");
+                                        let write_span = span!(Level::DEBUG, "write_response");
+                                        if !clients::reply_queued(&server, &conn, bytes.len()) {
+                                            debug!("Client over its output limit, reply dropped");
+                                            return Ok(None);
+                                        }
+                                        if let Err(e) = stream.write_all(&bytes).instrument(write_span).await {
                                             error!(error = ?e, "Failed to send response");
-                                            Err(e)
+                                            return Err(e);
                                         }
+                                        conn.output.written(bytes.len());
+                                        debug!("Response sent successfully");
+                                        if conn.quit {
+                                            return Ok(None);
+                                        }
+                                    },
+                                    None => {
+                                        debug!("Command produced no response");
                                     }
-                                },
-                                None => {
-                                    debug!("Command produced no response");
-                                    Ok(())
                                 }
+                            },
+                            Ok(None) => {
+                                debug!("Incomplete command, waiting for more data");
+                                clients::record(&server, &conn, None, &buf);
+                                return Ok(None);
+                            },
+                            Err(e) => {
+                                error!(error = ?e, "Failed to parse command");
+                                // there is no way to resync with a broken stream, drop what we have
+                                buf.clear();
+                                let error_msg = format!("-ERR parsing error: {:?}\r\n", e);
+                                stream.write_all(error_msg.as_bytes()).await?;
+                                return Ok(None);
                             }
-                        },
-                        Ok(None) => {
-                            debug!("Incomplete command, waiting for more data");
-                            Ok(())
-                        },
-                        Err(e) => {
-                            error!(error = ?e, "Failed to parse command");
-                            let error_msg = format!("-ERR parsing error: {:?}\r\n", e);
-                            stream.write_all(error_msg.as_bytes()).await?;
-                            Ok(())
                         }
                     }
                 }
                 .instrument(parse_span)
                 .await;
                 
-                if let Err(e) = result {
-                    error!(error = ?e, "Error in command processing loop");
-                    return Err(e);
+                match result {
+                    Ok(Some(psync)) => {
+                        info!("Switching connection to replica stream");
+                        clients::set_role(&server, client.id, Type::Replica);
+                        let output = client.output.clone();
+                        return replication::serve_replica(server, stream, peer, buf, psync, replica_port, output).await;
+                    },
+                    Ok(None) if conn.quit => {
+                        debug!("Client sent QUIT");
+                        break;
+                    },
+                    Ok(None) => {},
+                    Err(e) => {
+                        error!(error = ?e, "Error in command processing loop");
+                        return Err(e);
+                    }
                 }
             },
             Err(e) => {
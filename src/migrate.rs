@@ -0,0 +1,340 @@
+use crate::commands;
+use crate::db::{now_ms, Entry};
+use crate::handler::{arg_int, arg_str, error, ok, wrong_arity, ToResp};
+use crate::notify;
+use crate::parser::{RespOrig, RespParser};
+use crate::rdb;
+use crate::replication;
+use crate::scripting;
+use crate::server::Server;
+use bytes::{Bytes, BytesMut};
+use std::io::Error;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::Decoder;
+use tracing::{debug, info, warn};
+
+/// DUMP key
+pub fn dump(args: &[RespOrig], server: &Server, db: usize) -> RespOrig {
+    let [key] = args else {
+        return wrong_arity("dump");
+    };
+    let Some(key) = key.as_bytes() else {
+        return wrong_arity("dump");
+    };
+    match server.db(db).get(key) {
+        Some(entry) => RespOrig::BulkString(rdb::dump_value(&entry.value)),
+        None => RespOrig::NullBulkString,
+    }
+}
+
+/// RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency].
+/// there is no eviction policy, so IDLETIME and FREQ are only validated
+pub fn restore(args: &[RespOrig], server: &Server, index: usize) -> RespOrig {
+    let [key, ttl, payload, options @ ..] = args else {
+        return wrong_arity("restore");
+    };
+    let (Some(key), Some(payload)) = (key.as_bytes(), payload.as_bytes()) else {
+        return wrong_arity("restore");
+    };
+
+    let mut replace = false;
+    let mut absolute = false;
+    let mut i = 0;
+    while i < options.len() {
+        match arg_str(&options[i]).map(str::to_uppercase).as_deref() {
+            Some("REPLACE") => replace = true,
+            Some("ABSTTL") => absolute = true,
+            Some(option @ ("IDLETIME" | "FREQ")) => {
+                i += 1;
+                let valid = match options.get(i).and_then(arg_int) {
+                    Some(n) if option == "IDLETIME" => n >= 0,
+                    Some(n) => (0..=255).contains(&n),
+                    None => false,
+                };
+                if !valid {
+                    return error(&format!("ERR Invalid {} value", option.to_lowercase()));
+                }
+            }
+            _ => return error("ERR syntax error"),
+        }
+        i += 1;
+    }
+    let ttl = match arg_int(ttl) {
+        Some(ttl) if ttl >= 0 => ttl as u64,
+        Some(_) => return error("ERR Invalid TTL value, must be >= 0"),
+        None => return error("ERR value is not an integer or out of range"),
+    };
+    let value = match rdb::restore_value(payload) {
+        Ok(value) => value,
+        Err(e) => {
+            debug!(error = ?e, "Rejected restore payload");
+            return error("ERR DUMP payload version or checksum are wrong");
+        }
+    };
+
+    let mut db = server.db(index);
+    if !replace && db.contains(key) {
+        return error("BUSYKEY Target key name already exists.");
+    }
+    let expires_at = match (ttl, absolute) {
+        (0, _) => None,
+        (at, true) => Some(at),
+        (ttl, false) => Some(now_ms() + ttl),
+    };
+    let entry = Entry { value, expires_at };
+    if entry.is_expired(now_ms()) {
+        // an absolute ttl in the past: the key is gone as soon as it arrives
+        let existed = db.remove(key).is_some();
+        drop(db);
+        if existed {
+            notify::keyspace_event(server, index, notify::GENERIC, "del", key);
+        }
+        return ok();
+    }
+    let created = db.get(key).is_none();
+    db.set(key.clone(), entry);
+    drop(db);
+    debug!(key = ?key, ?expires_at, "Key restored");
+    if created {
+        notify::keyspace_event(server, index, notify::NEW, "new", key);
+    }
+    notify::keyspace_event(server, index, notify::GENERIC, "restore", key);
+    ok()
+}
+
+#[derive(Debug)]
+pub struct MigrateRequest {
+    host: String,
+    port: u16,
+    db: i64,
+    timeout: Duration,
+    copy: bool,
+    replace: bool,
+    /// optional username and the password
+    auth: Option<(Option<String>, String)>,
+    keys: Vec<Bytes>,
+}
+
+/// recognizes MIGRATE, which talks to another server and so cannot run inside the
+/// synchronous handler. a malformed request yields the error reply to send instead
+pub fn request(frame: &RespOrig) -> Option<Result<MigrateRequest, RespOrig>> {
+    let RespOrig::Array(items) = frame else {
+        return None;
+    };
+    if !items.first()?.as_bytes()?.eq_ignore_ascii_case(b"MIGRATE") {
+        return None;
+    }
+    Some(parse_request(&items[1..]))
+}
+
+fn parse_request(args: &[RespOrig]) -> Result<MigrateRequest, RespOrig> {
+    let [host, port, key, db, timeout_ms, options @ ..] = args else {
+        return Err(wrong_arity("migrate"));
+    };
+    let mut request = MigrateRequest {
+        host: arg_str(host).ok_or_else(|| error("ERR syntax error"))?.to_string(),
+        port: arg_int(port)
+            .and_then(|p| u16::try_from(p).ok())
+            .ok_or_else(|| error("ERR value is not an integer or out of range"))?,
+        db: arg_int(db)
+            .filter(|db| *db >= 0)
+            .ok_or_else(|| error("ERR value is not an integer or out of range"))?,
+        timeout: Duration::ZERO,
+        copy: false,
+        replace: false,
+        auth: None,
+        keys: Vec::new(),
+    };
+    let timeout_ms = arg_int(timeout_ms).ok_or_else(|| error("ERR value is not an integer or out of range"))?;
+    request.timeout = Duration::from_millis(if timeout_ms <= 0 { 1000 } else { timeout_ms as u64 });
+
+    let mut i = 0;
+    let mut with_keys = false;
+    while i < options.len() {
+        let text = |i: usize| options.get(i).and_then(arg_str).map(str::to_string);
+        match arg_str(&options[i]).map(str::to_uppercase).as_deref() {
+            Some("COPY") => request.copy = true,
+            Some("REPLACE") => request.replace = true,
+            Some("AUTH") => {
+                let password = text(i + 1).ok_or_else(|| error("ERR syntax error"))?;
+                request.auth = Some((None, password));
+                i += 1;
+            }
+            Some("AUTH2") => {
+                let (Some(user), Some(password)) = (text(i + 1), text(i + 2)) else {
+                    return Err(error("ERR syntax error"));
+                };
+                request.auth = Some((Some(user), password));
+                i += 2;
+            }
+            Some("KEYS") => {
+                if !key.as_bytes().is_some_and(|k| k.is_empty()) {
+                    return Err(error(
+                        "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                    ));
+                }
+                request.keys = options[i + 1..].iter().filter_map(|k| k.as_bytes().cloned()).collect();
+                with_keys = true;
+                break;
+            }
+            _ => return Err(error("ERR syntax error")),
+        }
+        i += 1;
+    }
+    if !with_keys {
+        request.keys.push(key.as_bytes().cloned().ok_or_else(|| error("ERR syntax error"))?);
+    }
+    Ok(request)
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH password]
+/// [AUTH2 username password] [KEYS key ...]. keys go over as RESTORE commands and are
+/// deleted here once the target accepted them, unless COPY is given. `index` is the
+/// database of the client, the keys come from there
+pub async fn migrate(server: &Server, index: usize, request: MigrateRequest) -> RespOrig {
+    if let Some(denied) = commands::lookup("MIGRATE").and_then(|c| replication::guard(server, c)) {
+        return denied;
+    }
+
+    let now = now_ms();
+    let entries: Vec<(Bytes, Entry)> = {
+        let _shared = match scripting::lock_shared(server) {
+            Ok(guard) => guard,
+            Err(busy) => return busy,
+        };
+        let mut db = server.db(index);
+        request
+            .keys
+            .iter()
+            .filter_map(|key| db.get(key).map(|entry| (key.clone(), entry.clone())))
+            .collect()
+    };
+    if entries.is_empty() {
+        return RespOrig::String(Bytes::from_static(b"NOKEY"));
+    }
+
+    let addr = format!("{}:{}", request.host, request.port);
+    let mut stream = match timeout(request.timeout, TcpStream::connect(&addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => {
+            warn!(target = %addr, "Migrate connection failed");
+            return error("IOERR error or timeout connecting to the client");
+        }
+    };
+
+    let mut pipeline = BytesMut::new();
+    let mut preamble = 0;
+    if let Some((user, password)) = &request.auth {
+        let mut auth = vec![bulk("AUTH")];
+        auth.extend(user.iter().map(|u| bulk(u.clone())));
+        auth.push(bulk(password.clone()));
+        pipeline.extend_from_slice(&RespOrig::Array(auth).to_resp());
+        preamble += 1;
+    }
+    if request.db != 0 {
+        let select = vec![bulk("SELECT"), bulk(request.db.to_string())];
+        pipeline.extend_from_slice(&RespOrig::Array(select).to_resp());
+        preamble += 1;
+    }
+    // on a cluster the target is importing the slot, so the restore has to be asking
+    let restore_cmd = if server.cluster.is_some() { "RESTORE-ASKING" } else { "RESTORE" };
+    for (key, entry) in &entries {
+        let ttl = entry.expires_at.map_or(0, |at| at.saturating_sub(now).max(1));
+        let mut restore = vec![
+            bulk(restore_cmd),
+            RespOrig::BulkString(key.clone()),
+            bulk(ttl.to_string()),
+            RespOrig::BulkString(rdb::dump_value(&entry.value)),
+        ];
+        if request.replace {
+            restore.push(bulk("REPLACE"));
+        }
+        pipeline.extend_from_slice(&RespOrig::Array(restore).to_resp());
+    }
+    if !matches!(timeout(request.timeout, stream.write_all(&pipeline)).await, Ok(Ok(()))) {
+        return error("IOERR error or timeout writing to target instance");
+    }
+
+    let replies = match timeout(request.timeout, read_replies(&mut stream, preamble + entries.len())).await {
+        Ok(Ok(replies)) => replies,
+        _ => return error("IOERR error or timeout reading to target instance"),
+    };
+    let mut failure = None;
+    for reply in &replies[..preamble] {
+        if let RespOrig::Error(msg) = reply {
+            failure.get_or_insert_with(|| msg.clone());
+        }
+    }
+    let mut moved = Vec::new();
+    if failure.is_none() {
+        for (entry, reply) in entries.iter().zip(&replies[preamble..]) {
+            match reply {
+                RespOrig::Error(msg) => {
+                    failure.get_or_insert_with(|| msg.clone());
+                }
+                _ => moved.push(entry),
+            }
+        }
+    }
+
+    if !request.copy && !moved.is_empty() {
+        // a key written while the transfer was in flight keeps its newer value
+        let mut deleted = Vec::new();
+        {
+            let _shared = match scripting::lock_shared(server) {
+                Ok(guard) => guard,
+                Err(busy) => return busy,
+            };
+            let mut db = server.db(index);
+            for (key, snapshot) in &moved {
+                if db.get(key) == Some(snapshot) {
+                    db.remove(key);
+                    deleted.push(key.clone());
+                } else {
+                    debug!(key = ?key, "Key changed during migration, not deleted");
+                }
+            }
+        }
+        for key in &deleted {
+            notify::keyspace_event(server, index, notify::GENERIC, "del", key);
+        }
+        if !deleted.is_empty() {
+            let mut del = vec![bulk("DEL")];
+            del.extend(deleted.into_iter().map(RespOrig::BulkString));
+            replication::propagate_to_db(server, index, RespOrig::Array(del));
+        }
+    }
+    info!(target = %addr, keys = moved.len(), copy = request.copy, "Migrated keys");
+
+    match failure {
+        Some(msg) => error(&format!(
+            "ERR Target instance replied with error: {}",
+            String::from_utf8_lossy(&msg)
+        )),
+        None => ok(),
+    }
+}
+
+async fn read_replies(stream: &mut TcpStream, count: usize) -> Result<Vec<RespOrig>, Error> {
+    let mut buf = BytesMut::with_capacity(256);
+    let mut parser = RespParser;
+    let mut replies = Vec::with_capacity(count);
+    while replies.len() < count {
+        match parser.decode(&mut buf).map_err(|e| Error::other(format!("{e:?}")))? {
+            Some(reply) => replies.push(reply),
+            None => {
+                if stream.read_buf(&mut buf).await? == 0 {
+                    return Err(Error::other("target closed the connection"));
+                }
+            }
+        }
+    }
+    Ok(replies)
+}
+
+fn bulk(s: impl Into<String>) -> RespOrig {
+    RespOrig::BulkString(Bytes::from(s.into()))
+}
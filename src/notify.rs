@@ -0,0 +1,104 @@
+use crate::pubsub;
+use crate::server::Server;
+use bytes::Bytes;
+use tracing::trace;
+
+/// event classes of `notify-keyspace-events`, one letter each
+pub const KEYSPACE: u32 = 1;
+pub const KEYEVENT: u32 = 1 << 1;
+pub const GENERIC: u32 = 1 << 2;
+pub const STRING: u32 = 1 << 3;
+pub const LIST: u32 = 1 << 4;
+pub const SET: u32 = 1 << 5;
+pub const HASH: u32 = 1 << 6;
+pub const ZSET: u32 = 1 << 7;
+pub const EXPIRED: u32 = 1 << 8;
+pub const EVICTED: u32 = 1 << 9;
+pub const STREAM: u32 = 1 << 10;
+pub const KEY_MISS: u32 = 1 << 11;
+pub const NEW: u32 = 1 << 12;
+/// `A`: every class except key misses and new keys
+pub const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+const LETTERS: &[(char, u32)] = &[
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+    ('m', KEY_MISS),
+    ('n', NEW),
+];
+
+/// parses a flag string such as `Ex` or `KA`, `None` on an unknown letter
+pub fn parse_flags(flags: &str) -> Option<u32> {
+    flags.chars().try_fold(0, |acc, c| {
+        let class = match c {
+            'A' => ALL,
+            c => LETTERS.iter().find(|(letter, _)| *letter == c)?.1,
+        };
+        Some(acc | class)
+    })
+}
+
+/// the canonical flag string, as CONFIG GET reports it
+pub fn flags_to_string(flags: u32) -> String {
+    let mut out = String::new();
+    if flags & ALL == ALL {
+        out.push('A');
+    }
+    for &(letter, class) in LETTERS {
+        let in_all = class & ALL != 0 && flags & ALL == ALL;
+        if flags & class != 0 && !in_all {
+            out.push(letter);
+        }
+    }
+    out
+}
+
+/// publishes `event` for `key` of database `db` on `__keyspace@<db>__:<key>` and
+/// `__keyevent@<db>__:<event>`, as far as the configured flags ask for it
+pub fn keyspace_event(server: &Server, db: usize, class: u32, event: &str, key: &[u8]) {
+    let flags = server.config().notify_keyspace_events;
+    if flags & class == 0 || flags & (KEYSPACE | KEYEVENT) == 0 {
+        return;
+    }
+    trace!(db, event, key = ?key, "Keyspace notification");
+    if flags & KEYSPACE != 0 {
+        let mut channel = format!("__keyspace@{db}__:").into_bytes();
+        channel.extend_from_slice(key);
+        pubsub::publish(server, &Bytes::from(channel), &Bytes::copy_from_slice(event.as_bytes()));
+    }
+    if flags & KEYEVENT != 0 {
+        let channel = Bytes::from(format!("__keyevent@{db}__:{event}"));
+        pubsub::publish(server, &channel, &Bytes::copy_from_slice(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_flags_reads_letters() {
+        assert_eq!(parse_flags(""), Some(0));
+        assert_eq!(parse_flags("Ex"), Some(KEYEVENT | EXPIRED));
+        assert_eq!(parse_flags("KA"), Some(KEYSPACE | ALL));
+        assert_eq!(parse_flags("Km$"), Some(KEYSPACE | KEY_MISS | STRING));
+        assert_eq!(parse_flags("Kq"), None);
+    }
+
+    #[test]
+    fn flags_string_is_canonical() {
+        assert_eq!(flags_to_string(parse_flags("EKA").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("xgE").unwrap()), "gxE");
+        assert_eq!(flags_to_string(parse_flags("glshzxet$").unwrap()), "A");
+        assert_eq!(flags_to_string(0), "");
+    }
+}
@@ -74,7 +74,7 @@ impl Resp {
 }
 
 /// original look of resp type for values flowing thorugh the system. inputs and ouputs converts into 'Resp'
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RespOrig {
     String(Bytes),
     BulkString(Bytes),
@@ -138,14 +138,13 @@ impl RespParser {
 
     #[tracing::instrument(level = "debug", skip(buf), fields(buf_len = buf.len()))]
     fn parse(buf: &BytesMut, pos: usize) -> RedisResult {
-        if buf.is_empty() {
+        // an array element that has not arrived yet starts at the end of the buffer
+        if buf.len() <= pos {
             debug!("Empty buffer, nothing to parse");
             return Ok(None);
         }
 
-        println!("buffer: \n\n{buf:?}\n\n");
-
-        
+        trace!(buf_len = buf.len(), pos, "Parsing buffer");
         match buf[pos] {
             b'+' => {
                 debug!("Detected simple string");
@@ -256,7 +255,7 @@ fn bulk_string(buf: &BytesMut, pos: usize) -> RedisResult {
                 Ok(None)
             } else {
                 // We have enough bytes, so we can generate the correct type.
-                let bb = Resp::String(BufSplit(pos, total_size));
+                let bb = Resp::BufString(BufSplit(pos, total_size));
                 // total_size + 2 == ...bulkstring\r\n<HERE> -- after CLRF
                 trace!(pos = total_size + 2, "Complete bulk string parsed");
                 Ok(Some((total_size + 2, bb)))
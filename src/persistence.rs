@@ -0,0 +1,234 @@
+use crate::functions;
+use crate::handler::{arg_str, error, ok, wrong_arity};
+use crate::parser::RespOrig;
+use crate::rdb::{self, Snapshot};
+use crate::server::Server;
+use bytes::Bytes;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, warn};
+
+/// state of the rdb file `dbfilename` in `dir`, which is the working directory
+#[derive(Debug)]
+pub struct Persistence {
+    /// unix seconds of the last successful save, or of the start
+    last_save: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    last_bgsave_ok: AtomicBool,
+}
+
+impl Default for Persistence {
+    fn default() -> Persistence {
+        Persistence {
+            last_save: AtomicU64::new(unix_time()),
+            bgsave_in_progress: AtomicBool::new(false),
+            last_bgsave_ok: AtomicBool::new(true),
+        }
+    }
+}
+
+/// how often SHUTDOWN checks whether a background save has finished
+const BGSAVE_POLL: Duration = Duration::from_millis(10);
+
+/// numbers temporary files, so a SAVE never writes into the file of a running BGSAVE
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// the databases and function libraries as an rdb image
+pub fn snapshot(server: &Server) -> Bytes {
+    // functions before the databases, the order a full resync takes them in
+    let functions = server.functions.lock().unwrap();
+    let dbs: Vec<_> = server.dbs.iter().map(|db| db.lock().unwrap()).collect();
+    rdb::encode(&dbs, &functions::sources(&functions))
+}
+
+/// replaces the whole dataset with `snapshot`, returns how many keys were loaded
+pub fn restore(server: &Server, snapshot: Snapshot) -> usize {
+    functions::load_all(server, snapshot.functions);
+    let mut dbs: Vec<_> = server.dbs.iter().map(|db| db.lock().unwrap()).collect();
+    for db in dbs.iter_mut() {
+        db.clear();
+    }
+    let mut keys = 0;
+    for (index, key, entry) in snapshot.entries {
+        match dbs.get_mut(index) {
+            Some(db) => {
+                db.set(key, entry);
+                keys += 1;
+            }
+            None => warn!(db = index, "Dropping key of a database we do not have"),
+        }
+    }
+    keys
+}
+
+/// loads `dbfilename` at startup. a missing file means an empty dataset
+pub fn load(server: &Server) -> Result<(), Error> {
+    let path = server.config().dbfilename.clone();
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!(file = %path, "No rdb file, starting with an empty dataset");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let snapshot = rdb::decode(&data).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{path}: {e:?}")))?;
+    let keys = restore(server, snapshot);
+    info!(file = %path, keys, "Loaded dataset from disk");
+    Ok(())
+}
+
+/// writes `data` to a temporary file and renames it over `dbfilename`, so a crash never
+/// leaves a half written rdb behind
+fn write(path: &str, data: &[u8]) -> Result<(), Error> {
+    let temp = format!("temp-{}-{}.rdb", std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed));
+    let result = fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    if let Err(e) = result.and_then(|()| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// saves in the foreground
+pub fn save(server: &Server) -> Result<(), Error> {
+    let path = server.config().dbfilename.clone();
+    let data = snapshot(server);
+    write(&path, &data).inspect_err(|e| error!(error = ?e, file = %path, "Failed saving the dataset"))?;
+    server.persistence.last_save.store(unix_time(), Ordering::Relaxed);
+    info!(file = %path, size = data.len(), "DB saved on disk");
+    Ok(())
+}
+
+/// the snapshot is taken right away, only writing the file happens in the background
+fn bgsave(server: &Arc<Server>) -> RespOrig {
+    if server.persistence.bgsave_in_progress.swap(true, Ordering::AcqRel) {
+        return error("ERR Background save already in progress");
+    }
+    let path = server.config().dbfilename.clone();
+    let data = snapshot(server);
+    let server = server.clone();
+    tokio::task::spawn_blocking(move || {
+        let result = write(&path, &data);
+        let state = &server.persistence;
+        match &result {
+            Ok(()) => {
+                state.last_save.store(unix_time(), Ordering::Relaxed);
+                info!(file = %path, size = data.len(), "Background saving terminated with success");
+            }
+            Err(e) => error!(error = ?e, file = %path, "Background saving failed"),
+        }
+        state.last_bgsave_ok.store(result.is_ok(), Ordering::Relaxed);
+        state.bgsave_in_progress.store(false, Ordering::Release);
+    });
+    RespOrig::String(Bytes::from_static(b"Background saving started"))
+}
+
+/// SAVE | BGSAVE [SCHEDULE] | LASTSAVE | SHUTDOWN [NOSAVE | SAVE] [NOW] [FORCE]
+pub fn command(name: &str, args: &[RespOrig], server: &Arc<Server>) -> RespOrig {
+    match (name, args) {
+        ("SAVE", []) => {
+            if server.persistence.bgsave_in_progress.load(Ordering::Acquire) {
+                return error("ERR Background save already in progress");
+            }
+            match save(server) {
+                Ok(()) => ok(),
+                Err(_) => error("ERR Failed saving the dataset, check the logs"),
+            }
+        }
+        ("BGSAVE", []) => bgsave(server),
+        ("BGSAVE", [schedule]) if arg_str(schedule).is_some_and(|s| s.eq_ignore_ascii_case("SCHEDULE")) => {
+            bgsave(server)
+        }
+        ("BGSAVE", _) => error("ERR syntax error"),
+        ("LASTSAVE", []) => RespOrig::Int(server.persistence.last_save.load(Ordering::Relaxed) as i64),
+        ("SHUTDOWN", _) => shutdown(args, server),
+        _ => wrong_arity(&name.to_lowercase()),
+    }
+}
+
+/// replies only when the shutdown failed, otherwise the process is gone
+fn shutdown(args: &[RespOrig], server: &Server) -> RespOrig {
+    let mut save_first = true;
+    let mut force = false;
+    for arg in args {
+        match arg_str(arg).map(str::to_uppercase).as_deref() {
+            Some("NOSAVE") => save_first = false,
+            Some("SAVE") => save_first = true,
+            Some("NOW") => {}
+            Some("FORCE") => force = true,
+            _ => return error("ERR syntax error"),
+        }
+    }
+    wait_for_bgsave(server);
+    if save_first && save(server).is_err() && !force {
+        return error("ERR Errors trying to SHUTDOWN. Check logs.");
+    }
+    exit()
+}
+
+/// lets a running BGSAVE finish its file, so exiting never leaves it half written
+fn wait_for_bgsave(server: &Server) {
+    if !server.persistence.bgsave_in_progress.load(Ordering::Acquire) {
+        return;
+    }
+    info!("Waiting for the background save to finish");
+    tokio::task::block_in_place(|| {
+        while server.persistence.bgsave_in_progress.load(Ordering::Acquire) {
+            std::thread::sleep(BGSAVE_POLL);
+        }
+    });
+}
+
+fn exit() -> ! {
+    info!("Redis is now ready to exit, bye bye...");
+    std::process::exit(0)
+}
+
+/// SIGTERM and SIGINT save the dataset and exit. when the save fails the server keeps
+/// running, as redis does
+pub async fn shutdown_on_signal(server: Arc<Server>) -> Result<(), Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => debug!("Received SIGTERM"),
+            _ = interrupt.recv() => debug!("Received SIGINT"),
+        }
+        info!("Shutdown requested, saving the final snapshot");
+        wait_for_bgsave(&server);
+        if save(&server).is_ok() {
+            exit();
+        }
+        warn!("Error trying to save the DB, can't exit");
+    }
+}
+
+/// `INFO persistence` section
+pub fn info(server: &Server) -> String {
+    let state = &server.persistence;
+    let mut out = String::from("# Persistence\r\n");
+    out.push_str("loading:0\r\n");
+    out.push_str(&format!(
+        "rdb_bgsave_in_progress:{}\r\n",
+        state.bgsave_in_progress.load(Ordering::Relaxed) as u8
+    ));
+    out.push_str(&format!("rdb_last_save_time:{}\r\n", state.last_save.load(Ordering::Relaxed)));
+    out.push_str(&format!(
+        "rdb_last_bgsave_status:{}\r\n",
+        if state.last_bgsave_ok.load(Ordering::Relaxed) { "ok" } else { "err" }
+    ));
+    out.push_str("aof_enabled:0\r\n");
+    out
+}
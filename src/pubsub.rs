@@ -0,0 +1,385 @@
+use crate::clients::{self, OutputBuffer};
+use crate::cluster::{self, key_slot};
+use crate::commands;
+use crate::config::OutputLimit;
+use crate::glob;
+use crate::handler::{arg_str, error, wrong_arity, ToResp};
+use crate::parser::RespOrig;
+use crate::server::Server;
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, trace};
+
+type Registry = HashMap<Bytes, HashMap<u64, Outbox>>;
+
+/// subscribers by channel, by pattern and by shard channel. each subscriber is a
+/// connection, known by its id and the sender its connection loop drains onto the socket
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: Registry,
+    patterns: Registry,
+    shard_channels: Registry,
+    next_id: u64,
+}
+
+/// where a subscriber's pushes go, and the output buffer they count against
+#[derive(Debug, Clone)]
+struct Outbox {
+    tx: UnboundedSender<Push>,
+    output: Arc<OutputBuffer>,
+}
+
+impl Outbox {
+    /// false when the subscriber is gone or over its output limit
+    fn send(&self, push: &Bytes, limit: OutputLimit) -> bool {
+        self.output.push(push.len(), limit) && self.tx.send(Push::Message(push.clone())).is_ok()
+    }
+}
+
+/// what a connection loop receives for its subscriber
+#[derive(Debug)]
+pub enum Push {
+    /// an encoded message, written as is
+    Message(Bytes),
+    /// the shard channel's slot moved to another shard, see `Subscriber::shard_channel_lost`
+    ShardChannelLost(Bytes),
+}
+
+/// delivers `message` to local subscribers and returns how many received it
+pub fn publish(server: &Server, channel: &Bytes, message: &Bytes) -> usize {
+    let limit = server.clients.limit(clients::Type::PubSub);
+    let pubsub = server.pubsub.lock().unwrap();
+    let mut receivers = 0;
+    if let Some(subscribers) = pubsub.channels.get(channel) {
+        let push = push_message(&[b"message", channel, message]);
+        for outbox in subscribers.values() {
+            receivers += outbox.send(&push, limit) as usize;
+        }
+    }
+    for (pattern, subscribers) in &pubsub.patterns {
+        if !glob::matches(pattern, channel, false) {
+            continue;
+        }
+        let push = push_message(&[b"pmessage", pattern, channel, message]);
+        for outbox in subscribers.values() {
+            receivers += outbox.send(&push, limit) as usize;
+        }
+    }
+    trace!(channel = ?channel, receivers, "Published message");
+    receivers
+}
+
+/// SPUBLISH delivery: shard channels have no pattern subscribers
+pub fn publish_shard(server: &Server, channel: &Bytes, message: &Bytes) -> usize {
+    let limit = server.clients.limit(clients::Type::PubSub);
+    let pubsub = server.pubsub.lock().unwrap();
+    let Some(subscribers) = pubsub.shard_channels.get(channel) else {
+        return 0;
+    };
+    let push = push_message(&[b"smessage", channel, message]);
+    let receivers = subscribers
+        .values()
+        .filter(|outbox| outbox.send(&push, limit))
+        .count();
+    trace!(channel = ?channel, receivers, "Published shard message");
+    receivers
+}
+
+/// drops the shard channels hashing to `slots`, which this node no longer serves, and
+/// tells their subscribers
+pub fn slots_lost(server: &Server, slots: &[usize]) {
+    if slots.is_empty() {
+        return;
+    }
+    let mut pubsub = server.pubsub.lock().unwrap();
+    let lost: Vec<Bytes> = pubsub
+        .shard_channels
+        .keys()
+        .filter(|c| slots.contains(&(key_slot(c) as usize)))
+        .cloned()
+        .collect();
+    for channel in lost {
+        debug!(channel = ?channel, "Shard channel moved away, unsubscribing its clients");
+        for outbox in pubsub.shard_channels.remove(&channel).into_iter().flat_map(|s| s.into_values()) {
+            let _ = outbox.tx.send(Push::ShardChannelLost(channel.clone()));
+        }
+    }
+}
+
+fn push_message(parts: &[&[u8]]) -> Bytes {
+    RespOrig::Array(parts.iter().map(|p| RespOrig::BulkString(Bytes::copy_from_slice(p))).collect()).to_resp()
+}
+
+/// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT | SHARDCHANNELS [pattern] |
+/// SHARDNUMSUB [channel ...]
+pub fn command(args: &[RespOrig], server: &Server) -> RespOrig {
+    let Some(sub) = args.first().and_then(arg_str).map(str::to_uppercase) else {
+        return wrong_arity("pubsub");
+    };
+    let args = &args[1..];
+    let pubsub = server.pubsub.lock().unwrap();
+    match sub.as_str() {
+        "CHANNELS" | "SHARDCHANNELS" if args.len() <= 1 => {
+            let pattern = args.first().and_then(RespOrig::as_bytes);
+            let registry = if sub == "CHANNELS" { &pubsub.channels } else { &pubsub.shard_channels };
+            RespOrig::Array(
+                registry
+                    .keys()
+                    .filter(|c| pattern.is_none_or(|p| glob::matches(p, c, false)))
+                    .map(|c| RespOrig::BulkString(c.clone()))
+                    .collect(),
+            )
+        }
+        "NUMSUB" | "SHARDNUMSUB" => {
+            let registry = if sub == "NUMSUB" { &pubsub.channels } else { &pubsub.shard_channels };
+            let mut out = Vec::with_capacity(args.len() * 2);
+            for channel in args.iter().filter_map(RespOrig::as_bytes) {
+                let count = registry.get(channel).map_or(0, HashMap::len);
+                out.push(RespOrig::BulkString(channel.clone()));
+                out.push(RespOrig::Int(count as i64));
+            }
+            RespOrig::Array(out)
+        }
+        "NUMPAT" if args.is_empty() => RespOrig::Int(pubsub.patterns.len() as i64),
+        "CHANNELS" | "SHARDCHANNELS" | "NUMPAT" => wrong_arity(&format!("pubsub|{}", sub.to_lowercase())),
+        _ => error(&format!(
+            "ERR unknown subcommand '{}'. Try PUBSUB HELP.",
+            sub.to_lowercase()
+        )),
+    }
+}
+
+/// the subscriptions of one connection. dropping it unsubscribes from everything
+#[derive(Debug)]
+pub struct Subscriber {
+    server: Arc<Server>,
+    id: u64,
+    tx: UnboundedSender<Push>,
+    /// the connection's, see `Connection::attach`
+    pub(crate) output: Arc<OutputBuffer>,
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+    shard_channels: HashSet<Bytes>,
+}
+
+impl Subscriber {
+    /// the receiver yields what the connection loop passes to `deliver`
+    pub fn new(server: Arc<Server>, output: Arc<OutputBuffer>) -> (Subscriber, UnboundedReceiver<Push>) {
+        let (tx, rx) = unbounded_channel();
+        let id = {
+            let mut pubsub = server.pubsub.lock().unwrap();
+            pubsub.next_id += 1;
+            pubsub.next_id
+        };
+        let subscriber = Subscriber {
+            server,
+            id,
+            tx,
+            output,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+        };
+        (subscriber, rx)
+    }
+
+    /// what (un)subscribe confirmations report; shard channels are counted apart
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// channels, patterns and shard channels, as CLIENT LIST shows them
+    pub fn counts(&self) -> (usize, usize, usize) {
+        (self.channels.len(), self.patterns.len(), self.shard_channels.len())
+    }
+
+    /// in subscribed mode the client may only manage subscriptions and ping
+    pub fn is_subscribed(&self) -> bool {
+        self.count() + self.shard_channels.len() > 0
+    }
+
+    /// bytes to write to the client for a push. a message leaves the output buffer here
+    pub fn deliver(&mut self, push: Push) -> Bytes {
+        match push {
+            Push::Message(bytes) => {
+                self.output.written(bytes.len());
+                bytes
+            }
+            Push::ShardChannelLost(channel) => {
+                if !self.shard_channels.remove(&channel) {
+                    // the client unsubscribed in the meantime
+                    return Bytes::new();
+                }
+                confirmation(b"sunsubscribe", Some(&channel), self.shard_channels.len())
+            }
+        }
+    }
+
+    /// handles the subscription commands, and refuses everything else while subscribed.
+    /// `None` means the frame is a regular command for the handler
+    pub fn handle(&mut self, frame: &RespOrig) -> Option<Bytes> {
+        let RespOrig::Array(items) = frame else {
+            return None;
+        };
+        let name = items.first().and_then(arg_str)?.to_uppercase();
+        let args = &items[1..];
+        let reply = match name.as_str() {
+            "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" if args.is_empty() => {
+                wrong_arity(&name.to_lowercase()).to_resp()
+            }
+            "SUBSCRIBE" => self.subscribe(args, Kind::Channel),
+            "PSUBSCRIBE" => self.subscribe(args, Kind::Pattern),
+            "SSUBSCRIBE" => {
+                // shard channels are routed like keys: same slot, served by this shard
+                let spec = commands::lookup("SSUBSCRIBE")?;
+                if let Some(redirect) = cluster::redirect(&self.server, spec, items, false) {
+                    return Some(redirect.to_resp());
+                }
+                self.subscribe(args, Kind::Shard)
+            }
+            "UNSUBSCRIBE" => self.unsubscribe(args, Kind::Channel),
+            "PUNSUBSCRIBE" => self.unsubscribe(args, Kind::Pattern),
+            "SUNSUBSCRIBE" => self.unsubscribe(args, Kind::Shard),
+            "PING" if self.is_subscribed() => {
+                let message = args.first().and_then(RespOrig::as_bytes).cloned().unwrap_or_default();
+                push_message(&[b"pong", &message])
+            }
+            // these end the subscribed state, the normal handler runs them
+            "QUIT" | "RESET" => return None,
+            _ if self.is_subscribed() => error(&format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                name.to_lowercase()
+            ))
+            .to_resp(),
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    /// leaves every channel, pattern and shard channel without confirming any of it
+    pub fn unsubscribe_all(&mut self) {
+        if !self.is_subscribed() {
+            return;
+        }
+        let mut pubsub = self.server.pubsub.lock().unwrap();
+        for channel in self.channels.drain() {
+            remove(&mut pubsub.channels, &channel, self.id);
+        }
+        for pattern in self.patterns.drain() {
+            remove(&mut pubsub.patterns, &pattern, self.id);
+        }
+        for channel in self.shard_channels.drain() {
+            remove(&mut pubsub.shard_channels, &channel, self.id);
+        }
+    }
+
+    fn subscribe(&mut self, args: &[RespOrig], kind: Kind) -> Bytes {
+        let mut out = BytesMut::new();
+        let server = self.server.clone();
+        let mut pubsub = server.pubsub.lock().unwrap();
+        let outbox = Outbox { tx: self.tx.clone(), output: self.output.clone() };
+        let id = self.id;
+        for name in args.iter().filter_map(RespOrig::as_bytes) {
+            let (mine, registry) = kind.select(self, &mut pubsub);
+            if mine.insert(name.clone()) {
+                registry.entry(name.clone()).or_default().insert(id, outbox.clone());
+                debug!(channel = ?name, ?kind, "Subscribed");
+            }
+            out.extend_from_slice(&confirmation(kind.reply(true), Some(name), self.reply_count(kind)));
+        }
+        out.freeze()
+    }
+
+    /// without arguments, drops every subscription of that kind
+    fn unsubscribe(&mut self, args: &[RespOrig], kind: Kind) -> Bytes {
+        let server = self.server.clone();
+        let names: Vec<Bytes> = if args.is_empty() {
+            let mut pubsub = server.pubsub.lock().unwrap();
+            kind.select(self, &mut pubsub).0.iter().cloned().collect()
+        } else {
+            args.iter().filter_map(RespOrig::as_bytes).cloned().collect()
+        };
+        if names.is_empty() {
+            return confirmation(kind.reply(false), None, self.reply_count(kind));
+        }
+
+        let mut out = BytesMut::new();
+        let mut pubsub = server.pubsub.lock().unwrap();
+        for name in names {
+            let id = self.id;
+            let (mine, registry) = kind.select(self, &mut pubsub);
+            if mine.remove(&name) {
+                remove(registry, &name, id);
+                debug!(channel = ?name, ?kind, "Unsubscribed");
+            }
+            out.extend_from_slice(&confirmation(kind.reply(false), Some(&name), self.reply_count(kind)));
+        }
+        out.freeze()
+    }
+
+    fn reply_count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Shard => self.shard_channels.len(),
+            _ => self.count(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Kind {
+    fn select<'a>(
+        self,
+        subscriber: &'a mut Subscriber,
+        pubsub: &'a mut PubSub,
+    ) -> (&'a mut HashSet<Bytes>, &'a mut Registry) {
+        match self {
+            Kind::Channel => (&mut subscriber.channels, &mut pubsub.channels),
+            Kind::Pattern => (&mut subscriber.patterns, &mut pubsub.patterns),
+            Kind::Shard => (&mut subscriber.shard_channels, &mut pubsub.shard_channels),
+        }
+    }
+
+    fn reply(self, subscribe: bool) -> &'static [u8] {
+        match (self, subscribe) {
+            (Kind::Channel, true) => b"subscribe",
+            (Kind::Channel, false) => b"unsubscribe",
+            (Kind::Pattern, true) => b"psubscribe",
+            (Kind::Pattern, false) => b"punsubscribe",
+            (Kind::Shard, true) => b"ssubscribe",
+            (Kind::Shard, false) => b"sunsubscribe",
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.unsubscribe_all();
+    }
+}
+
+fn remove(registry: &mut Registry, name: &Bytes, id: u64) {
+    if let Some(subscribers) = registry.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            registry.remove(name);
+        }
+    }
+}
+
+fn confirmation(kind: &[u8], name: Option<&Bytes>, count: usize) -> Bytes {
+    let name = name.map_or(RespOrig::NullBulkString, |n| RespOrig::BulkString(n.clone()));
+    RespOrig::Array(vec![
+        RespOrig::BulkString(Bytes::copy_from_slice(kind)),
+        name,
+        RespOrig::Int(count as i64),
+    ])
+    .to_resp()
+}
@@ -0,0 +1,430 @@
+use crate::db::{now_ms, Db, Entry};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::ops::Deref;
+use tracing::{debug, trace, warn};
+
+/// https://rdb.fnordig.de/file_format.html
+const MAGIC: &[u8] = b"REDIS";
+const VERSION: &[u8] = b"0011";
+/// rdb version stamped into DUMP payloads
+const DUMP_VERSION: u16 = 11;
+
+/// a function library, by its source code
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// largest string an LZF blob may claim to expand to, like `proto-max-bulk-len`
+const LZF_MAX_LEN: usize = 512 * 1024 * 1024;
+/// a 3 byte back reference yields at most 264 bytes, so no valid blob expands further
+const LZF_MAX_RATIO: usize = 88;
+
+#[derive(Debug)]
+pub enum RdbError {
+    BadMagic,
+    UnexpectedEnd,
+    UnsupportedType(u8),
+    UnsupportedEncoding(u8),
+    BadLzf,
+    /// DUMP payload with a wrong checksum or a newer rdb version
+    BadPayload,
+}
+
+/// what an rdb file holds
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// live keys only, with the index of their database
+    pub entries: Vec<(usize, Bytes, Entry)>,
+    /// function library sources, see `functions`
+    pub functions: Vec<Bytes>,
+}
+
+/// serializes the databases, by index, and function libraries into an rdb file image
+pub fn encode<D: Deref<Target = Db>>(dbs: &[D], functions: &[Bytes]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(64);
+    buf.put_slice(MAGIC);
+    buf.put_slice(VERSION);
+    put_aux(&mut buf, b"redis-ver", b"7.2.0");
+    put_aux(&mut buf, b"redis-bits", b"64");
+    for code in functions {
+        buf.put_u8(OP_FUNCTION2);
+        put_string(&mut buf, code);
+    }
+
+    for (index, db) in dbs.iter().enumerate().filter(|(_, db)| !db.is_empty()) {
+        buf.put_u8(OP_SELECTDB);
+        put_length(&mut buf, index as u64);
+        let entries: Vec<_> = db.iter().collect();
+        let expires = entries.iter().filter(|(_, e)| e.expires_at.is_some()).count();
+        buf.put_u8(OP_RESIZEDB);
+        put_length(&mut buf, entries.len() as u64);
+        put_length(&mut buf, expires as u64);
+        for (key, entry) in entries {
+            if let Some(at) = entry.expires_at {
+                buf.put_u8(OP_EXPIRETIME_MS);
+                buf.put_u64_le(at);
+            }
+            buf.put_u8(TYPE_STRING);
+            put_string(&mut buf, key);
+            put_string(&mut buf, &entry.value);
+        }
+    }
+
+    buf.put_u8(OP_EOF);
+    // zero checksum means "not computed", redis skips verification for it
+    buf.put_u64_le(0);
+    debug!(size = buf.len(), "Encoded rdb snapshot");
+    buf.freeze()
+}
+
+/// loads an rdb file image
+pub fn decode(data: &[u8]) -> Result<Snapshot, RdbError> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.take(5)? != MAGIC {
+        return Err(RdbError::BadMagic);
+    }
+    let _version = reader.take(4)?;
+
+    let now = now_ms();
+    let mut snapshot = Snapshot::default();
+    let mut expires_at = None;
+    let mut db = 0;
+    loop {
+        let op = reader.byte()?;
+        match op {
+            OP_EOF => break,
+            OP_AUX => {
+                let key = reader.string()?;
+                let value = reader.string()?;
+                trace!(key = ?key, value = ?value, "Rdb aux field");
+            }
+            OP_FUNCTION2 => {
+                snapshot.functions.push(reader.string()?);
+            }
+            OP_SELECTDB => {
+                db = reader.length()? as usize;
+                debug!(db, "Rdb select db");
+            }
+            OP_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OP_EXPIRETIME_MS => {
+                expires_at = Some(u64::from_le_bytes(reader.array::<8>()?));
+            }
+            OP_EXPIRETIME => {
+                expires_at = Some(u32::from_le_bytes(reader.array::<4>()?) as u64 * 1000);
+            }
+            TYPE_STRING => {
+                let key = reader.string()?;
+                let value = reader.string()?;
+                let entry = Entry {
+                    value,
+                    expires_at: expires_at.take(),
+                };
+                if entry.is_expired(now) {
+                    trace!(key = ?key, "Skipping expired key from rdb");
+                } else {
+                    snapshot.entries.push((db, key, entry));
+                }
+            }
+            other => {
+                warn!(value_type = other, "Unsupported rdb value type");
+                return Err(RdbError::UnsupportedType(other));
+            }
+        }
+    }
+    debug!(keys = snapshot.entries.len(), functions = snapshot.functions.len(), "Decoded rdb snapshot");
+    Ok(snapshot)
+}
+
+/// DUMP format: the value as in an rdb file, then the rdb version and a crc64 of the rest
+pub fn dump_value(value: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(value.len() + 16);
+    buf.put_u8(TYPE_STRING);
+    put_string(&mut buf, value);
+    seal(buf)
+}
+
+/// the value of a DUMP payload, as accepted by RESTORE
+pub fn restore_value(payload: &[u8]) -> Result<Bytes, RdbError> {
+    let body = unseal(payload)?;
+    let mut reader = Reader { data: body, pos: 0 };
+    match reader.byte()? {
+        TYPE_STRING => {}
+        other => return Err(RdbError::UnsupportedType(other)),
+    }
+    let value = reader.string()?;
+    if reader.pos != body.len() {
+        return Err(RdbError::BadPayload);
+    }
+    Ok(value)
+}
+
+/// FUNCTION DUMP format: the libraries as in an rdb file, sealed like a DUMP payload
+pub fn dump_functions(functions: &[Bytes]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(64);
+    for code in functions {
+        buf.put_u8(OP_FUNCTION2);
+        put_string(&mut buf, code);
+    }
+    seal(buf)
+}
+
+/// the library sources of a FUNCTION DUMP payload
+pub fn restore_functions(payload: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let body = unseal(payload)?;
+    let mut reader = Reader { data: body, pos: 0 };
+    let mut functions = Vec::new();
+    while reader.pos < body.len() {
+        match reader.byte()? {
+            OP_FUNCTION2 => functions.push(reader.string()?),
+            other => return Err(RdbError::UnsupportedType(other)),
+        }
+    }
+    Ok(functions)
+}
+
+/// appends the rdb version and a crc64 of everything before it
+fn seal(mut buf: BytesMut) -> Bytes {
+    buf.put_u16_le(DUMP_VERSION);
+    let crc = crc64(&buf);
+    buf.put_u64_le(crc);
+    buf.freeze()
+}
+
+/// checks and strips what `seal` added
+fn unseal(payload: &[u8]) -> Result<&[u8], RdbError> {
+    if payload.len() < 10 {
+        return Err(RdbError::BadPayload);
+    }
+    let (body, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let crc = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if version > DUMP_VERSION || crc64(&payload[..payload.len() - 8]) != crc {
+        return Err(RdbError::BadPayload);
+    }
+    Ok(body)
+}
+
+/// crc-64/jones, reflected, as used by redis
+fn crc64(data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+    let mut crc = 0u64;
+    for &byte in data {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn put_aux(buf: &mut BytesMut, key: &[u8], value: &[u8]) {
+    buf.put_u8(OP_AUX);
+    put_string(buf, key);
+    put_string(buf, value);
+}
+
+fn put_length(buf: &mut BytesMut, len: u64) {
+    if len < 1 << 6 {
+        buf.put_u8(len as u8);
+    } else if len < 1 << 14 {
+        buf.put_u16(0x4000 | len as u16);
+    } else if len <= u32::MAX as u64 {
+        buf.put_u8(0x80);
+        buf.put_u32(len as u32);
+    } else {
+        buf.put_u8(0x81);
+        buf.put_u64(len);
+    }
+}
+
+fn put_string(buf: &mut BytesMut, s: &[u8]) {
+    put_length(buf, s.len() as u64);
+    buf.put_slice(s);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len()).ok_or(RdbError::UnexpectedEnd)?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.byte()?;
+        match first >> 6 {
+            0 => Ok(Length::Len((first & 0x3F) as u64)),
+            1 => Ok(Length::Len(
+                (((first & 0x3F) as u64) << 8) | self.byte()? as u64,
+            )),
+            2 if first == 0x80 => Ok(Length::Len(u32::from_be_bytes(self.array::<4>()?) as u64)),
+            2 if first == 0x81 => Ok(Length::Len(u64::from_be_bytes(self.array::<8>()?))),
+            2 => Err(RdbError::UnsupportedEncoding(first)),
+            _ => Ok(Length::Encoded(first & 0x3F)),
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, RdbError> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(enc) => Err(RdbError::UnsupportedEncoding(enc)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Bytes, RdbError> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(Bytes::copy_from_slice(self.take(len as usize)?)),
+            Length::Encoded(ENC_INT8) => Ok(Bytes::from((self.byte()? as i8).to_string())),
+            Length::Encoded(ENC_INT16) => Ok(Bytes::from(
+                i16::from_le_bytes(self.array::<2>()?).to_string(),
+            )),
+            Length::Encoded(ENC_INT32) => Ok(Bytes::from(
+                i32::from_le_bytes(self.array::<4>()?).to_string(),
+            )),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                let compressed = self.take(compressed_len)?;
+                lzf_decompress(compressed, len).map(Bytes::from)
+            }
+            Length::Encoded(other) => Err(RdbError::UnsupportedEncoding(other)),
+        }
+    }
+}
+
+/// http://oldhome.schmorp.de/marc/liblzf.html. `len` comes from the payload, so it is
+/// checked against what `input` could possibly expand to before anything is allocated
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, RdbError> {
+    if len > LZF_MAX_LEN || len > input.len().saturating_mul(LZF_MAX_RATIO) {
+        return Err(RdbError::BadLzf);
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // literal run of ctrl + 1 bytes
+            let run = ctrl + 1;
+            if out.len() + run > len {
+                return Err(RdbError::BadLzf);
+            }
+            let literal = input.get(i..i + run).ok_or(RdbError::BadLzf)?;
+            out.extend_from_slice(literal);
+            i += run;
+        } else {
+            // back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or(RdbError::BadLzf)? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or(RdbError::BadLzf)? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1F) << 8) + low + 1;
+            if back > out.len() || out.len() + run + 2 > len {
+                return Err(RdbError::BadLzf);
+            }
+            let start = out.len() - back;
+            for j in 0..run + 2 {
+                out.push(out[start + j]);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err(RdbError::BadLzf);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a DUMP payload around `body`, with a valid checksum
+    fn sealed(body: &[u8]) -> Vec<u8> {
+        seal(BytesMut::from(body)).to_vec()
+    }
+
+    #[test]
+    fn dump_round_trips() {
+        for value in [&b""[..], b"hello", &[7; 20_000]] {
+            assert_eq!(restore_value(&dump_value(value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn corrupted_payload_is_rejected() {
+        let mut payload = dump_value(b"hello").to_vec();
+        payload[2] ^= 1;
+        assert!(matches!(restore_value(&payload), Err(RdbError::BadPayload)));
+        assert!(matches!(restore_value(b"short"), Err(RdbError::BadPayload)));
+    }
+
+    #[test]
+    fn truncated_payload_is_rejected() {
+        // claims 10 bytes, carries 3
+        let payload = sealed(&[TYPE_STRING, 10, b'a', b'b', b'c']);
+        assert!(matches!(restore_value(&payload), Err(RdbError::UnexpectedEnd)));
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let mut body = vec![TYPE_STRING, 0x81];
+        body.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(restore_value(&sealed(&body)), Err(RdbError::UnexpectedEnd)));
+    }
+
+    #[test]
+    fn oversized_lzf_length_is_rejected() {
+        // 2 compressed bytes that claim to expand to 4 GiB
+        let mut body = vec![TYPE_STRING, 0xC0 | ENC_LZF, 2, 0x80];
+        body.extend_from_slice(&(u32::MAX).to_be_bytes());
+        body.extend_from_slice(&[0, b'a']);
+        assert!(matches!(restore_value(&sealed(&body)), Err(RdbError::BadLzf)));
+        // a literal run longer than the claimed length
+        let body = [TYPE_STRING, 0xC0 | ENC_LZF, 4, 1, 3, b'a', b'b', b'c'];
+        assert!(matches!(restore_value(&sealed(&body)), Err(RdbError::BadLzf)));
+    }
+
+    #[test]
+    fn lzf_back_references_expand() {
+        // literal "ab", then 4 bytes copied from 2 back: "ababab"
+        let body = [TYPE_STRING, 0xC0 | ENC_LZF, 5, 6, 1, b'a', b'b', 2 << 5, 1];
+        assert_eq!(restore_value(&sealed(&body)).unwrap(), &b"ababab"[..]);
+    }
+}
@@ -0,0 +1,928 @@
+use crate::clients::{self, OutputBuffer};
+use crate::config::OutputLimit;
+use crate::commands::Command;
+use crate::connection::Connection;
+use crate::functions;
+use crate::handler::ToResp;
+use crate::parser::{RespOrig, RespParser};
+use crate::persistence;
+use crate::rdb;
+use crate::server::{random_id, Server};
+use crate::tls::{self, Stream};
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+use tokio_util::codec::Decoder;
+use tracing::{debug, error, info, span, trace, Instrument, Level};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkStatus {
+    Connecting,
+    Sync,
+    Connected,
+}
+
+impl LinkStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkStatus::Connected => "up",
+            LinkStatus::Connecting | LinkStatus::Sync => "down",
+        }
+    }
+}
+
+/// link to our master when running as a replica
+#[derive(Debug)]
+pub struct MasterLink {
+    pub host: String,
+    pub port: u16,
+    pub status: LinkStatus,
+    task: AbortHandle,
+}
+
+/// circular buffer holding the tail of the replication stream, so a replica that
+/// briefly lost its link can continue from its offset instead of a full resync
+#[derive(Debug)]
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    size: usize,
+}
+
+impl Backlog {
+    fn new(size: usize) -> Backlog {
+        Backlog {
+            buf: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(self.size)..];
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.size);
+        self.buf.drain(..overflow);
+        self.buf.extend(data);
+    }
+
+    /// first offset still available given the current end offset of the stream
+    fn first_byte_offset(&self, offset: u64) -> u64 {
+        offset + 1 - self.buf.len() as u64
+    }
+
+    /// everything from `from` (the next byte the replica wants) up to `offset`
+    fn since(&self, from: u64, offset: u64) -> Option<Bytes> {
+        if from < self.first_byte_offset(offset) || from > offset + 1 {
+            return None;
+        }
+        let skip = (from - self.first_byte_offset(offset)) as usize;
+        Some(self.buf.iter().skip(skip).copied().collect::<Vec<u8>>().into())
+    }
+}
+
+/// a replica attached to this server
+#[derive(Debug)]
+pub struct ReplicaInfo {
+    pub id: u64,
+    pub ip: String,
+    pub port: u16,
+    pub ack_offset: u64,
+    /// offset the replica reported as fsynced to its AOF (`FACK`)
+    pub aof_ack_offset: u64,
+    pub last_ack: Instant,
+    tx: UnboundedSender<Bytes>,
+    /// the replica connection's, the stream counts against the replica output limit
+    output: Arc<OutputBuffer>,
+}
+
+#[derive(Debug)]
+pub struct ReplicationState {
+    pub replid: String,
+    /// replid of our previous master, still accepted for partial resync up to `second_offset`
+    pub replid2: Option<String>,
+    pub second_offset: u64,
+    /// bytes of replication stream produced (master) or processed (replica)
+    pub offset: u64,
+    pub master: Option<MasterLink>,
+    pub replicas: Vec<ReplicaInfo>,
+    pub backlog: Option<Backlog>,
+    /// database the stream last SELECTed, `None` forces a SELECT before the next write
+    selected_db: Option<usize>,
+    /// woken on every `REPLCONF ACK`, for clients blocked in WAIT / WAITAOF
+    acks: Arc<Notify>,
+    next_replica_id: u64,
+}
+
+impl ReplicationState {
+    pub fn new() -> ReplicationState {
+        ReplicationState {
+            replid: random_id(),
+            replid2: None,
+            second_offset: 0,
+            offset: 0,
+            master: None,
+            replicas: Vec::new(),
+            backlog: None,
+            selected_db: None,
+            acks: Arc::new(Notify::new()),
+            next_replica_id: 1,
+        }
+    }
+
+    pub fn role(&self) -> &'static str {
+        if self.master.is_some() {
+            "slave"
+        } else {
+            "master"
+        }
+    }
+}
+
+impl Default for ReplicationState {
+    fn default() -> Self {
+        ReplicationState::new()
+    }
+}
+
+/// `REPLICAOF host port`: drops the current master link (if any) and starts syncing from a new one
+pub fn replicaof(server: &Arc<Server>, host: String, port: u16) {
+    let mut state = server.replication.lock().unwrap();
+    if let Some(link) = &state.master {
+        if link.host == host && link.port == port {
+            debug!(%host, port, "Already replicating from this master");
+            return;
+        }
+        link.task.abort();
+    }
+    info!(%host, port, "Becoming replica");
+    let task = tokio::spawn(
+        run_replica(server.clone(), host.clone(), port)
+            .instrument(span!(Level::INFO, "replica", master = %format!("{host}:{port}"))),
+    );
+    state.master = Some(MasterLink {
+        host,
+        port,
+        status: LinkStatus::Connecting,
+        task: task.abort_handle(),
+    });
+}
+
+/// `REPLICAOF NO ONE`: stops replicating and keeps the dataset
+pub fn promote(server: &Server) {
+    let mut state = server.replication.lock().unwrap();
+    if let Some(link) = state.master.take() {
+        info!(host = %link.host, port = link.port, "Stopping replication, becoming master");
+        link.task.abort();
+        // replicas of our old master can still continue from us
+        state.replid2 = Some(std::mem::replace(&mut state.replid, random_id()));
+        state.second_offset = state.offset + 1;
+        state.selected_db = None;
+    }
+}
+
+fn set_link_status(server: &Server, status: LinkStatus) {
+    if let Some(link) = server.replication.lock().unwrap().master.as_mut() {
+        link.status = status;
+    }
+}
+
+async fn run_replica(server: Arc<Server>, host: String, port: u16) {
+    loop {
+        set_link_status(&server, LinkStatus::Connecting);
+        match sync_with_master(&server, &host, port).await {
+            Ok(()) => info!("Master closed the replication link"),
+            Err(e) => error!(error = ?e, "Replication link failed"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_master(server: &Arc<Server>, host: &str, port: u16) -> Result<(), Error> {
+    let secure = server.config().tls_replication;
+    info!(secure, "Connecting to master");
+    let mut stream = tls::connect(server, host, port, secure).await?;
+    let mut buf = BytesMut::with_capacity(4096);
+
+    let (listening_port, masterauth) = {
+        let config = server.config();
+        (config.port.to_string(), config.masterauth.clone())
+    };
+    // before PING, which a master with `requirepass` refuses
+    if let Some(password) = &masterauth {
+        handshake_step(&mut stream, &mut buf, &["AUTH", password]).await?;
+    }
+    handshake_step(&mut stream, &mut buf, &["PING"]).await?;
+    handshake_step(
+        &mut stream,
+        &mut buf,
+        &["REPLCONF", "listening-port", &listening_port],
+    )
+    .await?;
+    handshake_step(&mut stream, &mut buf, &["REPLCONF", "capa", "psync2"]).await?;
+
+    set_link_status(server, LinkStatus::Sync);
+    let (replid, offset) = {
+        let state = server.replication.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    };
+    let psync_offset = (offset + 1).to_string();
+    stream
+        .write_all(&command(&["PSYNC", &replid, &psync_offset]))
+        .await?;
+    let reply = read_line(&mut stream, &mut buf).await?;
+    let mut parts = reply.split_whitespace();
+    match parts.next() {
+        Some("+FULLRESYNC") => {
+            let replid = parts.next().unwrap_or_default().to_string();
+            let offset: u64 = parts.next().and_then(|o| o.parse().ok()).unwrap_or(0);
+            info!(%replid, offset, "Full resync from master");
+            let payload = read_rdb(&mut stream, &mut buf).await?;
+            let snapshot = rdb::decode(&payload)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{e:?}")))?;
+            let keys = persistence::restore(server, snapshot);
+            info!(keys, "Loaded dataset from master");
+            let mut state = server.replication.lock().unwrap();
+            state.replid = replid;
+            state.replid2 = None;
+            state.offset = offset;
+            // history before the resync is meaningless to our own replicas, which have to
+            // sync again from the new dataset
+            if let Some(backlog) = state.backlog.as_mut() {
+                backlog.buf.clear();
+            }
+            if !state.replicas.is_empty() {
+                info!(replicas = state.replicas.len(), "Dropping replicas after full resync");
+                state.replicas.clear();
+            }
+        }
+        Some("+CONTINUE") => {
+            let mut state = server.replication.lock().unwrap();
+            if let Some(new_replid) = parts.next().filter(|id| *id != state.replid) {
+                // the master switched history, ours stays valid for our replicas up to here
+                let old = std::mem::replace(&mut state.replid, new_replid.to_string());
+                state.replid2 = Some(old);
+                state.second_offset = state.offset + 1;
+                // they reconnect and learn the new replid, continuing through replid2
+                state.replicas.clear();
+            }
+            info!("Partial resync accepted by master");
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected PSYNC reply: {reply}"),
+            ));
+        }
+    }
+
+    set_link_status(server, LinkStatus::Connected);
+    stream_from_master(server, stream, buf, format!("{host}:{port}")).await
+}
+
+/// applies the command stream sent by the master after the initial sync. the link is a
+/// client of type master meanwhile, which CLIENT KILL may end
+async fn stream_from_master<S: Stream>(
+    server: &Arc<Server>,
+    mut stream: S,
+    mut buf: BytesMut,
+    addr: String,
+) -> Result<(), Error> {
+    // only normal clients count against maxclients, so this is always admitted
+    let client = clients::register(server, clients::Type::Master, addr, String::new(), false)
+        .map_err(|_| Error::other("client table refused the master link"))?;
+    let mut parser = RespParser;
+    // MULTI / EXEC from the master are queued and applied like a client's
+    let (mut conn, _pushes) = Connection::new(server.clone());
+    let mut ack_timer = tokio::time::interval(ACK_INTERVAL);
+    loop {
+        // frames are proxied byte for byte from one copy per read, so the offset advances by
+        // what the master sent and our own replicas see the same bytes
+        let received = buf.clone().freeze();
+        let mut start = 0;
+        while !buf.is_empty() {
+            let before = buf.len();
+            let frame = match parser.decode(&mut buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("{e:?}")));
+                }
+            };
+            let consumed = before - buf.len();
+            let raw = received.slice(start..start + consumed);
+            start += consumed;
+            let is_getack = is_getack(&frame);
+            trace!(command = ?frame, consumed, "Applying replicated command");
+
+            if is_getack {
+                let offset = server.replication.lock().unwrap().offset;
+                stream.write_all(&ack(offset)).await?;
+            } else {
+                // replies to the master are suppressed
+                let _ = frame.handle_replicated(server, &mut conn);
+            }
+            // the master stream is proxied as-is to our own replicas
+            feed(server, &raw);
+        }
+
+        tokio::select! {
+            read = stream.read_buf(&mut buf) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+            }
+            _ = ack_timer.tick() => {
+                let offset = server.replication.lock().unwrap().offset;
+                stream.write_all(&ack(offset)).await?;
+            }
+            _ = client.killed() => {
+                return Err(Error::other("master link killed by CLIENT KILL"));
+            }
+        }
+    }
+}
+
+fn is_getack(frame: &RespOrig) -> bool {
+    match frame {
+        RespOrig::Array(items) if items.len() >= 2 => {
+            let name = items[0].as_bytes();
+            let sub = items[1].as_bytes();
+            name.is_some_and(|n| n.eq_ignore_ascii_case(b"REPLCONF"))
+                && sub.is_some_and(|s| s.eq_ignore_ascii_case(b"GETACK"))
+        }
+        _ => false,
+    }
+}
+
+fn ack(offset: u64) -> Bytes {
+    command(&["REPLCONF", "ACK", &offset.to_string()])
+}
+
+fn command(parts: &[&str]) -> Bytes {
+    parts
+        .iter()
+        .map(|p| RespOrig::BulkString(Bytes::copy_from_slice(p.as_bytes())))
+        .collect::<Vec<_>>()
+        .to_resp()
+}
+
+async fn handshake_step<S: Stream>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    parts: &[&str],
+) -> Result<(), Error> {
+    // never log the password
+    let shown = match parts {
+        ["AUTH", ..] => "AUTH".to_string(),
+        _ => parts.join(" "),
+    };
+    debug!(command = %shown, "Sending handshake command");
+    stream.write_all(&command(parts)).await?;
+    let reply = read_line(stream, buf).await?;
+    if reply.starts_with('-') {
+        return Err(Error::other(format!("master rejected {shown}: {reply}")));
+    }
+    debug!(%reply, "Handshake step acknowledged");
+    Ok(())
+}
+
+/// reads one CRLF terminated line, skipping the bare newlines masters send as keepalive
+async fn read_line<S: Stream>(stream: &mut S, buf: &mut BytesMut) -> Result<String, Error> {
+    loop {
+        while buf.first() == Some(&b'\n') {
+            buf.advance(1);
+        }
+        if let Some(end) = memchr::memmem::find(buf, b"\r\n") {
+            let line = buf.split_to(end + 2);
+            return Ok(String::from_utf8_lossy(&line[..end]).into_owned());
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "master closed connection"));
+        }
+    }
+}
+
+/// rdb payload is sent as `$<len>\r\n<bytes>` without a trailing CRLF
+async fn read_rdb<S: Stream>(stream: &mut S, buf: &mut BytesMut) -> Result<Bytes, Error> {
+    let header = read_line(stream, buf).await?;
+    let len: usize = header
+        .strip_prefix('$')
+        .and_then(|l| l.parse().ok())
+        .ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, format!("bad rdb header: {header}"))
+        })?;
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "master closed during rdb transfer"));
+        }
+    }
+    debug!(size = len, "Received rdb payload");
+    Ok(buf.split_to(len).freeze())
+}
+
+/// appends to the replication stream: grows the offset, fills the backlog and
+/// forwards the bytes to every attached replica
+pub fn feed(server: &Server, data: &[u8]) {
+    let limit = server.clients.limit(clients::Type::Replica);
+    feed_locked(&mut server.replication.lock().unwrap(), data, limit);
+}
+
+/// replicas over `limit` are disconnected and dropped
+fn feed_locked(state: &mut ReplicationState, data: &[u8], limit: OutputLimit) {
+    state.offset += data.len() as u64;
+    if let Some(backlog) = state.backlog.as_mut() {
+        backlog.push(data);
+    }
+    if state.replicas.is_empty() {
+        return;
+    }
+    let data = Bytes::copy_from_slice(data);
+    state.replicas.retain(|r| r.output.push(data.len(), limit) && r.tx.send(data.clone()).is_ok());
+    trace!(offset = state.offset, replicas = state.replicas.len(), "Fed replication stream");
+}
+
+/// propagates a write command executed on this master
+pub fn propagate(server: &Server, command: RespOrig) {
+    {
+        let state = server.replication.lock().unwrap();
+        // a replica only proxies what its master sends, see `stream_from_master`.
+        // until a replica ever attached there is no stream to speak of
+        if state.master.is_some() || state.backlog.is_none() {
+            return;
+        }
+    }
+    feed(server, &command.to_resp());
+}
+
+/// propagates a write to database `db`, preceded by a SELECT when the stream is on another
+pub fn propagate_to_db(server: &Server, db: usize, write: RespOrig) {
+    let limit = server.clients.limit(clients::Type::Replica);
+    // one lock for both, so no other write slips in between the SELECT and its command
+    let mut state = server.replication.lock().unwrap();
+    if state.master.is_some() || state.backlog.is_none() {
+        return;
+    }
+    if state.selected_db != Some(db) {
+        state.selected_db = Some(db);
+        feed_locked(&mut state, &command(&["SELECT", &db.to_string()]), limit);
+    }
+    feed_locked(&mut state, &write.to_resp(), limit);
+}
+
+pub fn is_psync(frame: &RespOrig) -> bool {
+    match frame {
+        RespOrig::Array(items) => items
+            .first()
+            .and_then(RespOrig::as_bytes)
+            .is_some_and(|n| n.eq_ignore_ascii_case(b"PSYNC")),
+        _ => false,
+    }
+}
+
+/// `REPLCONF listening-port <port>` sent by a replica during its handshake
+pub fn listening_port(frame: &RespOrig) -> Option<u16> {
+    let RespOrig::Array(items) = frame else {
+        return None;
+    };
+    let [name, sub, port] = items.as_slice() else {
+        return None;
+    };
+    let is_replconf = name.as_bytes()?.eq_ignore_ascii_case(b"REPLCONF");
+    let is_port = sub.as_bytes()?.eq_ignore_ascii_case(b"listening-port");
+    if !(is_replconf && is_port) {
+        return None;
+    }
+    std::str::from_utf8(port.as_bytes()?).ok()?.parse().ok()
+}
+
+/// master side of `PSYNC replid offset`: answers with `+CONTINUE` and the missing part of the
+/// backlog when possible, otherwise `+FULLRESYNC` followed by a snapshot. the replica is
+/// registered before the lock is released, so no propagated write is missed
+fn psync(
+    server: &Server,
+    frame: &RespOrig,
+    ip: String,
+    port: u16,
+    output: Arc<OutputBuffer>,
+) -> (Bytes, u64, UnboundedReceiver<Bytes>) {
+    let requested = match frame {
+        RespOrig::Array(items) => {
+            let replid = items.get(1).and_then(RespOrig::as_bytes);
+            let offset = items
+                .get(2)
+                .and_then(RespOrig::as_bytes)
+                .and_then(|o| std::str::from_utf8(o).ok())
+                .and_then(|o| o.parse::<i64>().ok());
+            replid.zip(offset)
+        }
+        _ => None,
+    };
+
+    // taken first: FUNCTION commands propagate while holding it
+    let functions = server.functions.lock().unwrap();
+    let mut state = server.replication.lock().unwrap();
+    // writes made while there was no backlog were never recorded, such as those of a
+    // promoted replica, so a new backlog cannot continue anyone
+    let fresh = state.backlog.is_none();
+    if fresh {
+        info!(size = DEFAULT_BACKLOG_SIZE, "Creating replication backlog");
+        state.backlog = Some(Backlog::new(DEFAULT_BACKLOG_SIZE));
+    }
+    let (tx, rx) = unbounded_channel();
+    let id = state.next_replica_id;
+    state.next_replica_id += 1;
+    let offset = state.offset;
+
+    let mut reply = BytesMut::new();
+    let continued = requested.and_then(|(replid, from)| {
+        let from = u64::try_from(from).ok()?;
+        let known = replid == state.replid.as_bytes()
+            || (state.replid2.as_deref().is_some_and(|r| replid == r.as_bytes())
+                && from <= state.second_offset);
+        if !known || fresh {
+            return None;
+        }
+        state.backlog.as_ref()?.since(from, offset)
+    });
+    match continued {
+        Some(missing) => {
+            info!(offset, missing = missing.len(), "Partial resync with replica");
+            reply.extend_from_slice(format!("+CONTINUE {}\r\n", state.replid).as_bytes());
+            reply.extend_from_slice(&missing);
+        }
+        None => {
+            let dbs: Vec<_> = server.dbs.iter().map(|db| db.lock().unwrap()).collect();
+            let snapshot = rdb::encode(&dbs, &functions::sources(&functions));
+            drop(dbs);
+            // the replica starts out on db 0
+            state.selected_db = None;
+            info!(replid = %state.replid, offset, rdb_size = snapshot.len(), "Starting full resync with replica");
+            reply.extend_from_slice(format!("+FULLRESYNC {} {offset}\r\n", state.replid).as_bytes());
+            reply.extend_from_slice(format!("${}\r\n", snapshot.len()).as_bytes());
+            reply.extend_from_slice(&snapshot);
+        }
+    }
+
+    state.replicas.push(ReplicaInfo {
+        id,
+        ip,
+        port,
+        ack_offset: 0,
+        aof_ack_offset: 0,
+        last_ack: Instant::now(),
+        tx,
+        output,
+    });
+    (reply.freeze(), id, rx)
+}
+
+/// turns a client connection that sent `PSYNC` into a replica link: the sync reply is
+/// written, then propagated writes are streamed while `REPLCONF ACK`s are read back
+pub async fn serve_replica<S: Stream>(
+    server: Arc<Server>,
+    mut stream: S,
+    peer: Option<SocketAddr>,
+    mut buf: BytesMut,
+    frame: RespOrig,
+    listening_port: Option<u16>,
+    output: Arc<OutputBuffer>,
+) -> Result<(), Error> {
+    // a replica on the unix socket runs on this host
+    let ip = peer.map_or_else(|| "127.0.0.1".to_string(), |p| p.ip().to_string());
+    let port = listening_port.or(peer.map(|p| p.port())).unwrap_or_default();
+    let (reply, id, mut rx) = psync(&server, &frame, ip, port, output.clone());
+    info!(replica = id, ?peer, port, "Replica attached");
+
+    let result = async {
+        stream.write_all(&reply).await?;
+        let mut parser = RespParser;
+        loop {
+            tokio::select! {
+                data = rx.recv() => match data {
+                    Some(data) => {
+                        stream.write_all(&data).await?;
+                        output.written(data.len());
+                    }
+                    None => return Ok(()),
+                },
+                read = stream.read_buf(&mut buf) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                    while let Some(frame) = parser
+                        .decode(&mut buf)
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{e:?}")))?
+                    {
+                        if let Some((offset, aof_offset)) = ack_offsets(&frame) {
+                            record_ack(&server, id, offset, aof_offset);
+                        } else {
+                            debug!(command = ?frame, "Ignoring command from replica");
+                        }
+                    }
+                }
+            }
+        }
+    }
+    .await;
+
+    server.replication.lock().unwrap().replicas.retain(|r| r.id != id);
+    info!(replica = id, "Replica detached");
+    result
+}
+
+fn error_reply(msg: &str) -> RespOrig {
+    RespOrig::Error(Bytes::copy_from_slice(msg.as_bytes()))
+}
+
+/// checks done before a client command runs: replicas refuse writes (`replica-read-only`)
+/// and, with `replica-serve-stale-data no`, anything but a few commands while the link is
+/// down. a master refuses writes when fewer than `min-replicas-to-write` replicas have
+/// acked within `min-replicas-max-lag` seconds
+pub fn guard(server: &Server, command: &Command) -> Option<RespOrig> {
+    let config = server.config();
+    let state = server.replication.lock().unwrap();
+    match &state.master {
+        Some(link) => {
+            if link.status != LinkStatus::Connected
+                && !config.replica_serve_stale_data
+                && !command.is_stale()
+            {
+                return Some(error_reply("MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."));
+            }
+            if command.is_write() && config.replica_read_only {
+                return Some(error_reply("READONLY You can't write against a read only replica."));
+            }
+        }
+        None => {
+            if command.is_write() && config.min_replicas_to_write > 0 {
+                let max_lag = Duration::from_secs(config.min_replicas_max_lag);
+                let good = state
+                    .replicas
+                    .iter()
+                    .filter(|r| r.last_ack.elapsed() <= max_lag)
+                    .count();
+                if good < config.min_replicas_to_write {
+                    debug!(good, required = config.min_replicas_to_write, "Not enough good replicas");
+                    return Some(error_reply("NOREPLICAS Not enough good replicas to write."));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// `REPLCONF ACK <offset> [FACK <aofoffset>]`
+fn ack_offsets(frame: &RespOrig) -> Option<(u64, Option<u64>)> {
+    let RespOrig::Array(items) = frame else {
+        return None;
+    };
+    let name = items.first()?.as_bytes()?;
+    let sub = items.get(1)?.as_bytes()?;
+    if !(name.eq_ignore_ascii_case(b"REPLCONF") && sub.eq_ignore_ascii_case(b"ACK")) {
+        return None;
+    }
+    let offset = std::str::from_utf8(items.get(2)?.as_bytes()?).ok()?.parse().ok()?;
+    let aof_offset = match (items.get(3).and_then(RespOrig::as_bytes), items.get(4)) {
+        (Some(fack), Some(value)) if fack.eq_ignore_ascii_case(b"FACK") => value
+            .as_bytes()
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse().ok()),
+        _ => None,
+    };
+    Some((offset, aof_offset))
+}
+
+fn record_ack(server: &Server, id: u64, offset: u64, aof_offset: Option<u64>) {
+    let mut state = server.replication.lock().unwrap();
+    if let Some(replica) = state.replicas.iter_mut().find(|r| r.id == id) {
+        trace!(replica = id, offset, ?aof_offset, "Replica ack");
+        replica.ack_offset = offset;
+        // replicas of this server keep no AOF and send plain acks, those count for WAITAOF too
+        replica.aof_ack_offset = aof_offset.unwrap_or(offset);
+        replica.last_ack = Instant::now();
+    }
+    state.acks.notify_waiters();
+}
+
+#[derive(Debug)]
+pub enum WaitRequest {
+    /// WAIT numreplicas timeout
+    Replicas { numreplicas: usize, timeout: u64 },
+    /// WAITAOF numlocal numreplicas timeout
+    Aof { numlocal: usize, numreplicas: usize, timeout: u64 },
+}
+
+
+/// recognizes WAIT / WAITAOF, which block the client instead of replying right away.
+/// a malformed request yields the error reply to send instead
+pub fn wait_request(frame: &RespOrig) -> Option<Result<WaitRequest, RespOrig>> {
+    let RespOrig::Array(items) = frame else {
+        return None;
+    };
+    let name = items.first()?.as_bytes()?.to_ascii_uppercase();
+    let (cmd, argc) = match name.as_slice() {
+        b"WAIT" => ("wait", 3),
+        b"WAITAOF" => ("waitaof", 4),
+        _ => return None,
+    };
+    if items.len() != argc {
+        return Some(Err(error_reply(&format!(
+            "ERR wrong number of arguments for '{cmd}' command"
+        ))));
+    }
+
+    let int = |arg: &RespOrig| -> Option<i64> {
+        std::str::from_utf8(arg.as_bytes()?).ok()?.parse().ok()
+    };
+    let Some(timeout) = int(&items[argc - 1]) else {
+        return Some(Err(error_reply("ERR timeout is not an integer or out of range")));
+    };
+    if timeout < 0 {
+        return Some(Err(error_reply("ERR timeout is negative")));
+    }
+    let counts: Option<Vec<usize>> = items[1..argc - 1]
+        .iter()
+        .map(|arg| int(arg).map(|n| n.max(0) as usize))
+        .collect();
+    let Some(counts) = counts else {
+        return Some(Err(error_reply("ERR value is not an integer or out of range")));
+    };
+
+    let timeout = timeout as u64;
+    Some(Ok(match counts.as_slice() {
+        [numreplicas] => WaitRequest::Replicas { numreplicas: *numreplicas, timeout },
+        [numlocal, numreplicas] => WaitRequest::Aof {
+            numlocal: *numlocal,
+            numreplicas: *numreplicas,
+            timeout,
+        },
+        _ => unreachable!("argc checked above"),
+    }))
+}
+
+/// what a WAIT / WAITAOF is waiting for
+struct WaitTarget {
+    offset: u64,
+    numreplicas: usize,
+    timeout: u64,
+    aof: bool,
+    acks: Arc<Notify>,
+}
+
+impl WaitTarget {
+    fn new(server: &Server, request: WaitRequest) -> Result<WaitTarget, RespOrig> {
+        let (numreplicas, timeout, aof) = match request {
+            WaitRequest::Replicas { numreplicas, timeout } => (numreplicas, timeout, false),
+            WaitRequest::Aof { numlocal, numreplicas, timeout } => {
+                if numlocal > 0 {
+                    // there is no append only file to fsync locally
+                    return Err(error_reply(
+                        "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled.",
+                    ));
+                }
+                (numreplicas, timeout, true)
+            }
+        };
+
+        let state = server.replication.lock().unwrap();
+        if state.master.is_some() {
+            return Err(error_reply(if aof {
+                "ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated."
+            } else {
+                "ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated."
+            }));
+        }
+        Ok(WaitTarget { offset: state.offset, numreplicas, timeout, aof, acks: state.acks.clone() })
+    }
+
+    fn acked(&self, server: &Server) -> usize {
+        let state = server.replication.lock().unwrap();
+        state
+            .replicas
+            .iter()
+            .filter(|r| if self.aof { r.aof_ack_offset } else { r.ack_offset } >= self.offset)
+            .count()
+    }
+
+    fn reply(&self, count: usize) -> RespOrig {
+        if self.aof {
+            RespOrig::Array(vec![RespOrig::Int(0), RespOrig::Int(count as i64)])
+        } else {
+            RespOrig::Int(count as i64)
+        }
+    }
+}
+
+/// WAIT inside a transaction cannot block: replies with the replicas that already
+/// acknowledged everything
+pub fn wait_now(server: &Server, request: WaitRequest) -> RespOrig {
+    match WaitTarget::new(server, request) {
+        Ok(target) => target.reply(target.acked(server)),
+        Err(reply) => reply,
+    }
+}
+
+/// blocks until enough replicas acknowledged everything written so far, or the timeout
+/// (milliseconds, 0 waits forever) expires. replies with what was achieved either way
+pub async fn wait(server: &Server, request: WaitRequest) -> RespOrig {
+    let wait = match WaitTarget::new(server, request) {
+        Ok(target) => target,
+        Err(reply) => return reply,
+    };
+    let WaitTarget { offset: target, numreplicas, timeout, aof, ref acks } = wait;
+    let acked = |server: &Server| wait.acked(server);
+    let reply = |count: usize| wait.reply(count);
+
+    let count = acked(server);
+    if count >= numreplicas {
+        return reply(count);
+    }
+
+    debug!(target, numreplicas, timeout, aof, "Blocking client until replicas acknowledge");
+    feed(server, &command(&["REPLCONF", "GETACK", "*"]));
+    let deadline = (timeout > 0).then(|| tokio::time::Instant::now() + Duration::from_millis(timeout));
+    loop {
+        let notified = acks.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let count = acked(server);
+        if count >= numreplicas {
+            return reply(count);
+        }
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    let count = acked(server);
+                    debug!(count, "WAIT timed out");
+                    return reply(count);
+                }
+            }
+            None => notified.await,
+        }
+    }
+}
+
+/// `INFO replication` section
+pub fn info(server: &Server) -> String {
+    let state = server.replication.lock().unwrap();
+    let mut out = String::from("# Replication\r\n");
+    out.push_str(&format!("role:{}\r\n", state.role()));
+    if let Some(link) = &state.master {
+        out.push_str(&format!("master_host:{}\r\n", link.host));
+        out.push_str(&format!("master_port:{}\r\n", link.port));
+        out.push_str(&format!("master_link_status:{}\r\n", link.status.as_str()));
+        out.push_str(&format!(
+            "master_sync_in_progress:{}\r\n",
+            (link.status == LinkStatus::Sync) as u8
+        ));
+        out.push_str(&format!("slave_repl_offset:{}\r\n", state.offset));
+    }
+    out.push_str(&format!("connected_slaves:{}\r\n", state.replicas.len()));
+    for (i, replica) in state.replicas.iter().enumerate() {
+        out.push_str(&format!(
+            "slave{i}:ip={},port={},state=online,offset={},lag={}\r\n",
+            replica.ip,
+            replica.port,
+            replica.ack_offset,
+            replica.last_ack.elapsed().as_secs()
+        ));
+    }
+    out.push_str(&format!("master_replid:{}\r\n", state.replid));
+    out.push_str(&format!(
+        "master_replid2:{}\r\n",
+        state.replid2.as_deref().unwrap_or("0000000000000000000000000000000000000000")
+    ));
+    out.push_str(&format!("master_repl_offset:{}\r\n", state.offset));
+    out.push_str(&format!(
+        "second_repl_offset:{}\r\n",
+        if state.replid2.is_some() { state.second_offset as i64 } else { -1 }
+    ));
+    match &state.backlog {
+        Some(backlog) => {
+            out.push_str("repl_backlog_active:1\r\n");
+            out.push_str(&format!("repl_backlog_size:{}\r\n", backlog.size));
+            out.push_str(&format!(
+                "repl_backlog_first_byte_offset:{}\r\n",
+                backlog.first_byte_offset(state.offset)
+            ));
+            out.push_str(&format!("repl_backlog_histlen:{}\r\n", backlog.buf.len()));
+        }
+        None => {
+            out.push_str("repl_backlog_active:0\r\n");
+            out.push_str(&format!("repl_backlog_size:{DEFAULT_BACKLOG_SIZE}\r\n"));
+            out.push_str("repl_backlog_first_byte_offset:0\r\n");
+            out.push_str("repl_backlog_histlen:0\r\n");
+        }
+    }
+    out
+}
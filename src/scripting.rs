@@ -0,0 +1,505 @@
+use crate::acl;
+use crate::commands;
+use crate::connection::Connection;
+use crate::handler::{self, arg_int, arg_str, error, ok, wrong_arity};
+use crate::parser::{RespOrig, RespParser};
+use crate::replication;
+use crate::server::Server;
+use bytes::{Bytes, BytesMut};
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value};
+use sha1::{Digest, Sha1};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use tokio_util::codec::Decoder;
+use tracing::{debug, info, trace};
+
+/// how often a command waiting on a running script checks whether it should give up with BUSY
+const BUSY_POLL: Duration = Duration::from_millis(1);
+/// lua instructions between checks for SCRIPT KILL
+const KILL_CHECK_INSTRUCTIONS: u32 = 1000;
+
+/// the lua VM and every script it was handed, by sha1
+#[derive(Debug)]
+pub struct Scripting {
+    lua: Lua,
+    scripts: HashMap<String, Bytes>,
+}
+
+impl Default for Scripting {
+    fn default() -> Self {
+        Scripting { lua: new_vm(), scripts: HashMap::new() }
+    }
+}
+
+/// the script holding `exec_lock` right now
+#[derive(Debug)]
+pub struct RunningScript {
+    started: Instant,
+    killed: Arc<AtomicBool>,
+    wrote: bool,
+}
+
+pub(crate) fn new_vm() -> Lua {
+    Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())
+        .expect("lua standard libraries load")
+}
+
+pub fn sha1_hex(body: &[u8]) -> String {
+    Sha1::digest(body).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// a command error raised by `redis.call`, replied as is rather than as a lua error
+#[derive(Debug)]
+struct CommandError(String);
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// the shared side of `exec_lock`, for a regular command. once a script has been running
+/// for longer than `busy-reply-threshold`, commands stop waiting for it and get BUSY
+pub fn lock_shared(server: &Server) -> Result<RwLockReadGuard<'_, ()>, RespOrig> {
+    acquire(server, || server.exec_lock.try_read().ok())
+}
+
+/// the exclusive side of `exec_lock`, for EXEC and scripts
+pub fn lock_exclusive(server: &Server) -> Result<RwLockWriteGuard<'_, ()>, RespOrig> {
+    acquire(server, || server.exec_lock.try_write().ok())
+}
+
+fn acquire<G>(server: &Server, mut try_lock: impl FnMut() -> Option<G>) -> Result<G, RespOrig> {
+    if let Some(guard) = try_lock() {
+        return Ok(guard);
+    }
+    // waiting must not hold up the other connections on this worker, SCRIPT KILL among them
+    tokio::task::block_in_place(|| loop {
+        if let Some(guard) = try_lock() {
+            return Ok(guard);
+        }
+        let threshold = Duration::from_millis(server.config().busy_reply_threshold);
+        let busy = server
+            .running_script
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|script| script.started.elapsed() >= threshold);
+        if busy {
+            return Err(error(
+                "BUSY Redis is busy running a script. You can only call SCRIPT KILL or FUNCTION KILL or SHUTDOWN NOSAVE.",
+            ));
+        }
+        std::thread::sleep(BUSY_POLL);
+    })
+}
+
+/// SCRIPT KILL and FUNCTION KILL have to get through while the script holds `exec_lock`
+pub fn is_kill(items: &[RespOrig]) -> bool {
+    let name = items.first().and_then(arg_str);
+    let sub = items.get(1).and_then(arg_str);
+    items.len() == 2
+        && name.is_some_and(|n| n.eq_ignore_ascii_case("SCRIPT") || n.eq_ignore_ascii_case("FUNCTION"))
+        && sub.is_some_and(|s| s.eq_ignore_ascii_case("KILL"))
+}
+
+pub(crate) fn kill(server: &Server) -> RespOrig {
+    let running = server.running_script.lock().unwrap();
+    match running.as_ref() {
+        None => error("NOTBUSY No scripts in execution right now."),
+        Some(script) if script.wrote => error(
+            "UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.",
+        ),
+        Some(script) => {
+            info!(elapsed = ?script.started.elapsed(), "Killing running script");
+            script.killed.store(true, Ordering::Relaxed);
+            ok()
+        }
+    }
+}
+
+/// `SCRIPT LOAD|EXISTS|FLUSH|KILL`
+pub fn command(args: &[RespOrig], server: &Server) -> RespOrig {
+    let Some(sub) = args.first().and_then(arg_str).map(str::to_uppercase) else {
+        return wrong_arity("script");
+    };
+    let args = &args[1..];
+    match sub.as_str() {
+        "LOAD" => {
+            let [body] = args else {
+                return wrong_arity("script|load");
+            };
+            let Some(body) = body.as_bytes() else {
+                return wrong_arity("script|load");
+            };
+            let mut scripting = server.scripting.lock().unwrap();
+            if let Err(e) = scripting.lua.load(body.as_ref()).set_name("@user_script").into_function() {
+                return compile_error(&e);
+            }
+            let sha = sha1_hex(body);
+            debug!(%sha, "Script loaded");
+            scripting.scripts.insert(sha.clone(), body.clone());
+            RespOrig::BulkString(Bytes::from(sha))
+        }
+        "EXISTS" if !args.is_empty() => {
+            let scripting = server.scripting.lock().unwrap();
+            RespOrig::Array(
+                args.iter()
+                    .map(|sha| {
+                        let known = arg_str(sha)
+                            .is_some_and(|sha| scripting.scripts.contains_key(&sha.to_lowercase()));
+                        RespOrig::Int(known as i64)
+                    })
+                    .collect(),
+            )
+        }
+        "FLUSH" => {
+            match args {
+                [] => {}
+                [mode] if arg_str(mode).is_some_and(|m| m.eq_ignore_ascii_case("SYNC") || m.eq_ignore_ascii_case("ASYNC")) => {}
+                _ => return error("ERR SCRIPT FLUSH only support SYNC|ASYNC option"),
+            }
+            let mut scripting = server.scripting.lock().unwrap();
+            info!(scripts = scripting.scripts.len(), "Flushing script cache");
+            *scripting = Scripting::default();
+            ok()
+        }
+        "KILL" if args.is_empty() => kill(server),
+        "EXISTS" | "KILL" => wrong_arity(&format!("script|{}", sub.to_lowercase())),
+        _ => error(&format!(
+            "ERR unknown subcommand '{}'. Try SCRIPT HELP.",
+            sub.to_lowercase()
+        )),
+    }
+}
+
+/// EVAL / EVALSHA
+pub fn eval(items: &[RespOrig], server: &Arc<Server>, conn: &Connection) -> RespOrig {
+    let by_sha = items.first().and_then(arg_str).is_some_and(|n| n.eq_ignore_ascii_case("EVALSHA"));
+    let Some(source) = items.get(1).and_then(RespOrig::as_bytes) else {
+        return wrong_arity(if by_sha { "evalsha" } else { "eval" });
+    };
+    let (keys, argv) = match split_keys(items) {
+        Ok(split) => split,
+        Err(reply) => return reply,
+    };
+    let _exclusive = match lock_for(server, conn) {
+        Ok(guard) => guard,
+        Err(busy) => return busy,
+    };
+
+    let mut scripting = server.scripting.lock().unwrap();
+    let (sha, body) = if by_sha {
+        let sha = String::from_utf8_lossy(source).to_lowercase();
+        match scripting.scripts.get(&sha) {
+            Some(body) => (sha, body.clone()),
+            None => return error("NOSCRIPT No matching script. Please use EVAL."),
+        }
+    } else {
+        let sha = sha1_hex(source);
+        scripting.scripts.insert(sha.clone(), source.clone());
+        (sha, source.clone())
+    };
+    let function = match scripting.lua.load(body.as_ref()).set_name("@user_script").into_function() {
+        Ok(function) => function,
+        Err(e) => return compile_error(&e),
+    };
+    let invocation = Invocation { label: &sha, keys, argv, read_only: false };
+    run(&scripting.lua, server, conn, invocation, |lua, keys, argv| {
+        let globals = lua.globals();
+        globals.set("KEYS", keys)?;
+        globals.set("ARGV", argv)?;
+        function.call(())
+    })
+}
+
+/// `numkeys key [key ...] arg [arg ...]` as the third argument onwards of EVAL and FCALL
+pub(crate) fn split_keys(items: &[RespOrig]) -> Result<(&[RespOrig], &[RespOrig]), RespOrig> {
+    let Some(numkeys) = items.get(2).and_then(arg_int) else {
+        return Err(error("ERR value is not an integer or out of range"));
+    };
+    if numkeys < 0 {
+        return Err(error("ERR Number of keys can't be negative"));
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > items.len() - 3 {
+        return Err(error("ERR Number of keys can't be greater than number of args"));
+    }
+    Ok((&items[3..3 + numkeys], &items[3 + numkeys..]))
+}
+
+/// the exclusive lock a script runs under. inside EXEC the transaction already holds it
+pub(crate) fn lock_for<'a>(
+    server: &'a Server,
+    conn: &Connection,
+) -> Result<Option<RwLockWriteGuard<'a, ()>>, RespOrig> {
+    if conn.in_exec {
+        return Ok(None);
+    }
+    lock_exclusive(server).map(Some)
+}
+
+/// one run of a script or function
+pub(crate) struct Invocation<'a> {
+    /// names the script in error replies: its sha1, or the function name
+    pub label: &'a str,
+    pub keys: &'a [RespOrig],
+    pub argv: &'a [RespOrig],
+    /// `no-writes`: `redis.call` refuses write commands
+    pub read_only: bool,
+}
+
+/// state the `redis.call` callbacks share while a script runs
+struct CallContext<'a> {
+    server: &'a Arc<Server>,
+    /// the client the script's commands run as. it never takes `exec_lock`, the script has it
+    conn: Connection,
+    /// whether our writes are wrapped in MULTI / EXEC here rather than by an outer EXEC
+    wrap: bool,
+    read_only: bool,
+}
+
+/// runs a script with the `redis` library in place, `exec_lock` already held by the caller.
+/// `body` gets the KEYS and ARGV tables. the script runs alone: every other command waits
+/// for it, or gets BUSY once it runs for too long. its writes reach the replicas as the
+/// commands it ran, wrapped in MULTI / EXEC
+pub(crate) fn run<'lua>(
+    lua: &'lua Lua,
+    server: &Arc<Server>,
+    conn: &Connection,
+    invocation: Invocation,
+    body: impl FnOnce(&'lua Lua, Table<'lua>, Table<'lua>) -> mlua::Result<Value<'lua>>,
+) -> RespOrig {
+    let Invocation { label, keys, argv, read_only } = invocation;
+    let wrap = !conn.in_exec;
+    let killed = Arc::new(AtomicBool::new(false));
+    *server.running_script.lock().unwrap() = Some(RunningScript {
+        started: Instant::now(),
+        killed: killed.clone(),
+        wrote: false,
+    });
+    lua.set_hook(
+        HookTriggers { every_nth_instruction: Some(KILL_CHECK_INSTRUCTIONS), ..HookTriggers::new() },
+        move |_, _| {
+            if killed.load(Ordering::Relaxed) {
+                return Err(mlua::Error::external(CommandError(
+                    "ERR Script killed by user with SCRIPT KILL...".to_string(),
+                )));
+            }
+            Ok(())
+        },
+    );
+    debug!(script = label, keys = keys.len(), args = argv.len(), read_only, "Running script");
+
+    let (mut script_conn, _pushes) = Connection::new(server.clone());
+    script_conn.in_exec = true;
+    script_conn.db = conn.db;
+    script_conn.user = conn.user.clone();
+    let context = RefCell::new(CallContext { server, conn: script_conn, wrap, read_only });
+    let result = tokio::task::block_in_place(|| {
+        lua.scope(|scope| {
+            let redis = lua.create_table()?;
+            redis.set("call", scope.create_function(|lua, args| call(lua, &context, args, true))?)?;
+            redis.set("pcall", scope.create_function(|lua, args| call(lua, &context, args, false))?)?;
+            redis.set(
+                "error_reply",
+                lua.create_function(|lua, msg: mlua::String| reply_table(lua, "err", msg))?,
+            )?;
+            redis.set(
+                "status_reply",
+                lua.create_function(|lua, msg: mlua::String| reply_table(lua, "ok", msg))?,
+            )?;
+            redis.set(
+                "sha1hex",
+                lua.create_function(|_, body: mlua::String| Ok(sha1_hex(body.as_bytes())))?,
+            )?;
+            lua.globals().set("redis", redis)?;
+
+            body(lua, string_table(lua, keys)?, string_table(lua, argv)?).map(|value| to_reply(&value))
+        })
+    });
+    lua.remove_hook();
+
+    let wrote = server.running_script.lock().unwrap().take().is_some_and(|s| s.wrote);
+    if wrote && wrap {
+        replication::propagate(server, RespOrig::Array(vec![bulk("EXEC")]));
+    }
+    trace!(script = label, wrote, "Script finished");
+    match result {
+        Ok(reply) => reply,
+        Err(e) => script_error(&e, label),
+    }
+}
+
+/// `redis.call` raises command errors, `redis.pcall` hands them back as `{err = ...}`
+fn call<'lua>(
+    lua: &'lua Lua,
+    context: &RefCell<CallContext>,
+    args: MultiValue<'lua>,
+    raise: bool,
+) -> mlua::Result<Value<'lua>> {
+    let failed = |msg: &str| -> mlua::Result<Value<'lua>> {
+        if raise {
+            Err(mlua::Error::external(CommandError(msg.to_string())))
+        } else {
+            reply_table(lua, "err", lua.create_string(msg)?).map(Value::Table)
+        }
+    };
+
+    let mut items = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            Value::String(s) => items.push(RespOrig::BulkString(Bytes::copy_from_slice(s.as_bytes()))),
+            Value::Integer(_) | Value::Number(_) => {
+                let s = lua.coerce_string(arg)?.expect("numbers coerce to strings");
+                items.push(RespOrig::BulkString(Bytes::copy_from_slice(s.as_bytes())));
+            }
+            _ => return failed("ERR Lua redis lib command arguments must be strings or integers"),
+        }
+    }
+    if !handler::rename_command(context.borrow().server, &mut items) {
+        return failed("ERR Unknown Redis command called from script");
+    }
+    let Some(name) = items.first().and_then(arg_str).map(str::to_uppercase) else {
+        return failed("ERR Please specify at least one argument for this redis lib call");
+    };
+    let Some(spec) = commands::lookup(&name) else {
+        return failed("ERR Unknown Redis command called from script");
+    };
+    let argc = items.len() as i32;
+    if (spec.arity > 0 && argc != spec.arity) || argc < -spec.arity {
+        return failed("ERR Wrong number of args calling Redis command from script");
+    }
+    if spec.is_no_script() {
+        return failed("ERR This Redis command is not allowed from script");
+    }
+
+    let mut context = context.borrow_mut();
+    let server = context.server;
+    if let Err(RespOrig::Error(denied)) = acl::check_call(server, &context.conn, spec, &items, "lua") {
+        return failed(&String::from_utf8_lossy(&denied));
+    }
+    if spec.is_write() && context.read_only {
+        return failed("ERR Write commands are not allowed from read-only scripts.");
+    }
+    if spec.is_write() {
+        let mut running = server.running_script.lock().unwrap();
+        let first_write = running.as_mut().is_some_and(|s| !std::mem::replace(&mut s.wrote, true));
+        drop(running);
+        if first_write && context.wrap {
+            replication::propagate(server, RespOrig::Array(vec![bulk("MULTI")]));
+        }
+    }
+    trace!(command = %name, "Script calling command");
+    let reply = RespOrig::Array(items).handle_scripted(server, &mut context.conn);
+    drop(context);
+
+    let reply = match reply {
+        Some(bytes) => RespParser.decode(&mut BytesMut::from(&bytes[..])).ok().flatten(),
+        None => None,
+    };
+    match reply {
+        Some(RespOrig::Error(msg)) => failed(&String::from_utf8_lossy(&msg)),
+        Some(reply) => to_lua(lua, reply),
+        None => Ok(Value::Boolean(false)),
+    }
+}
+
+fn string_table<'lua>(lua: &'lua Lua, items: &[RespOrig]) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table_with_capacity(items.len(), 0)?;
+    for (i, item) in items.iter().enumerate() {
+        let bytes = item.as_bytes().map(|b| b.as_ref()).unwrap_or_default();
+        table.raw_set(i + 1, lua.create_string(bytes)?)?;
+    }
+    Ok(table)
+}
+
+fn reply_table<'lua>(lua: &'lua Lua, field: &str, msg: mlua::String<'lua>) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, msg)?;
+    Ok(table)
+}
+
+/// redis reply to lua value: integers become numbers, bulk strings strings, arrays tables,
+/// status and error replies `{ok = ...}` / `{err = ...}` and nulls `false`
+fn to_lua(lua: &Lua, reply: RespOrig) -> mlua::Result<Value<'_>> {
+    Ok(match reply {
+        RespOrig::Int(n) => Value::Number(n as f64),
+        RespOrig::BulkString(bytes) => Value::String(lua.create_string(&bytes[..])?),
+        RespOrig::String(status) => Value::Table(reply_table(lua, "ok", lua.create_string(&status[..])?)?),
+        RespOrig::Error(msg) => Value::Table(reply_table(lua, "err", lua.create_string(&msg[..])?)?),
+        RespOrig::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        RespOrig::NullBulkString | RespOrig::NullArray => Value::Boolean(false),
+    })
+}
+
+/// lua value to redis reply: numbers are truncated to integers, `true` is 1, `false` and
+/// `nil` are null, tables are arrays up to the first nil unless they carry `ok` or `err`
+fn to_reply(value: &Value) -> RespOrig {
+    match value {
+        Value::Integer(n) => RespOrig::Int(*n),
+        Value::Number(n) => RespOrig::Int(*n as i64),
+        Value::Boolean(true) => RespOrig::Int(1),
+        Value::String(s) => RespOrig::BulkString(Bytes::copy_from_slice(s.as_bytes())),
+        Value::Table(table) => {
+            if let Ok(Value::String(msg)) = table.raw_get::<_, Value>("err") {
+                return RespOrig::Error(Bytes::copy_from_slice(msg.as_bytes()));
+            }
+            if let Ok(Value::String(status)) = table.raw_get::<_, Value>("ok") {
+                return RespOrig::String(Bytes::copy_from_slice(status.as_bytes()));
+            }
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(item) => items.push(to_reply(&item)),
+                }
+            }
+            RespOrig::Array(items)
+        }
+        Value::Error(e) => script_error(e, ""),
+        _ => RespOrig::NullBulkString,
+    }
+}
+
+pub(crate) fn compile_error(e: &mlua::Error) -> RespOrig {
+    let message = match e {
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        other => other.to_string(),
+    };
+    error(&format!("ERR Error compiling script (new function): {message}"))
+}
+
+/// errors from `redis.call` are replied as the command gave them, lua errors get the
+/// script they came from appended
+pub(crate) fn script_error(e: &mlua::Error, sha: &str) -> RespOrig {
+    let suffix = if sha.is_empty() { String::new() } else { format!(" script: {sha}") };
+    match e {
+        mlua::Error::CallbackError { cause, .. } => script_error(cause, sha),
+        mlua::Error::ExternalError(inner) => match inner.downcast_ref::<CommandError>() {
+            Some(CommandError(msg)) => error(msg),
+            None => error(&format!("ERR {inner}{suffix}")),
+        },
+        // lua appends a traceback, an error reply has to stay on one line
+        mlua::Error::RuntimeError(msg) => {
+            let msg = msg.lines().next().unwrap_or_default();
+            error(&format!("ERR {msg}{suffix}"))
+        }
+        other => error(&format!("ERR {other}{suffix}")),
+    }
+}
+
+fn bulk(s: &'static str) -> RespOrig {
+    RespOrig::BulkString(Bytes::from_static(s.as_bytes()))
+}
@@ -0,0 +1,150 @@
+use crate::acl::Acl;
+use crate::clients::Clients;
+use crate::cluster::ClusterState;
+use crate::commands;
+use crate::config::Config;
+use crate::db::Db;
+use crate::functions::Functions;
+use crate::persistence::Persistence;
+use crate::pubsub::PubSub;
+use crate::replication::ReplicationState;
+use crate::scripting::{RunningScript, Scripting};
+use crate::tls::Tls;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::info;
+
+/// state shared by every connection task
+#[derive(Debug)]
+pub struct Server {
+    /// see `config()`, CONFIG SET changes it
+    config: RwLock<Config>,
+    pub stats: Stats,
+    /// see `clients`
+    pub clients: Clients,
+    /// present when anything is configured to use TLS
+    pub tls: Option<Tls>,
+    /// users and their permissions, see `acl`
+    pub acl: Mutex<Acl>,
+    /// `rename-command` names, see `commands::renames`
+    pub renamed_commands: HashMap<String, Option<&'static str>>,
+    /// the logical databases. several are always locked in ascending index order
+    pub dbs: Vec<Mutex<Db>>,
+    pub replication: Mutex<ReplicationState>,
+    /// present only with `cluster-enabled yes`
+    pub cluster: Option<Mutex<ClusterState>>,
+    pub pubsub: Mutex<PubSub>,
+    /// commands hold it shared, EXEC exclusively so a transaction runs uninterrupted
+    pub exec_lock: RwLock<()>,
+    pub scripting: Mutex<Scripting>,
+    pub running_script: Mutex<Option<RunningScript>>,
+    pub functions: Mutex<Functions>,
+    /// SAVE and BGSAVE bookkeeping
+    pub persistence: Persistence,
+}
+
+impl Server {
+    pub fn new(config: Config, tls: Option<Tls>) -> Result<Arc<Server>, Error> {
+        info!(port = config.port, "Initializing server state");
+        let cluster = if config.cluster_enabled {
+            let state = ClusterState::load(
+                PathBuf::from(&config.cluster_config_file),
+                config.announce_ip(),
+                config.port,
+                config.cluster_node_timeout,
+            )?;
+            Some(Mutex::new(state))
+        } else {
+            None
+        };
+        let acl = Acl::load(&config)?;
+        let renamed_commands = commands::renames(&config.rename_commands);
+        let clients = Clients::new(config.output_limits);
+        let dbs = (0..config.databases).map(|_| Mutex::new(Db::default())).collect();
+        Ok(Arc::new(Server {
+            config: RwLock::new(config),
+            stats: Stats::default(),
+            clients,
+            tls,
+            acl: Mutex::new(acl),
+            renamed_commands,
+            dbs,
+            replication: Mutex::new(ReplicationState::new()),
+            cluster,
+            pubsub: Mutex::new(PubSub::default()),
+            exec_lock: RwLock::new(()),
+            scripting: Mutex::new(Scripting::default()),
+            running_script: Mutex::new(None),
+            functions: Mutex::new(Functions::default()),
+            persistence: Persistence::default(),
+        }))
+    }
+
+    pub fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap()
+    }
+
+    pub fn config_mut(&self) -> RwLockWriteGuard<'_, Config> {
+        self.config.write().unwrap()
+    }
+
+    /// database `index`, which the caller checked against `config.databases`
+    pub fn db(&self, index: usize) -> MutexGuard<'_, Db> {
+        self.dbs[index].lock().unwrap()
+    }
+}
+
+/// counters for INFO stats, zeroed by CONFIG RESETSTAT
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub connections_received: AtomicU64,
+    pub commands_processed: AtomicU64,
+    pub keyspace_hits: AtomicU64,
+    pub keyspace_misses: AtomicU64,
+    pub expired_keys: AtomicU64,
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64, by: u64) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for counter in self.counters() {
+            counter.1.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// INFO field names with their counters
+    pub fn counters(&self) -> [(&'static str, &AtomicU64); 5] {
+        [
+            ("total_connections_received", &self.connections_received),
+            ("total_commands_processed", &self.commands_processed),
+            ("keyspace_hits", &self.keyspace_hits),
+            ("keyspace_misses", &self.keyspace_misses),
+            ("expired_keys", &self.expired_keys),
+        ]
+    }
+}
+
+/// 40 hex chars, the format redis uses for replication and node ids
+pub fn random_id() -> String {
+    let mut id = String::with_capacity(40);
+    while id.len() < 40 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(id.len());
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(40);
+    id
+}
+
+/// cheap randomness for jitter and sampling, not for anything security related
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
@@ -0,0 +1,125 @@
+use crate::config::Config;
+use crate::server::Server;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, info};
+
+/// any transport a connection runs over: TCP, TLS or a unix socket
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// the certificates of `tls-cert-file` and friends, ready for both sides of a handshake
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    /// for links we open ourselves: to our master and to other cluster nodes. `None`
+    /// without `tls-ca-cert-file`, since their certificates could not be verified
+    connector: Option<TlsConnector>,
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls").field("connector", &self.connector.is_some()).finish_non_exhaustive()
+    }
+}
+
+impl Tls {
+    /// `None` unless the tls port, replication or the cluster bus use TLS
+    pub fn load(config: &Config) -> Result<Option<Tls>, Error> {
+        if config.tls_port == 0 && !config.tls_replication && !config.tls_cluster {
+            return Ok(None);
+        }
+        let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+            return Err(invalid("TLS needs tls-cert-file and tls-key-file"));
+        };
+        let certs = load_certs(cert_file)?;
+        let key = PrivateKeyDer::from_pem_file(key_file)
+            .map_err(|e| invalid(format!("can't load private key {}: {e}", key_file.display())))?;
+        let roots = match &config.tls_ca_cert_file {
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_file)? {
+                    roots.add(cert).map_err(|e| invalid(format!("bad CA certificate: {e}")))?;
+                }
+                Some(Arc::new(roots))
+            }
+            None => None,
+        };
+
+        let verifier = match (config.tls_auth_clients.as_str(), &roots) {
+            ("no", _) => None,
+            (_, None) => return Err(invalid("tls-auth-clients needs tls-ca-cert-file")),
+            ("optional", Some(roots)) => Some(WebPkiClientVerifier::builder(roots.clone()).allow_unauthenticated().build()),
+            (_, Some(roots)) => Some(WebPkiClientVerifier::builder(roots.clone()).build()),
+        };
+        let builder = ServerConfig::builder();
+        let builder = match verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier.map_err(|e| invalid(e.to_string()))?),
+            None => builder.with_no_client_auth(),
+        };
+        let server_config = builder
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(|e| invalid(format!("certificate and key do not match: {e}")))?;
+
+        // the same certificate identifies us to the servers we connect to
+        let connector = match roots {
+            Some(roots) => {
+                let client_config = ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| invalid(e.to_string()))?;
+                Some(TlsConnector::from(Arc::new(client_config)))
+            }
+            None if config.tls_replication || config.tls_cluster => {
+                return Err(invalid("tls-replication and tls-cluster need tls-ca-cert-file"));
+            }
+            None => None,
+        };
+        info!(auth_clients = %config.tls_auth_clients, "TLS configured");
+        Ok(Some(Tls { acceptor: TlsAcceptor::from(Arc::new(server_config)), connector }))
+    }
+
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+}
+
+/// opens a link to another server, over TLS when `secure`
+pub async fn connect(server: &Server, host: &str, port: u16, secure: bool) -> Result<Box<dyn Stream>, Error> {
+    let stream = TcpStream::connect((host, port)).await?;
+    if !secure {
+        return Ok(Box::new(stream));
+    }
+    let connector = server
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.connector.as_ref())
+        .ok_or_else(|| Error::other("TLS is not configured"))?;
+    let name = ServerName::try_from(host.to_string()).map_err(|e| invalid(e.to_string()))?;
+    let stream = connector.connect(name, stream).await?;
+    debug!(host, port, "TLS link established");
+    Ok(Box::new(stream))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("can't load certificates {}: {e}", path.display())))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn invalid(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg.into())
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::{Instance, Reply};
+
+#[test]
+fn user_is_limited_to_its_commands_and_keys() {
+    let server = Instance::start(&[]);
+    let mut admin = server.client();
+    let setuser = ["ACL", "SETUSER", "reader", "on", ">secret", "+get", "~public:*"];
+    assert_eq!(admin.call(&setuser), Reply::ok());
+    assert_eq!(admin.call(&["SET", "public:a", "1"]), Reply::ok());
+
+    let mut c = server.client();
+    assert!(c.call(&["AUTH", "reader", "wrong"]).is_error("WRONGPASS"));
+    assert_eq!(c.call(&["AUTH", "reader", "secret"]), Reply::ok());
+    assert_eq!(c.call(&["GET", "public:a"]), Reply::bulk("1"));
+    assert!(c.call(&["GET", "private:a"]).is_error("NOPERM"));
+    assert!(c.call(&["SET", "public:a", "2"]).is_error("NOPERM"));
+    assert_eq!(admin.call(&["GET", "public:a"]), Reply::bulk("1"));
+
+    let Reply::Array(Some(log)) = admin.call(&["ACL", "LOG"]) else {
+        panic!("ACL LOG is an array");
+    };
+    assert!(!log.is_empty());
+}
+
+#[test]
+fn read_only_pattern_refuses_scripts() {
+    let server = Instance::start(&[]);
+    let mut admin = server.client();
+    let setuser = ["ACL", "SETUSER", "u", "on", "nopass", "+@all", "%R~ro:*", "~rw:*"];
+    assert_eq!(admin.call(&setuser), Reply::ok());
+
+    let mut c = server.client();
+    assert_eq!(c.call(&["AUTH", "u", "any"]), Reply::ok());
+    // script keys need read and write access
+    assert!(c.call(&["EVAL", "return 1", "1", "ro:a"]).is_error("NOPERM"));
+    assert_eq!(c.call(&["EVAL", "return 1", "1", "rw:a"]), Reply::Int(1));
+}
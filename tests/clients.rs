@@ -0,0 +1,75 @@
+mod common;
+
+use common::{eventually, Client, Instance, Reply};
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[test]
+fn maxclients_counts_only_normal_clients() {
+    let master = Instance::start(&["--maxclients", "2"]);
+    let _replica = Instance::start(&["--replicaof", "127.0.0.1", &master.port.to_string()]);
+    let mut first = master.client();
+    assert_eq!(first.call(&["WAIT", "1", "5000"]), Reply::Int(1));
+
+    // the replica link is connected but leaves room for a second client
+    let mut second = master.client();
+    assert_eq!(second.call(&["PING"]), Reply::Status("PONG".to_string()));
+    let mut third = master.client();
+    assert!(third.read().unwrap().is_error("ERR max number of clients reached"));
+
+    drop(second);
+    eventually("a slot frees up", || master.client().try_call(&["PING"]).is_ok_and(|r| !r.is_error("ERR")));
+}
+
+#[test]
+fn idle_client_is_closed_after_timeout() {
+    let server = Instance::start(&["--timeout", "1"]);
+    let stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut idle = Client::new(stream);
+    assert_eq!(idle.call(&["PING"]), Reply::Status("PONG".to_string()));
+    let started = Instant::now();
+    let closed = idle.read().unwrap_err();
+    assert_eq!(closed.kind(), ErrorKind::UnexpectedEof, "closed by the server, not the read timeout");
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[test]
+fn client_kill_closes_the_connection() {
+    let server = Instance::start(&[]);
+    let mut admin = server.client();
+    let mut victim = server.client();
+    assert_eq!(victim.call(&["CLIENT", "SETNAME", "victim"]), Reply::ok());
+    let Reply::Int(id) = victim.call(&["CLIENT", "ID"]) else {
+        panic!("CLIENT ID is an integer");
+    };
+    let Reply::Bulk(Some(list)) = admin.call(&["CLIENT", "LIST"]) else {
+        panic!("CLIENT LIST is a bulk string");
+    };
+    assert!(list.lines().any(|l| l.contains(&format!("id={id} ")) && l.contains("name=victim")), "{list}");
+
+    assert_eq!(admin.call(&["CLIENT", "KILL", "ID", &id.to_string()]), Reply::Int(1));
+    assert!(victim.try_call(&["PING"]).is_err());
+    assert_eq!(admin.call(&["CLIENT", "KILL", "ID", &id.to_string()]), Reply::Int(0));
+}
+
+#[test]
+fn client_pause_holds_writes_only() {
+    let server = Instance::start(&[]);
+    let mut admin = server.client();
+    let mut c = server.client();
+    assert_eq!(admin.call(&["CLIENT", "PAUSE", "500", "WRITE"]), Reply::ok());
+
+    let started = Instant::now();
+    assert_eq!(c.call(&["GET", "k"]), Reply::Bulk(None));
+    assert!(started.elapsed() < Duration::from_millis(400), "reads go through");
+    assert_eq!(c.call(&["SET", "k", "v"]), Reply::ok());
+    assert!(started.elapsed() >= Duration::from_millis(400), "writes wait for the pause");
+
+    assert_eq!(admin.call(&["CLIENT", "PAUSE", "5000"]), Reply::ok());
+    assert_eq!(admin.call(&["CLIENT", "UNPAUSE"]), Reply::ok());
+    let started = Instant::now();
+    assert_eq!(c.call(&["GET", "k"]), Reply::bulk("v"));
+    assert!(started.elapsed() < Duration::from_secs(1), "UNPAUSE lifts the pause");
+}
@@ -0,0 +1,173 @@
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// a server process on a free port with a scratch `dir`, killed when dropped
+pub struct Instance {
+    pub port: u16,
+    pub dir: TempDir,
+    child: Child,
+}
+
+impl Instance {
+    pub fn start(args: &[&str]) -> Instance {
+        Instance::start_in(tempfile::tempdir().unwrap(), args)
+    }
+
+    /// for tests that put files such as certificates into `dir` first
+    pub fn start_in(dir: TempDir, args: &[&str]) -> Instance {
+        Instance::spawn(dir, None, args)
+    }
+
+    /// a server reading `redis.conf` with `contents` from its `dir` before the arguments
+    pub fn start_with_config(contents: &str, args: &[&str]) -> Instance {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_file(dir.path(), "redis.conf", contents);
+        Instance::spawn(dir, Some(&config), args)
+    }
+
+    fn spawn(dir: TempDir, config: Option<&str>, args: &[&str]) -> Instance {
+        let port = free_port();
+        let child = Command::new(env!("CARGO_BIN_EXE_codecrafters-redis"))
+            .args(config)
+            .args(["--port", &port.to_string(), "--dir"])
+            .arg(dir.path())
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("server binary starts");
+        let instance = Instance { port, dir, child };
+        eventually("server accepts connections", || TcpStream::connect(("127.0.0.1", port)).is_ok());
+        instance
+    }
+
+    /// a new process on the same `dir`, for tests of what survives a restart
+    pub fn restart(mut self, args: &[&str]) -> Instance {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let dir = std::mem::replace(&mut self.dir, tempfile::tempdir().unwrap());
+        Instance::start_in(dir, args)
+    }
+
+    /// waits for the process to exit by itself, as after SHUTDOWN
+    pub fn wait_exit(&mut self) {
+        eventually("server exits", || self.child.try_wait().unwrap().is_some());
+    }
+
+    pub fn client(&self) -> Client<TcpStream> {
+        Client::new(TcpStream::connect(("127.0.0.1", self.port)).unwrap())
+    }
+
+    pub fn path(&self, file: &str) -> String {
+        self.dir.path().join(file).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// polls `condition` for up to five seconds
+pub fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+pub fn write_file(dir: &Path, name: &str, contents: &str) -> String {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Int(i64),
+    Bulk(Option<String>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    pub fn ok() -> Reply {
+        Reply::Status("OK".to_string())
+    }
+
+    pub fn bulk(s: &str) -> Reply {
+        Reply::Bulk(Some(s.to_string()))
+    }
+
+    pub fn is_error(&self, prefix: &str) -> bool {
+        matches!(self, Reply::Error(e) if e.starts_with(prefix))
+    }
+}
+
+/// a blocking RESP2 client over any stream
+pub struct Client<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Client<S> {
+        Client { stream: BufReader::new(stream) }
+    }
+
+    pub fn call(&mut self, args: &[&str]) -> Reply {
+        self.try_call(args).expect("server replies")
+    }
+
+    pub fn try_call(&mut self, args: &[&str]) -> std::io::Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+        }
+        let stream = self.stream.get_mut();
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+        self.read()
+    }
+
+    pub fn read(&mut self) -> std::io::Result<Reply> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let (kind, rest) = line.trim_end_matches("\r\n").split_at(1);
+        Ok(match kind {
+            "+" => Reply::Status(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Int(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut data = vec![0; len as usize + 2];
+                    self.stream.read_exact(&mut data)?;
+                    data.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(data).unwrap()))
+                }
+            },
+            "*" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Array(None),
+                len => Reply::Array(Some((0..len).map(|_| self.read()).collect::<Result<_, _>>()?)),
+            },
+            other => panic!("unexpected reply type {other:?}"),
+        })
+    }
+}
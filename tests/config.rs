@@ -0,0 +1,39 @@
+mod common;
+
+use common::{Instance, Reply};
+
+#[test]
+fn config_set_and_get() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["CONFIG", "SET", "maxclients", "50", "timeout", "30"]), Reply::ok());
+    assert_eq!(
+        c.call(&["CONFIG", "GET", "maxclients"]),
+        Reply::Array(Some(vec![Reply::bulk("maxclients"), Reply::bulk("50")]))
+    );
+    let Reply::Array(Some(matched)) = c.call(&["CONFIG", "GET", "max*"]) else {
+        panic!("CONFIG GET is an array");
+    };
+    assert!(matched.contains(&Reply::bulk("maxclients")));
+    assert!(c.call(&["CONFIG", "SET", "maxclients", "lots"]).is_error("ERR"));
+    assert!(c.call(&["CONFIG", "SET", "no-such-thing", "1"]).is_error("ERR"));
+}
+
+#[test]
+fn rewrite_keeps_comments_and_updates_directives() {
+    let server = Instance::start_with_config("# keep this comment\nmaxclients 100\nmaxclients 200\n", &[]);
+    let mut c = server.client();
+    // the last occurrence wins
+    let get = c.call(&["CONFIG", "GET", "maxclients"]);
+    assert_eq!(get, Reply::Array(Some(vec![Reply::bulk("maxclients"), Reply::bulk("200")])));
+    assert_eq!(c.call(&["CONFIG", "SET", "maxclients", "50"]), Reply::ok());
+    assert_eq!(c.call(&["CONFIG", "SET", "masterauth", "two words"]), Reply::ok());
+    assert_eq!(c.call(&["CONFIG", "REWRITE"]), Reply::ok());
+
+    let text = std::fs::read_to_string(server.path("redis.conf")).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[..2], ["# keep this comment", "maxclients 50"], "{text}");
+    assert_eq!(lines.iter().filter(|l| l.starts_with("maxclients")).count(), 1, "{text}");
+    assert!(lines.contains(&"# Generated by CONFIG REWRITE"), "{text}");
+    assert!(lines.contains(&"masterauth \"two words\""), "{text}");
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::{Instance, Reply};
+
+#[test]
+fn select_isolates_databases() {
+    let server = Instance::start(&["--databases", "4"]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["SET", "k", "zero"]), Reply::ok());
+    assert_eq!(c.call(&["SELECT", "1"]), Reply::ok());
+    assert_eq!(c.call(&["GET", "k"]), Reply::Bulk(None));
+    assert_eq!(c.call(&["SET", "k", "one"]), Reply::ok());
+    assert_eq!(c.call(&["DBSIZE"]), Reply::Int(1));
+    assert!(c.call(&["SELECT", "4"]).is_error("ERR DB index is out of range"));
+
+    // another connection starts in database 0
+    assert_eq!(server.client().call(&["GET", "k"]), Reply::bulk("zero"));
+    assert_eq!(c.call(&["FLUSHDB"]), Reply::ok());
+    assert_eq!(server.client().call(&["GET", "k"]), Reply::bulk("zero"));
+}
+
+#[test]
+fn swapdb_and_move() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    let mut other = server.client();
+    assert_eq!(c.call(&["SET", "a", "1"]), Reply::ok());
+    assert_eq!(other.call(&["SELECT", "2"]), Reply::ok());
+
+    assert_eq!(c.call(&["SWAPDB", "0", "2"]), Reply::ok());
+    assert_eq!(c.call(&["GET", "a"]), Reply::Bulk(None));
+    assert_eq!(other.call(&["GET", "a"]), Reply::bulk("1"));
+
+    assert_eq!(other.call(&["MOVE", "a", "0"]), Reply::Int(1));
+    assert_eq!(other.call(&["MOVE", "a", "0"]), Reply::Int(0));
+    assert_eq!(c.call(&["GET", "a"]), Reply::bulk("1"));
+    // an existing key at the target is left alone
+    assert_eq!(other.call(&["SET", "a", "2"]), Reply::ok());
+    assert_eq!(other.call(&["MOVE", "a", "0"]), Reply::Int(0));
+    assert_eq!(c.call(&["GET", "a"]), Reply::bulk("1"));
+    assert!(c.call(&["MOVE", "a", "0"]).is_error("ERR"));
+}
@@ -0,0 +1,23 @@
+mod common;
+
+use common::{Instance, Reply};
+
+#[test]
+fn set_and_expiry_are_notified() {
+    let server = Instance::start(&["--notify-keyspace-events", "KEA"]);
+    let mut sub = server.client();
+    let mut c = server.client();
+    sub.call(&["PSUBSCRIBE", "__key*@0__:*"]);
+
+    assert_eq!(c.call(&["SET", "k", "v", "PX", "50"]), Reply::ok());
+    let event = |pattern: &str, channel: &str, message: &str| {
+        Reply::Array(Some(["pmessage", pattern, channel, message].iter().map(|s| Reply::bulk(s)).collect()))
+    };
+    assert_eq!(sub.read().unwrap(), event("__key*@0__:*", "__keyspace@0__:k", "set"));
+    assert_eq!(sub.read().unwrap(), event("__key*@0__:*", "__keyevent@0__:set", "k"));
+    assert_eq!(sub.read().unwrap(), event("__key*@0__:*", "__keyspace@0__:k", "expire"));
+    assert_eq!(sub.read().unwrap(), event("__key*@0__:*", "__keyevent@0__:expire", "k"));
+    // the active expire cycle removes the key without anyone touching it
+    assert_eq!(sub.read().unwrap(), event("__key*@0__:*", "__keyspace@0__:k", "expired"));
+    assert_eq!(sub.read().unwrap(), event("__key*@0__:*", "__keyevent@0__:expired", "k"));
+}
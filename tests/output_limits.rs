@@ -0,0 +1,23 @@
+mod common;
+
+use common::{eventually, Instance, Reply};
+
+#[test]
+fn slow_subscriber_is_disconnected_over_the_hard_limit() {
+    let server = Instance::start(&["--client-output-buffer-limit", "pubsub 64kb 0 0"]);
+    let mut subscriber = server.client();
+    subscriber.call(&["SUBSCRIBE", "ch"]);
+    let mut publisher = server.client();
+    let numsub = |publisher: &mut common::Client<_>| publisher.call(&["PUBSUB", "NUMSUB", "ch"]);
+    let subscribed = Reply::Array(Some(vec![Reply::bulk("ch"), Reply::Int(1)]));
+    assert_eq!(numsub(&mut publisher), subscribed);
+
+    // the subscriber never reads, so once the socket buffers are full output queues up
+    let message = "x".repeat(64 * 1024);
+    eventually("the subscriber is dropped", || {
+        publisher.call(&["PUBLISH", "ch", &message]);
+        numsub(&mut publisher) != subscribed
+    });
+    // only the subscriber was over its limit
+    assert_eq!(publisher.call(&["PING"]), Reply::Status("PONG".to_string()));
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::{eventually, Instance, Reply};
+
+#[test]
+fn saved_dataset_survives_a_restart() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["SET", "plain", "v"]), Reply::ok());
+    assert_eq!(c.call(&["SET", "expiring", "v", "EX", "1000"]), Reply::ok());
+    assert_eq!(c.call(&["SET", "short", "v", "PX", "300"]), Reply::ok());
+    assert_eq!(c.call(&["SELECT", "3"]), Reply::ok());
+    assert_eq!(c.call(&["SET", "other", "db"]), Reply::ok());
+    assert_eq!(c.call(&["SAVE"]), Reply::ok());
+
+    let server = server.restart(&[]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["GET", "plain"]), Reply::bulk("v"));
+    assert_eq!(c.call(&["GET", "expiring"]), Reply::bulk("v"));
+    // the expiry was saved with the key
+    eventually("the short lived key expires", || c.call(&["GET", "short"]) == Reply::Bulk(None));
+    assert_eq!(c.call(&["SELECT", "3"]), Reply::ok());
+    assert_eq!(c.call(&["GET", "other"]), Reply::bulk("db"));
+}
+
+#[test]
+fn shutdown_saves_after_a_background_save() {
+    let mut server = Instance::start(&[]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["SET", "k", "before"]), Reply::ok());
+    assert_eq!(c.call(&["BGSAVE"]), Reply::Status("Background saving started".to_string()));
+    assert_eq!(c.call(&["SET", "k", "after"]), Reply::ok());
+    // the connection drops without a reply once the server is gone
+    assert!(c.try_call(&["SHUTDOWN"]).is_err());
+    server.wait_exit();
+
+    let server = server.restart(&[]);
+    assert_eq!(server.client().call(&["GET", "k"]), Reply::bulk("after"));
+    let leftovers: Vec<_> = std::fs::read_dir(server.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("temp-"))
+        .collect();
+    assert!(leftovers.is_empty(), "temporary files left behind: {leftovers:?}");
+}
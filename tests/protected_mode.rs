@@ -0,0 +1,44 @@
+mod common;
+
+use common::{Client, Instance, Reply};
+use std::net::{IpAddr, TcpStream, UdpSocket};
+
+/// an address of this host other than loopback, if it has one. routing a UDP socket
+/// sends nothing
+fn external_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    Some(socket.local_addr().ok()?.ip()).filter(|ip| !ip.is_loopback() && !ip.is_unspecified())
+}
+
+fn connect(server: &Instance, ip: IpAddr) -> Client<TcpStream> {
+    Client::new(TcpStream::connect((ip, server.port)).unwrap())
+}
+
+#[test]
+fn protected_mode_refuses_remote_clients_without_a_password() {
+    let Some(ip) = external_ip() else {
+        eprintln!("no address besides loopback, skipping");
+        return;
+    };
+    let open = Instance::start(&["--bind", "0.0.0.0"]);
+    assert!(connect(&open, ip).read().unwrap().is_error("DENIED"));
+    assert_eq!(open.client().call(&["PING"]), Reply::Status("PONG".to_string()));
+
+    let unprotected = Instance::start(&["--bind", "0.0.0.0", "--protected-mode", "no"]);
+    assert_eq!(connect(&unprotected, ip).call(&["PING"]), Reply::Status("PONG".to_string()));
+
+    let password = Instance::start(&["--bind", "0.0.0.0", "--requirepass", "secret"]);
+    assert!(connect(&password, ip).call(&["PING"]).is_error("NOAUTH"));
+}
+
+#[test]
+fn renamed_commands_answer_to_their_new_name() {
+    let server = Instance::start(&["--rename-command", "GET", "FETCH", "--rename-command", "FLUSHALL", ""]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["SET", "k", "v"]), Reply::ok());
+    assert!(c.call(&["GET", "k"]).is_error("ERR unknown command"));
+    assert_eq!(c.call(&["FETCH", "k"]), Reply::bulk("v"));
+    assert!(c.call(&["FLUSHALL"]).is_error("ERR unknown command"));
+    assert_eq!(c.call(&["FETCH", "k"]), Reply::bulk("v"));
+}
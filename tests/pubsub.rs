@@ -0,0 +1,47 @@
+mod common;
+
+use common::{Instance, Reply};
+
+fn bulks(items: &[&str]) -> Vec<Reply> {
+    items.iter().map(|s| Reply::bulk(s)).collect()
+}
+
+#[test]
+fn messages_reach_channel_and_pattern_subscribers() {
+    let server = Instance::start(&[]);
+    let mut sub = server.client();
+    let mut psub = server.client();
+    let mut publisher = server.client();
+
+    let mut subscribed = bulks(&["subscribe", "news"]);
+    subscribed.push(Reply::Int(1));
+    assert_eq!(sub.call(&["SUBSCRIBE", "news"]), Reply::Array(Some(subscribed)));
+    let mut psubscribed = bulks(&["psubscribe", "n*"]);
+    psubscribed.push(Reply::Int(1));
+    assert_eq!(psub.call(&["PSUBSCRIBE", "n*"]), Reply::Array(Some(psubscribed)));
+
+    assert_eq!(publisher.call(&["PUBLISH", "news", "hi"]), Reply::Int(2));
+    assert_eq!(sub.read().unwrap(), Reply::Array(Some(bulks(&["message", "news", "hi"]))));
+    assert_eq!(psub.read().unwrap(), Reply::Array(Some(bulks(&["pmessage", "n*", "news", "hi"]))));
+    assert_eq!(publisher.call(&["PUBLISH", "other", "hi"]), Reply::Int(0));
+
+    assert_eq!(publisher.call(&["PUBSUB", "CHANNELS"]), Reply::Array(Some(bulks(&["news"]))));
+    assert_eq!(publisher.call(&["PUBSUB", "NUMPAT"]), Reply::Int(1));
+    let mut numsub = bulks(&["news"]);
+    numsub.push(Reply::Int(1));
+    assert_eq!(publisher.call(&["PUBSUB", "NUMSUB", "news"]), Reply::Array(Some(numsub)));
+}
+
+#[test]
+fn subscribed_client_is_limited_to_pubsub_commands() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    c.call(&["SUBSCRIBE", "ch"]);
+    assert!(c.call(&["GET", "k"]).is_error("ERR Can't execute 'get'"));
+    assert_eq!(c.call(&["PING"]), Reply::Array(Some(bulks(&["pong", ""]))));
+
+    let mut unsubscribed = bulks(&["unsubscribe", "ch"]);
+    unsubscribed.push(Reply::Int(0));
+    assert_eq!(c.call(&["UNSUBSCRIBE"]), Reply::Array(Some(unsubscribed)));
+    assert_eq!(c.call(&["GET", "k"]), Reply::Bulk(None));
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::{eventually, Instance, Reply};
+
+#[test]
+fn replica_follows_master() {
+    let master = Instance::start(&[]);
+    let replica = Instance::start(&["--replicaof", "127.0.0.1", &master.port.to_string()]);
+    let mut m = master.client();
+    let mut r = replica.client();
+
+    assert_eq!(m.call(&["SET", "a", "1"]), Reply::ok());
+    assert_eq!(m.call(&["SET", "b", "2", "EX", "100"]), Reply::ok());
+    assert_eq!(m.call(&["WAIT", "1", "5000"]), Reply::Int(1));
+    assert_eq!(r.call(&["GET", "a"]), Reply::bulk("1"));
+    assert_eq!(r.call(&["GET", "b"]), Reply::bulk("2"));
+    assert!(r.call(&["SET", "a", "2"]).is_error("READONLY"));
+}
+
+#[test]
+fn roles_can_be_swapped() {
+    let first = Instance::start(&[]);
+    let second = Instance::start(&["--replicaof", "127.0.0.1", &first.port.to_string()]);
+    let mut a = first.client();
+    let mut b = second.client();
+    assert_eq!(a.call(&["SET", "before", "x"]), Reply::ok());
+    assert_eq!(a.call(&["WAIT", "1", "5000"]), Reply::Int(1));
+
+    // promote the replica, then demote the old master under it
+    assert_eq!(b.call(&["REPLICAOF", "NO", "ONE"]), Reply::ok());
+    assert_eq!(a.call(&["REPLICAOF", "127.0.0.1", &second.port.to_string()]), Reply::ok());
+    assert_eq!(b.call(&["SET", "after", "y"]), Reply::ok());
+    eventually("the old master has the new write", || a.call(&["GET", "after"]) == Reply::bulk("y"));
+    assert_eq!(a.call(&["GET", "before"]), Reply::bulk("x"));
+    assert!(a.call(&["SET", "before", "z"]).is_error("READONLY"));
+}
+
+#[test]
+fn waitaof_counts_replica_acks() {
+    let master = Instance::start(&[]);
+    let _replica = Instance::start(&["--replicaof", "127.0.0.1", &master.port.to_string()]);
+    let mut m = master.client();
+    assert_eq!(m.call(&["SET", "a", "1"]), Reply::ok());
+    assert_eq!(m.call(&["WAITAOF", "0", "1", "5000"]), Reply::Array(Some(vec![Reply::Int(0), Reply::Int(1)])));
+    let reply = m.call(&["WAITAOF", "1", "1", "100"]);
+    assert!(reply.is_error("ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."));
+}
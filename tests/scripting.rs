@@ -0,0 +1,46 @@
+mod common;
+
+use common::{Instance, Reply};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn eval_reads_keys_and_args() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    let script = "redis.call('SET', KEYS[1], ARGV[1]); return {KEYS[1], redis.call('GET', KEYS[1]), 7}";
+    assert_eq!(
+        c.call(&["EVAL", script, "1", "k", "v"]),
+        Reply::Array(Some(vec![Reply::bulk("k"), Reply::bulk("v"), Reply::Int(7)]))
+    );
+    assert_eq!(c.call(&["GET", "k"]), Reply::bulk("v"));
+    assert!(c.call(&["EVAL", "return redis.call('NOPE')", "0"]).is_error("ERR"));
+    assert!(c.call(&["EVAL", "return redis.call('SUBSCRIBE', 'ch')", "0"]).is_error("ERR"));
+}
+
+#[test]
+fn evalsha_runs_loaded_scripts() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    let Reply::Bulk(Some(sha)) = c.call(&["SCRIPT", "LOAD", "return ARGV[1]"]) else {
+        panic!("SCRIPT LOAD returns the sha");
+    };
+    assert_eq!(c.call(&["EVALSHA", &sha, "0", "x"]), Reply::bulk("x"));
+    assert_eq!(c.call(&["SCRIPT", "EXISTS", &sha, "0000"]), Reply::Array(Some(vec![Reply::Int(1), Reply::Int(0)])));
+    assert_eq!(c.call(&["SCRIPT", "FLUSH"]), Reply::ok());
+    assert!(c.call(&["EVALSHA", &sha, "0", "x"]).is_error("NOSCRIPT"));
+}
+
+#[test]
+fn long_script_makes_others_busy_until_killed() {
+    let server = Instance::start(&["--busy-reply-threshold", "100"]);
+    let mut runner = server.client();
+    let mut other = server.client();
+    let script = thread::spawn(move || runner.call(&["EVAL", "while true do end", "0"]));
+    thread::sleep(Duration::from_millis(300));
+
+    assert!(other.call(&["GET", "k"]).is_error("BUSY"));
+    assert_eq!(other.call(&["SCRIPT", "KILL"]), Reply::ok());
+    assert!(script.join().unwrap().is_error("ERR"));
+    assert_eq!(other.call(&["GET", "k"]), Reply::Bulk(None));
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use common::{free_port, write_file, Client, Instance, Reply};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use std::net::TcpStream;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+type TlsClient = Client<StreamOwned<ClientConnection, TcpStream>>;
+
+/// a CA, and a server and a client certificate it signed, all made up for one test
+struct Pki {
+    ca: Certificate,
+    server: (Certificate, KeyPair),
+    client: (Certificate, KeyPair),
+}
+
+impl Pki {
+    fn generate() -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let leaf = |names: Vec<String>, usage| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(names).unwrap();
+            params.extended_key_usages = vec![usage];
+            (params.signed_by(&key, &ca, &ca_key).unwrap(), key)
+        };
+        let server = leaf(vec!["localhost".into(), "127.0.0.1".into()], ExtendedKeyUsagePurpose::ServerAuth);
+        let client = leaf(vec!["client".into()], ExtendedKeyUsagePurpose::ClientAuth);
+        Pki { ca, server, client }
+    }
+
+    /// a server with a TLS port next to its plain one, returned as well
+    fn start_server(&self, auth_clients: &str) -> (Instance, u16) {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = write_file(dir.path(), "server.crt", &self.server.0.pem());
+        let key = write_file(dir.path(), "server.key", &self.server.1.serialize_pem());
+        let ca = write_file(dir.path(), "ca.crt", &self.ca.pem());
+        let tls_port = free_port().to_string();
+        let args = [
+            "--tls-port", &tls_port,
+            "--tls-cert-file", &cert,
+            "--tls-key-file", &key,
+            "--tls-ca-cert-file", &ca,
+            "--tls-auth-clients", auth_clients,
+        ];
+        let server = Instance::start_in(dir, &args);
+        (server, tls_port.parse().unwrap())
+    }
+
+    fn connect(&self, port: u16, with_cert: bool) -> TlsClient {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.ca.der().clone()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = if with_cert {
+            let (cert, key) = &self.client;
+            let key = PrivateKeyDer::from_pem_slice(key.serialize_pem().as_bytes()).unwrap();
+            builder.with_client_auth_cert(vec![CertificateDer::from(cert.der().to_vec())], key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        Client::new(StreamOwned::new(connection, socket))
+    }
+}
+
+#[test]
+fn client_certificate_is_required() {
+    let pki = Pki::generate();
+    let (_server, port) = pki.start_server("yes");
+
+    let mut anonymous = pki.connect(port, false);
+    assert!(anonymous.try_call(&["PING"]).is_err(), "handshake without a certificate must fail");
+
+    let mut authenticated = pki.connect(port, true);
+    assert_eq!(authenticated.call(&["PING"]), Reply::Status("PONG".to_string()));
+    assert_eq!(authenticated.call(&["SET", "k", "v"]), Reply::ok());
+}
+
+#[test]
+fn client_certificate_is_optional() {
+    let pki = Pki::generate();
+    let (server, port) = pki.start_server("optional");
+
+    let mut anonymous = pki.connect(port, false);
+    assert_eq!(anonymous.call(&["SET", "k", "v"]), Reply::ok());
+    let mut authenticated = pki.connect(port, true);
+    assert_eq!(authenticated.call(&["GET", "k"]), Reply::bulk("v"));
+    // the plain port keeps working next to the TLS one
+    assert_eq!(server.client().call(&["GET", "k"]), Reply::bulk("v"));
+}
+
+#[test]
+fn client_certificate_is_not_asked_for() {
+    let pki = Pki::generate();
+    let (_server, port) = pki.start_server("no");
+
+    let mut anonymous = pki.connect(port, false);
+    assert_eq!(anonymous.call(&["PING"]), Reply::Status("PONG".to_string()));
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use common::{Instance, Reply};
+
+#[test]
+fn exec_runs_the_queue() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["MULTI"]), Reply::ok());
+    assert_eq!(c.call(&["SET", "k", "v"]), Reply::Status("QUEUED".to_string()));
+    assert_eq!(c.call(&["GET", "k"]), Reply::Status("QUEUED".to_string()));
+    assert_eq!(c.call(&["EXEC"]), Reply::Array(Some(vec![Reply::ok(), Reply::bulk("v")])));
+}
+
+#[test]
+fn rejected_command_aborts_exec() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["MULTI"]), Reply::ok());
+    assert!(c.call(&["GET"]).is_error("ERR wrong number of arguments"));
+    assert_eq!(c.call(&["SET", "k", "v"]), Reply::Status("QUEUED".to_string()));
+    assert!(c.call(&["EXEC"]).is_error("EXECABORT"));
+    assert_eq!(c.call(&["GET", "k"]), Reply::Bulk(None));
+    assert!(c.call(&["DISCARD"]).is_error("ERR DISCARD without MULTI"));
+}
+
+#[test]
+fn watched_key_change_aborts_exec() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    let mut other = server.client();
+    assert_eq!(c.call(&["WATCH", "k"]), Reply::ok());
+    assert_eq!(other.call(&["SET", "k", "theirs"]), Reply::ok());
+    assert_eq!(c.call(&["MULTI"]), Reply::ok());
+    assert_eq!(c.call(&["SET", "k", "mine"]), Reply::Status("QUEUED".to_string()));
+    assert_eq!(c.call(&["EXEC"]), Reply::Array(None));
+    assert_eq!(c.call(&["GET", "k"]), Reply::bulk("theirs"));
+
+    // EXEC unwatched everything, so the next transaction goes through
+    assert_eq!(c.call(&["MULTI"]), Reply::ok());
+    assert_eq!(c.call(&["SET", "k", "mine"]), Reply::Status("QUEUED".to_string()));
+    assert_eq!(c.call(&["EXEC"]), Reply::Array(Some(vec![Reply::ok()])));
+}
+
+#[test]
+fn psync_is_refused_inside_multi() {
+    let server = Instance::start(&[]);
+    let mut c = server.client();
+    assert_eq!(c.call(&["MULTI"]), Reply::ok());
+    assert!(c.call(&["PSYNC", "?", "-1"]).is_error("ERR Command not allowed inside a transaction"));
+    assert!(c.call(&["EXEC"]).is_error("EXECABORT"));
+    assert_eq!(c.call(&["PING"]), Reply::Status("PONG".to_string()));
+}